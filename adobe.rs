@@ -0,0 +1,60 @@
+use std::fmt;
+
+use crate::segment::Segment;
+
+/// The colour transform applied by the encoder, as recorded in an Adobe APP14 segment
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AdobeTransform {
+    /// No transform, i.e RGB for 3 components or CMYK for 4
+    Unknown,
+    /// 3 components stored as YCbCr
+    YCbCr,
+    /// 4 components stored as YCbCr and K
+    Ycck,
+    /// A value not defined by Adobe
+    Other(u8),
+}
+
+impl fmt::Display for AdobeTransform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AdobeTransform::Unknown => write!(f, "Unknown (RGB or CMYK)"),
+            AdobeTransform::YCbCr => write!(f, "YCbCr"),
+            AdobeTransform::Ycck => write!(f, "YCCK"),
+            AdobeTransform::Other(b) => write!(f, "{}", b),
+        }
+    }
+}
+
+/// Contents of an Adobe APP14 segment (Adobe Technical Note 5116)
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct AdobeSegment {
+    /// DCTEncode/DCTDecode version, typically 100 or 101
+    pub version: u16,
+    pub flags0: u16,
+    pub flags1: u16,
+    pub transform: AdobeTransform,
+}
+
+/// Length of an Adobe APP14 payload: "Adobe", the version, both flags and the transform
+const ADOBE_LEN: usize = 12;
+
+/// Parse an APP14 segment, returning None if it is not (or is too short to be) an Adobe segment
+pub(crate) fn parse_adobe(segment: &Segment) -> Option<AdobeSegment> {
+    let data = &segment.payload;
+    if data.len() < ADOBE_LEN || !data.starts_with(b"Adobe") {
+        return None;
+    }
+    let conv = |idx: usize| u16::from_be_bytes([data[idx], data[idx + 1]]);
+    Some(AdobeSegment {
+        version: conv(5),
+        flags0: conv(7),
+        flags1: conv(9),
+        transform: match data[11] {
+            0 => AdobeTransform::Unknown,
+            1 => AdobeTransform::YCbCr,
+            2 => AdobeTransform::Ycck,
+            b => AdobeTransform::Other(b),
+        },
+    })
+}
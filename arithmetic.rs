@@ -0,0 +1,525 @@
+use crate::{
+    error::{EntropyErrorKind, JpegError, Result},
+    huffman::TableClass,
+    marker::JpegMarker,
+    quant::ZIGZAG,
+    segment::Segment,
+};
+
+/// Arithmetic coding conditioning, as defined by a DAC segment
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ConditioningTable {
+    pub class: TableClass,
+    /// Arithmetic coding conditioning table destination identifier (Tb), 0 -> 3
+    pub id: u8,
+    /// Conditioning table value (Cs). For DC tables the lower bound L is held in the low 4 bits
+    /// and the upper bound U in the high 4 bits, for AC tables it is the threshold Kx.
+    pub value: u8,
+}
+
+impl ConditioningTable {
+    /// The bounds (L, U) used to classify DC differences as small or large
+    pub fn dc_bounds(&self) -> (u8, u8) {
+        (self.value & 0x0F, self.value >> 4)
+    }
+}
+
+/// Conditioning used until a DAC segment says otherwise (ITU T.81 F.1.4.4.1.4 and F.1.4.4.2.1)
+pub(crate) const DEFAULT_DC_BOUNDS: (u8, u8) = (0, 1);
+pub(crate) const DEFAULT_AC_THRESHOLD: u8 = 5;
+
+/// Parse every conditioning table defined in a DAC segment
+pub(crate) fn parse_conditioning_tables(segment: &Segment) -> Result<Vec<ConditioningTable>> {
+    let malformed = |reason| JpegError::Malformed {
+        offset: segment.offset,
+        marker: segment.marker,
+        reason,
+    };
+    if !segment.payload.len().is_multiple_of(2) {
+        return Err(JpegError::BadLength {
+            offset: segment.offset,
+            marker: segment.marker,
+            length: segment.length,
+        });
+    }
+
+    let mut tables = Vec::with_capacity(segment.payload.len() / 2);
+    for entry in segment.payload.chunks_exact(2) {
+        let class = match entry[0] >> 4 {
+            0 => TableClass::DC,
+            1 => TableClass::AC,
+            _ => return Err(malformed("conditioning table class must be 0 or 1")),
+        };
+        let table = ConditioningTable {
+            class,
+            id: entry[0] & 0x0F,
+            value: entry[1],
+        };
+        if table.id > 3 {
+            return Err(malformed("conditioning table destination must be 0 -> 3"));
+        }
+        match class {
+            TableClass::DC => {
+                let (lower, upper) = table.dc_bounds();
+                if lower > upper {
+                    return Err(malformed(
+                        "DC conditioning lower bound exceeds the upper bound",
+                    ));
+                }
+            }
+            TableClass::AC if !(1..=63).contains(&table.value) => {
+                return Err(malformed("AC conditioning threshold must be 1 -> 63"))
+            }
+            TableClass::AC => {}
+        }
+        tables.push(table);
+    }
+    Ok(tables)
+}
+
+/// Probability estimation state machine (ITU T.81 Table D.2), as (Qe, Next_Index_LPS,
+/// Next_Index_MPS, Switch_MPS). The final entry is not part of the table: it never adapts and
+/// is used where the standard calls for a fixed probability of one half.
+#[rustfmt::skip]
+const QE_TABLE: [(u32, u8, u8, bool); 114] = [
+    (0x5A1D, 1, 1, true), (0x2586, 14, 2, false), (0x1114, 16, 3, false), (0x080B, 18, 4, false),
+    (0x03D8, 20, 5, false), (0x01DA, 23, 6, false), (0x00E5, 25, 7, false), (0x006F, 28, 8, false),
+    (0x0036, 30, 9, false), (0x001A, 33, 10, false), (0x000D, 35, 11, false), (0x0006, 9, 12, false),
+    (0x0003, 10, 13, false), (0x0001, 12, 13, false), (0x5A7F, 15, 15, true), (0x3F25, 36, 16, false),
+    (0x2CF2, 38, 17, false), (0x207C, 39, 18, false), (0x17B9, 40, 19, false), (0x1182, 42, 20, false),
+    (0x0CEF, 43, 21, false), (0x09A1, 45, 22, false), (0x072F, 46, 23, false), (0x055C, 48, 24, false),
+    (0x0406, 49, 25, false), (0x0303, 51, 26, false), (0x0240, 52, 27, false), (0x01B1, 54, 28, false),
+    (0x0144, 56, 29, false), (0x00F5, 57, 30, false), (0x00B7, 59, 31, false), (0x008A, 60, 32, false),
+    (0x0068, 62, 33, false), (0x004E, 63, 34, false), (0x003B, 32, 35, false), (0x002C, 33, 9, false),
+    (0x5AE1, 37, 37, true), (0x484C, 64, 38, false), (0x3A0D, 65, 39, false), (0x2EF1, 67, 40, false),
+    (0x261F, 68, 41, false), (0x1F33, 69, 42, false), (0x19A8, 70, 43, false), (0x1518, 72, 44, false),
+    (0x1177, 73, 45, false), (0x0E74, 74, 46, false), (0x0BFB, 75, 47, false), (0x09F8, 77, 48, false),
+    (0x0861, 78, 49, false), (0x0706, 79, 50, false), (0x05CD, 48, 51, false), (0x04DE, 50, 52, false),
+    (0x040F, 50, 53, false), (0x0363, 51, 54, false), (0x02D4, 52, 55, false), (0x025C, 53, 56, false),
+    (0x01F8, 54, 57, false), (0x01A4, 55, 58, false), (0x0160, 56, 59, false), (0x0125, 57, 60, false),
+    (0x00F6, 58, 61, false), (0x00CB, 59, 62, false), (0x00AB, 61, 63, false), (0x008F, 61, 32, false),
+    (0x5B12, 65, 65, true), (0x4D04, 80, 66, false), (0x412C, 81, 67, false), (0x37D8, 82, 68, false),
+    (0x2FE8, 83, 69, false), (0x293C, 84, 70, false), (0x2379, 86, 71, false), (0x1EDF, 87, 72, false),
+    (0x1AA9, 87, 73, false), (0x174E, 72, 74, false), (0x1424, 72, 75, false), (0x119C, 74, 76, false),
+    (0x0F6B, 74, 77, false), (0x0D51, 75, 78, false), (0x0BB6, 77, 79, false), (0x0A40, 77, 48, false),
+    (0x5832, 80, 81, true), (0x4D1C, 88, 82, false), (0x438E, 89, 83, false), (0x3BDD, 90, 84, false),
+    (0x34EE, 91, 85, false), (0x2EAE, 92, 86, false), (0x299A, 93, 87, false), (0x2516, 86, 71, false),
+    (0x5570, 88, 89, true), (0x4CA9, 95, 90, false), (0x44D9, 96, 91, false), (0x3E22, 97, 92, false),
+    (0x3824, 99, 93, false), (0x32B4, 99, 94, false), (0x2E17, 93, 86, false), (0x56A8, 95, 96, true),
+    (0x4F46, 101, 97, false), (0x47E5, 102, 98, false), (0x41CF, 103, 99, false), (0x3C3D, 104, 100, false),
+    (0x375E, 99, 93, false), (0x5231, 105, 102, false), (0x4C0F, 106, 103, false), (0x4639, 107, 104, false),
+    (0x415E, 103, 99, false), (0x5627, 105, 106, true), (0x50E7, 108, 107, false), (0x4B85, 109, 103, false),
+    (0x5597, 110, 109, false), (0x504F, 111, 107, false), (0x5A10, 110, 111, true), (0x5522, 112, 109, false),
+    (0x59EB, 112, 111, true), (0x5A1D, 113, 113, false),
+];
+
+/// Index of the non-adapting state in `QE_TABLE`
+const FIXED_STATE: u8 = 113;
+
+/// Number of statistics bins used for DC and AC coding (ITU T.81 Tables F.4 and F.5)
+const DC_BINS: usize = 64;
+const AC_BINS: usize = 256;
+
+/// The QM-coder of ITU T.81 Annex D, decoding binary decisions from the entropy-coded data of a scan.
+/// Unlike Huffman coded data, reaching a marker is not an error: zero bytes are supplied after it.
+/// Zero bytes are supplied at the end of a truncated file too, but that is noted as an overrun.
+struct QmDecoder<'a> {
+    /// The file, ending at the end of the scan's entropy-coded data
+    data: &'a [u8],
+    /// Whether a marker follows the end of `data`, rather than the end of the file
+    terminated: bool,
+    /// File offset of the next byte to be read
+    pos: usize,
+    /// File offset of the last byte shifted into `c`, or of the marker or end of the data once
+    /// zero bytes are being supplied instead
+    consumed: usize,
+    /// Whether a byte was needed from beyond the end of a truncated file
+    overrun: bool,
+    /// The code register (C)
+    c: i64,
+    /// The interval register (A)
+    a: i64,
+    /// Number of bits which can be shifted out of `c` before another byte is needed.
+    /// Negative whilst the first two bytes are being read.
+    ct: i32,
+    /// The marker which stopped the decoder, if one was found
+    marker: Option<JpegMarker>,
+}
+
+impl<'a> QmDecoder<'a> {
+    fn new(data: &'a [u8], start: usize, terminated: bool) -> QmDecoder<'a> {
+        QmDecoder {
+            data,
+            terminated,
+            pos: start,
+            consumed: start,
+            overrun: false,
+            c: 0,
+            a: 0,
+            ct: -16,
+            marker: None,
+        }
+    }
+
+    /// Forget the state of the decoder, so that the next decision initialises it from fresh data
+    fn reset(&mut self) {
+        self.c = 0;
+        self.a = 0;
+        self.ct = -16;
+    }
+
+    fn next_byte(&mut self) -> u8 {
+        if self.marker.is_some() {
+            return 0;
+        }
+        let Some(&byte) = self.data.get(self.pos) else {
+            self.consumed = self.pos;
+            self.overrun |= !self.terminated;
+            return 0;
+        };
+        self.consumed = self.pos;
+        self.pos += 1;
+        if byte != 0xFF {
+            return byte;
+        }
+        // Skip any fill bytes to find a stuffed zero or a marker
+        while self.data.get(self.pos) == Some(&0xFF) {
+            self.pos += 1;
+        }
+        match self.data.get(self.pos) {
+            Some(0x00) => {
+                self.pos += 1;
+                0xFF
+            }
+            Some(marker) => {
+                // Step back so that the marker begins at `pos`
+                self.pos -= 1;
+                self.consumed = self.pos;
+                self.marker = Some(JpegMarker::from_u8(*marker));
+                0
+            }
+            None => {
+                self.consumed = self.pos;
+                self.overrun |= !self.terminated;
+                0
+            }
+        }
+    }
+
+    /// Decode a single decision using the probability estimate in `state`, updating the estimate
+    /// (ITU T.81 D.2.4 -> D.2.6). The most significant bit of `state` holds the more probable symbol.
+    fn decode(&mut self, state: &mut u8) -> bool {
+        // Renormalisation and data input
+        while self.a < 0x8000 {
+            self.ct -= 1;
+            if self.ct < 0 {
+                self.c = (self.c << 8) | self.next_byte() as i64;
+                self.ct += 8;
+                if self.ct < 0 {
+                    self.ct += 1;
+                    if self.ct == 0 {
+                        // Two bytes have been read, so A can start at its full interval
+                        self.a = 0x8000;
+                    }
+                }
+            }
+            self.a <<= 1;
+        }
+
+        let sv = *state;
+        let (qe, next_lps, next_mps, switch) = QE_TABLE[(sv & 0x7F) as usize];
+        let qe = qe as i64;
+        let mps = sv & 0x80;
+        let after_mps = mps | next_mps;
+        let after_lps = (if switch { mps ^ 0x80 } else { mps }) | next_lps;
+
+        self.a -= qe;
+        let threshold = self.a << self.ct;
+        if self.c >= threshold {
+            self.c -= threshold;
+            // The lower sub-interval was decoded, which is the LPS unless it is the larger one
+            if self.a < qe {
+                self.a = qe;
+                *state = after_mps;
+                mps != 0
+            } else {
+                self.a = qe;
+                *state = after_lps;
+                mps == 0
+            }
+        } else if self.a < 0x8000 {
+            if self.a < qe {
+                *state = after_lps;
+                mps == 0
+            } else {
+                *state = after_mps;
+                mps != 0
+            }
+        } else {
+            mps != 0
+        }
+    }
+}
+
+/// The state of an arithmetic coded scan: the decoder and the adaptive statistics of each table
+pub(crate) struct ArithmeticScan<'a> {
+    decoder: QmDecoder<'a>,
+    dc_stats: [[u8; DC_BINS]; 4],
+    ac_stats: [[u8; AC_BINS]; 4],
+    fixed: u8,
+    /// DC conditioning category of each scan component (ITU T.81 Table F.4)
+    dc_context: [usize; 4],
+    /// The previous DC value of each scan component
+    predictions: [i32; 4],
+}
+
+impl<'a> ArithmeticScan<'a> {
+    /// Decode the data from `start` up to the end of `data`, which is followed by a marker
+    /// unless `terminated` is false (i.e the file ended without one)
+    pub(crate) fn new(data: &'a [u8], start: usize, terminated: bool) -> ArithmeticScan<'a> {
+        ArithmeticScan {
+            decoder: QmDecoder::new(data, start, terminated),
+            dc_stats: [[0; DC_BINS]; 4],
+            ac_stats: [[0; AC_BINS]; 4],
+            fixed: FIXED_STATE,
+            dc_context: [0; 4],
+            predictions: [0; 4],
+        }
+    }
+
+    /// File offset of the byte being decoded, i.e the last one read into the decoder
+    pub(crate) fn position(&self) -> usize {
+        self.decoder.consumed
+    }
+
+    /// Whether decoding needed data from beyond the end of a truncated file
+    pub(crate) fn overrun(&self) -> bool {
+        self.decoder.overrun
+    }
+
+    /// Move past the RSTn marker which should end the current restart interval, skipping any data
+    /// which was not needed, and reset the decoder and statistics.
+    /// Returns the restart number (0 -> 7) of the marker.
+    pub(crate) fn restart(&mut self) -> std::result::Result<u8, EntropyErrorKind> {
+        let decoder = &mut self.decoder;
+        while decoder.marker.is_none() && decoder.pos < decoder.data.len() {
+            decoder.next_byte();
+        }
+        let number = match decoder.marker {
+            Some(JpegMarker::RST(b)) => b - 0xD0,
+            Some(marker) => return Err(EntropyErrorKind::UnexpectedMarker(marker)),
+            None => return Err(EntropyErrorKind::PrematureEnd),
+        };
+        decoder.pos += 2;
+        decoder.marker = None;
+        decoder.reset();
+        self.dc_stats = [[0; DC_BINS]; 4];
+        self.ac_stats = [[0; AC_BINS]; 4];
+        self.dc_context = [0; 4];
+        self.predictions = [0; 4];
+        Ok(number)
+    }
+
+    /// Decode the DC difference of a block (ITU T.81 F.2.4.1), storing the DC coefficient scaled by `approx_low`
+    pub(crate) fn decode_dc(
+        &mut self,
+        scan_idx: usize,
+        table: usize,
+        bounds: (u8, u8),
+        approx_low: u8,
+        block: &mut [i16; 64],
+    ) -> std::result::Result<(), EntropyErrorKind> {
+        let stats = &mut self.dc_stats[table];
+        let context = self.dc_context[scan_idx];
+        if !self.decoder.decode(&mut stats[context]) {
+            self.dc_context[scan_idx] = 0;
+        } else {
+            let sign = self.decoder.decode(&mut stats[context + 1]) as usize;
+            let mut bin = context + 2 + sign;
+            let mut magnitude = 0i32;
+            if self.decoder.decode(&mut stats[bin]) {
+                magnitude = 1;
+                bin = 20;
+                while self.decoder.decode(&mut stats[bin]) {
+                    magnitude <<= 1;
+                    if magnitude == 0x8000 {
+                        return Err(EntropyErrorKind::CoefficientOverflow);
+                    }
+                    bin += 1;
+                }
+            }
+
+            // Classify the difference to choose the statistics for the next block (F.1.4.4.1.2)
+            self.dc_context[scan_idx] = if magnitude < (1 << bounds.0) >> 1 {
+                0
+            } else if magnitude > (1 << bounds.1) >> 1 {
+                12 + sign * 4
+            } else {
+                4 + sign * 4
+            };
+
+            let diff = magnitude_bits(&mut self.decoder, &mut stats[bin + 14], magnitude) + 1;
+            self.predictions[scan_idx] += if sign == 1 { -diff } else { diff };
+        }
+        block[0] = i16::try_from(self.predictions[scan_idx] << approx_low)
+            .map_err(|_| EntropyErrorKind::CoefficientOverflow)?;
+        Ok(())
+    }
+
+    /// Decode a band of AC coefficients (ITU T.81 F.2.4.2), scaled by `approx_low`.
+    /// `threshold` is the conditioning Kx of the table.
+    pub(crate) fn decode_ac(
+        &mut self,
+        table: usize,
+        threshold: u8,
+        band: (u8, u8),
+        approx_low: u8,
+        block: &mut [i16; 64],
+    ) -> std::result::Result<(), EntropyErrorKind> {
+        let (start, end) = (band.0 as usize, band.1 as usize);
+        let mut k = start;
+        while k <= end {
+            let stats = &mut self.ac_stats[table];
+            let mut bin = 3 * (k - 1);
+            if self.decoder.decode(&mut stats[bin]) {
+                // EOB
+                break;
+            }
+            while !self.decoder.decode(&mut stats[bin + 1]) {
+                bin += 3;
+                k += 1;
+                if k > end {
+                    return Err(EntropyErrorKind::CoefficientOverflow);
+                }
+            }
+
+            let negative = self.decoder.decode(&mut self.fixed);
+            bin += 2;
+            let mut magnitude = 0i32;
+            if self.decoder.decode(&mut stats[bin]) {
+                magnitude = 1;
+                if self.decoder.decode(&mut stats[bin]) {
+                    magnitude <<= 1;
+                    bin = if k <= threshold as usize { 189 } else { 217 };
+                    while self.decoder.decode(&mut stats[bin]) {
+                        magnitude <<= 1;
+                        if magnitude == 0x8000 {
+                            return Err(EntropyErrorKind::CoefficientOverflow);
+                        }
+                        bin += 1;
+                    }
+                }
+            }
+            let value = magnitude_bits(&mut self.decoder, &mut stats[bin + 14], magnitude) + 1;
+            let value = if negative { -value } else { value };
+            block[ZIGZAG[k]] = i16::try_from(value << approx_low)
+                .map_err(|_| EntropyErrorKind::CoefficientOverflow)?;
+            k += 1;
+        }
+        Ok(())
+    }
+
+    /// Add the next bit of a block's DC coefficient (ITU T.81 G.1.3.1)
+    pub(crate) fn decode_dc_refine(&mut self, approx_low: u8, block: &mut [i16; 64]) {
+        if self.decoder.decode(&mut self.fixed) {
+            block[0] |= 1 << approx_low;
+        }
+    }
+
+    /// Add the next bit of a band of AC coefficients (ITU T.81 G.1.3.3)
+    pub(crate) fn decode_ac_refine(
+        &mut self,
+        table: usize,
+        band: (u8, u8),
+        approx_low: u8,
+        block: &mut [i16; 64],
+    ) -> std::result::Result<(), EntropyErrorKind> {
+        let (start, end) = (band.0 as usize, band.1 as usize);
+        let bit = 1i16 << approx_low;
+        // The end of block position of the previous stage, before which EOB cannot be coded
+        let eob = (1..=end)
+            .rev()
+            .find(|k| block[ZIGZAG[*k]] != 0)
+            .unwrap_or(0);
+
+        let mut k = start;
+        while k <= end {
+            let stats = &mut self.ac_stats[table];
+            let mut bin = 3 * (k - 1);
+            if k > eob && self.decoder.decode(&mut stats[bin]) {
+                break;
+            }
+            loop {
+                let coefficient = &mut block[ZIGZAG[k]];
+                if *coefficient != 0 {
+                    if self.decoder.decode(&mut stats[bin + 2]) {
+                        let step = if *coefficient < 0 { -bit } else { bit };
+                        *coefficient = coefficient
+                            .checked_add(step)
+                            .ok_or(EntropyErrorKind::CoefficientOverflow)?;
+                    }
+                    break;
+                }
+                if self.decoder.decode(&mut stats[bin + 1]) {
+                    // Newly non-zero coefficients can only be +/-1 at this bit position
+                    *coefficient = if self.decoder.decode(&mut self.fixed) {
+                        -bit
+                    } else {
+                        bit
+                    };
+                    break;
+                }
+                bin += 3;
+                k += 1;
+                if k > end {
+                    return Err(EntropyErrorKind::CoefficientOverflow);
+                }
+            }
+            k += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Decode the bits below the leading one of `magnitude` (ITU T.81 Figure F.24)
+fn magnitude_bits(decoder: &mut QmDecoder, state: &mut u8, magnitude: i32) -> i32 {
+    let mut value = magnitude;
+    let mut bit = magnitude >> 1;
+    while bit > 0 {
+        if decoder.decode(state) {
+            value |= bit;
+        }
+        bit >>= 1;
+    }
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn position_is_the_last_byte_read() {
+        let data = [0x12, 0xFF, 0x00, 0x34];
+        let mut decoder = QmDecoder::new(&data, 0, true);
+        let mut state = 0;
+        decoder.decode(&mut state);
+        // The first decision reads two bytes, the second of them a stuffed 0xFF
+        assert_eq!(decoder.consumed, 1);
+        assert_eq!(decoder.pos, 3);
+    }
+
+    #[test]
+    fn reading_past_a_truncated_end_is_an_overrun() {
+        let data = [0x12, 0x34];
+        for terminated in [true, false] {
+            let mut decoder = QmDecoder::new(&data, 0, terminated);
+            let mut state = 0;
+            for _ in 0..64 {
+                decoder.decode(&mut state);
+            }
+            assert_eq!(decoder.consumed, data.len());
+            assert_eq!(decoder.overrun, !terminated);
+        }
+    }
+}
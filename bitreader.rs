@@ -0,0 +1,292 @@
+use crate::{error::EntropyErrorKind, marker::JpegMarker};
+
+/// Reads bits, most significant first, from the entropy-coded data of a scan.
+/// Stuffed bytes (0xFF 0x00) are removed, and reading stops at the first marker, after which
+/// zero bits are supplied so that lookahead never runs dry.
+pub(crate) struct BitReader<'a> {
+    /// The file, ending at the end of the scan's entropy-coded data
+    data: &'a [u8],
+    /// File offset of the next byte to be loaded into `bits`
+    pos: usize,
+    /// Bits not yet consumed, aligned to the most significant bit
+    bits: u64,
+    /// Number of valid bits in `bits`
+    count: u32,
+    /// How many of the bits in `bits` are zero padding appended after the end of the data
+    padding: u32,
+    /// The marker which stopped the reader, if one was found
+    marker: Option<JpegMarker>,
+    /// File offset of each of the last bytes loaded into `bits`, indexed by `loaded` modulo their
+    /// number. Stuffed bytes take two bytes of the file, so these are not simply consecutive.
+    offsets: [usize; 16],
+    /// Number of bytes of data (i.e not padding) loaded since the reader started or restarted
+    loaded: usize,
+}
+
+impl<'a> BitReader<'a> {
+    /// Read the data from `start` up to the end of `data`
+    pub(crate) fn new(data: &'a [u8], start: usize) -> BitReader<'a> {
+        BitReader {
+            data,
+            pos: start,
+            bits: 0,
+            count: 0,
+            padding: 0,
+            marker: None,
+            offsets: [0; 16],
+            loaded: 0,
+        }
+    }
+
+    /// File offset of the byte containing the next unconsumed bit, or of the next byte to be
+    /// loaded if every bit of data loaded so far has been consumed
+    pub(crate) fn position(&self) -> usize {
+        let unconsumed = (self.count.saturating_sub(self.padding) as usize).div_ceil(8);
+        if unconsumed == 0 {
+            return self.pos.min(self.data.len());
+        }
+        self.offsets[(self.loaded - unconsumed) % self.offsets.len()]
+    }
+
+    /// Whether more bits have been consumed than the data contained
+    pub(crate) fn overrun(&self) -> bool {
+        self.count < self.padding
+    }
+
+    /// The marker which stopped the reader, if one was found
+    pub(crate) fn marker(&self) -> Option<JpegMarker> {
+        self.marker
+    }
+
+    /// Top up `bits` so that at least 57 bits are available
+    pub(crate) fn fill(&mut self) {
+        while self.count <= 56 {
+            let byte = match self.next_byte() {
+                Some(byte) => {
+                    // 0xFF is only ever returned for a stuffed 0xFF 0x00
+                    let offset = self.pos - if byte == 0xFF { 2 } else { 1 };
+                    self.offsets[self.loaded % self.offsets.len()] = offset;
+                    self.loaded += 1;
+                    byte
+                }
+                None => {
+                    self.padding += 8;
+                    0
+                }
+            };
+            self.bits |= (byte as u64) << (56 - self.count);
+            self.count += 8;
+        }
+    }
+
+    fn next_byte(&mut self) -> Option<u8> {
+        if self.marker.is_some() {
+            return None;
+        }
+        let byte = *self.data.get(self.pos)?;
+        if byte != 0xFF {
+            self.pos += 1;
+            return Some(byte);
+        }
+        match self.data.get(self.pos + 1) {
+            Some(0x00) => {
+                self.pos += 2;
+                Some(0xFF)
+            }
+            // Fill bytes may precede a marker
+            Some(0xFF) => {
+                self.pos += 1;
+                self.next_byte()
+            }
+            Some(marker) => {
+                self.marker = Some(JpegMarker::from_u8(*marker));
+                None
+            }
+            None => {
+                self.pos += 1;
+                None
+            }
+        }
+    }
+
+    /// Look at the next `n` (<= 32) bits without consuming them
+    pub(crate) fn peek(&mut self, n: u32) -> u32 {
+        if self.count < n {
+            self.fill();
+        }
+        (self.bits >> (64 - n)) as u32
+    }
+
+    pub(crate) fn consume(&mut self, n: u32) {
+        self.bits <<= n;
+        self.count -= n;
+    }
+
+    /// Read `n` (<= 16) bits as an unsigned integer
+    pub(crate) fn get_bits(&mut self, n: u32) -> u32 {
+        if n == 0 {
+            return 0;
+        }
+        let value = self.peek(n);
+        self.consume(n);
+        value
+    }
+
+    pub(crate) fn get_bit(&mut self) -> bool {
+        self.get_bits(1) == 1
+    }
+
+    /// Read an `s` bit value and extend it to a signed integer (ITU T.81 F.2.2.1, EXTEND)
+    pub(crate) fn receive_extend(&mut self, s: u32) -> i32 {
+        if s == 0 {
+            return 0;
+        }
+        let value = self.get_bits(s) as i32;
+        if value < 1 << (s - 1) {
+            value - (1 << s) + 1
+        } else {
+            value
+        }
+    }
+
+    /// Discard the rest of the current restart interval without decoding it, moving to the marker
+    /// which ends it. The RSTn marker is then read by `restart` as usual.
+    pub(crate) fn skip_to_marker(&mut self) {
+        self.bits = 0;
+        self.count = 0;
+        self.padding = 0;
+        while self.marker.is_none() {
+            // Only an 0xFF byte can begin a marker
+            match self.data[self.pos.min(self.data.len())..]
+                .iter()
+                .position(|b| *b == 0xFF)
+            {
+                Some(skip) => self.pos += skip,
+                None => {
+                    self.pos = self.data.len();
+                    return;
+                }
+            }
+            self.next_byte();
+        }
+    }
+
+    /// Discard any bits left in the current byte and move past the RSTn marker which should follow.
+    /// Returns the restart number (0 -> 7) of the marker.
+    pub(crate) fn restart(&mut self) -> Result<u8, EntropyErrorKind> {
+        self.bits = 0;
+        self.count = 0;
+        self.padding = 0;
+        if self.marker.is_none() {
+            // The marker should immediately follow the bytes which have been loaded
+            self.next_byte();
+        }
+        match self.marker.take() {
+            Some(JpegMarker::RST(b)) => {
+                self.pos += 2;
+                Ok(b - 0xD0)
+            }
+            Some(marker) => {
+                self.marker = Some(marker);
+                Err(EntropyErrorKind::UnexpectedMarker(marker))
+            }
+            None if self.pos >= self.data.len() => Err(EntropyErrorKind::PrematureEnd),
+            None => Err(EntropyErrorKind::MissingRestart),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Pack `(code, length)` pairs into entropy-coded bytes, most significant bit first, padding
+    /// with 1 bits and stuffing a zero byte after each 0xFF
+    pub(crate) fn pack(codes: &[(u32, u32)]) -> Vec<u8> {
+        let (mut bytes, mut acc, mut count) = (Vec::new(), 0u64, 0);
+        for &(code, length) in codes.iter().chain([(0x7F, 7)].iter()) {
+            acc = (acc << length) | code as u64;
+            count += length;
+            while count >= 8 {
+                count -= 8;
+                bytes.push((acc >> count) as u8);
+            }
+        }
+        bytes
+            .into_iter()
+            .flat_map(|byte| match byte {
+                0xFF => vec![0xFF, 0x00],
+                _ => vec![byte],
+            })
+            .collect()
+    }
+
+    #[test]
+    fn stuffed_bytes_are_removed_and_markers_stop_reading() {
+        let data = [0xAB, 0xFF, 0x00, 0xCD, 0xFF, 0xD9];
+        let mut reader = BitReader::new(&data, 0);
+        assert_eq!(reader.get_bits(8), 0xAB);
+        assert_eq!(reader.get_bits(16), 0xFFCD);
+        assert!(!reader.overrun());
+        assert_eq!(reader.marker(), Some(JpegMarker::END));
+        // Zero bits are supplied after the marker
+        assert_eq!(reader.get_bits(8), 0);
+        assert!(reader.overrun());
+    }
+
+    #[test]
+    fn receive_extend_gives_negative_values_below_half_the_range() {
+        let data = [0b0110_1100, 0b1000_0000];
+        let mut reader = BitReader::new(&data, 0);
+        assert_eq!(reader.receive_extend(2), -2);
+        assert_eq!(reader.receive_extend(2), 2);
+        assert_eq!(reader.receive_extend(3), 6);
+        assert_eq!(reader.receive_extend(0), 0);
+        assert_eq!(reader.receive_extend(1), -1);
+        assert_eq!(reader.receive_extend(1), 1);
+    }
+
+    #[test]
+    fn restart_moves_past_the_next_rst_marker() {
+        let data = [0x12, 0xFF, 0xD3, 0x34, 0xFF, 0xD9];
+        let mut reader = BitReader::new(&data, 0);
+        reader.get_bits(3);
+        assert_eq!(reader.restart(), Ok(3));
+        assert_eq!(reader.get_bits(8), 0x34);
+        assert_eq!(
+            reader.restart(),
+            Err(EntropyErrorKind::UnexpectedMarker(JpegMarker::END))
+        );
+    }
+
+    #[test]
+    fn position_accounts_for_stuffed_bytes() {
+        let data = [0xAB, 0xFF, 0x00, 0xCD, 0xFF, 0x00, 0xFF, 0x00, 0x12];
+        let mut reader = BitReader::new(&data, 0);
+        assert_eq!(reader.position(), 0);
+        assert_eq!(reader.get_bits(8), 0xAB);
+        // The next bit is in the stuffed 0xFF, not the 0x00 which follows it
+        assert_eq!(reader.position(), 1);
+        assert_eq!(reader.get_bits(8), 0xFF);
+        assert_eq!(reader.position(), 3);
+        assert_eq!(reader.get_bits(4), 0xC);
+        assert_eq!(reader.position(), 3);
+        assert_eq!(reader.get_bits(12), 0xDFF);
+        assert_eq!(reader.position(), 6);
+        assert_eq!(reader.get_bits(16), 0xFF12);
+        assert_eq!(reader.position(), data.len());
+        assert!(!reader.overrun());
+    }
+
+    #[test]
+    fn position_stops_at_a_marker() {
+        let data = [0x12, 0xFF, 0xFF, 0xD9];
+        let mut reader = BitReader::new(&data, 0);
+        assert_eq!(reader.get_bits(8), 0x12);
+        // Fill bytes before the marker are skipped, leaving the reader at the marker itself
+        assert_eq!(reader.position(), 2);
+        reader.get_bits(8);
+        assert!(reader.overrun());
+        assert_eq!(reader.marker(), Some(JpegMarker::END));
+    }
+}
@@ -0,0 +1,94 @@
+use std::fmt;
+
+use crate::{
+    adobe::{AdobeSegment, AdobeTransform},
+    frame::ImgProps,
+    image::Sample,
+};
+
+/// The colour space the components of an image are stored in
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ColorSpace {
+    Grayscale,
+    YCbCr,
+    Rgb,
+    Cmyk,
+    /// YCbCr and K, as written by Adobe applications
+    Ycck,
+}
+
+impl ColorSpace {
+    /// Infer the colour space of a frame as the IJG's decoder does. A JFIF segment implies YCbCr,
+    /// then the transform of an Adobe segment decides, and failing those the component identifiers
+    /// 'R', 'G' and 'B' mark RGB. Lossless frames have no colour transform of their own, so three
+    /// components are RGB unless one of those segments says YCbCr. Returns None for frames with 2
+    /// or more than 4 components.
+    pub(crate) fn detect(
+        props: &ImgProps,
+        lossless: bool,
+        jfif: bool,
+        adobe: Option<&AdobeSegment>,
+    ) -> Option<ColorSpace> {
+        let transform = adobe.map(|adobe| adobe.transform);
+        let ids: Vec<u8> = props.components.iter().map(|c| c.id).collect();
+        match ids.len() {
+            1 => Some(ColorSpace::Grayscale),
+            3 if jfif => Some(ColorSpace::YCbCr),
+            3 if lossless && transform != Some(AdobeTransform::YCbCr) => Some(ColorSpace::Rgb),
+            3 if transform == Some(AdobeTransform::Unknown) => Some(ColorSpace::Rgb),
+            3 if transform.is_none() && ids == b"RGB" => Some(ColorSpace::Rgb),
+            3 => Some(ColorSpace::YCbCr),
+            4 if transform.is_none() || transform == Some(AdobeTransform::Unknown) => {
+                Some(ColorSpace::Cmyk)
+            }
+            4 => Some(ColorSpace::Ycck),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ColorSpace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ColorSpace::Grayscale => "Grayscale",
+            ColorSpace::YCbCr => "YCbCr",
+            ColorSpace::Rgb => "RGB",
+            ColorSpace::Cmyk => "CMYK",
+            ColorSpace::Ycck => "YCCK",
+        })
+    }
+}
+
+/// Convert a JFIF YCbCr sample of `precision` bits to RGB (ITU T.871 section 7), using 16 bit
+/// fixed point arithmetic. The chroma terms are rounded before being added to Y, as the IJG's decoder does.
+/// The products are 64 bit, as those of 16 bit lossless samples overflow 32 bits.
+#[inline]
+pub(crate) fn ycbcr_to_rgb<T: Sample>(y: T, cb: T, cr: T, precision: usize) -> [T; 3] {
+    let center = 1 << (precision - 1);
+    let y = y.into() as i64;
+    let cb = cb.into() as i64 - center;
+    let cr = cr.into() as i64 - center;
+    let half = 1 << 15;
+    let r = y + ((91881 * cr + half) >> 16);
+    let g = y + ((-22554 * cb - 46802 * cr + half) >> 16);
+    let b = y + ((116130 * cb + half) >> 16);
+    [r, g, b].map(|c| T::from_u32(c.clamp(0, (1 << precision) - 1) as u32))
+}
+
+/// Convert a YCCK sample to CMYK by converting YCbCr to RGB and complementing it, as the IJG's
+/// decoder does. K is unchanged.
+#[inline]
+pub(crate) fn ycck_to_cmyk<T: Sample>(y: T, cb: T, cr: T, k: T, precision: usize) -> [T; 4] {
+    let max = (1 << precision) - 1;
+    let [r, g, b] = ycbcr_to_rgb(y, cb, cr, precision).map(|c| T::from_u32(max - c.into()));
+    [r, g, b, k]
+}
+
+/// Convert an inverted CMYK sample (where 0 is full coverage of ink, as Adobe applications
+/// write it) to RGB, by scaling each of C, M and Y by K
+#[inline]
+pub(crate) fn inverted_cmyk_to_rgb<T: Sample>(cmyk: [T; 4], precision: usize) -> [T; 3] {
+    let max = (1 << precision) - 1;
+    let k = cmyk[3].into();
+    [cmyk[0], cmyk[1], cmyk[2]].map(|c| T::from_u32((c.into() * k + max / 2) / max))
+}
@@ -0,0 +1,2327 @@
+use std::{ops::Range, thread};
+
+use crate::{
+    adobe::{self, AdobeSegment},
+    arithmetic::{self, ArithmeticScan},
+    bitreader::BitReader,
+    color::ColorSpace,
+    error::{EntropyErrorKind, JpegError, Result},
+    frame::{self, ImgProps},
+    huffman::{self, HuffmanDecoder, TableClass},
+    idct::idct_block_scaled,
+    image::{Conversion, Image, Image16, Region, Scale},
+    lossless::{self, SamplePlane},
+    marker::JpegMarker,
+    parallel, progressive,
+    quant::{self, QuantTable, ZIGZAG},
+    scan::{self, ScanHeader},
+    segment::{EntropyData, Segment, SegmentReader},
+    stream::Scanlines,
+};
+
+/// Quantized DCT coefficients of a single component
+#[derive(Debug, Clone)]
+pub struct ComponentCoefficients {
+    /// Component identifier (Ci)
+    pub id: u8,
+    /// Number of blocks in each row of `blocks`, including any padding needed to complete the final MCU
+    pub blocks_per_line: usize,
+    /// Number of rows of blocks, including padding
+    pub block_rows: usize,
+    /// Coefficients of every block in raster order, each block in natural (row-major) order
+    pub blocks: Vec<[i16; 64]>,
+    /// Quantization table (in natural order) used by this component, if it was defined
+    pub quant_table: Option<[u16; 64]>,
+}
+
+/// Samples of a single component of a sequential frame decoded at a reduced scale. Each block is
+/// transformed as soon as it is decoded, so its coefficients need not be kept.
+#[derive(Debug, Clone)]
+pub(crate) struct ComponentSamples {
+    pub(crate) blocks_per_line: usize,
+    /// Width and height of the samples of each block
+    pub(crate) size: usize,
+    /// Samples of every row of blocks, `blocks_per_line * size` to a row
+    pub(crate) samples: Vec<u16>,
+}
+
+impl ComponentSamples {
+    /// Number of samples in each row
+    pub(crate) fn stride(&self) -> usize {
+        self.blocks_per_line * self.size
+    }
+}
+
+/// Some rows of blocks of the samples of a component, which the blocks of a scan are transformed into
+struct SampleRows<'s> {
+    samples: &'s mut [u16],
+    blocks_per_line: usize,
+    size: usize,
+    quant: [u16; 64],
+    precision: usize,
+}
+
+impl<'s> SampleRows<'s> {
+    /// Split off the first `rows` rows of blocks, leaving the rest
+    fn split_rows(&mut self, rows: usize) -> SampleRows<'s> {
+        let len = (rows * self.blocks_per_line * self.size * self.size).min(self.samples.len());
+        let (first, rest) = std::mem::take(&mut self.samples).split_at_mut(len);
+        self.samples = rest;
+        SampleRows {
+            samples: first,
+            blocks_per_line: self.blocks_per_line,
+            size: self.size,
+            quant: self.quant,
+            precision: self.precision,
+        }
+    }
+
+    /// Number of samples in each row
+    fn stride(&self) -> usize {
+        self.blocks_per_line * self.size
+    }
+
+    /// Where the samples of the block at `n` (in raster order) start
+    fn start(&self, n: usize) -> usize {
+        (n / self.blocks_per_line * self.stride() + n % self.blocks_per_line) * self.size
+    }
+
+    /// Dequantize and transform `block` into the samples of the block at `n`
+    fn store(&mut self, n: usize, block: &[i16; 64]) {
+        let (start, stride) = (self.start(n), self.stride());
+        let out = &mut self.samples[start..];
+        idct_block_scaled(block, &self.quant, out, stride, self.precision, self.size);
+    }
+
+    /// Copy the samples of the block at `source` over those of the block at `n`, or make them
+    /// grey (as a block without coefficients would be) if there is no source
+    fn copy(&mut self, n: usize, source: Option<usize>) {
+        let (start, stride, size) = (self.start(n), self.stride(), self.size);
+        for row in 0..size {
+            let at = start + row * stride;
+            match source {
+                Some(source) => {
+                    let from = self.start(source) + row * stride;
+                    self.samples.copy_within(from..from + size, at);
+                }
+                None => self.samples[at..at + size].fill(1 << (self.precision - 1)),
+            }
+        }
+    }
+}
+
+/// The quantized DCT coefficients of every component of an image, as stored in its entropy-coded data
+#[derive(Debug, Clone)]
+pub struct Coefficients {
+    pub props: ImgProps,
+    pub components: Vec<ComponentCoefficients>,
+}
+
+/// MCUs of a scan which could not be decoded, and were concealed instead
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Damage {
+    /// Index of the scan, counting from 0 in the order they appear
+    pub scan: usize,
+    /// The MCUs lost, numbered in raster order across the scan
+    pub mcus: Range<usize>,
+    /// Number of MCUs in each row of the scan, so that `mcus` can be turned into rows and columns
+    pub mcus_per_line: usize,
+    /// File offset at which decoding failed
+    pub offset: usize,
+    pub kind: EntropyErrorKind,
+}
+
+impl Damage {
+    fn new(
+        scan: usize,
+        mcus: Range<usize>,
+        layout: &ScanLayout,
+        offset: usize,
+        kind: EntropyErrorKind,
+    ) -> Damage {
+        Damage {
+            scan,
+            mcus,
+            mcus_per_line: layout.mcus_per_line,
+            offset,
+            kind,
+        }
+    }
+}
+
+/// Where the entropy-coded data of a file first failed to decode, as found by `Decoder::check`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fault {
+    /// Index of the scan, counting from 0 in the order they appear
+    pub scan: usize,
+    /// File offset of the byte at which decoding failed
+    pub offset: usize,
+    /// Row and column of the MCU being decoded, counting MCUs of the scan. For a missing or
+    /// out of order RSTn marker this is the first MCU of the interval it should have begun.
+    pub mcu_row: usize,
+    pub mcu_column: usize,
+    /// Identifier (Ci) of the component being decoded, or None if the data failed between MCUs
+    /// (i.e at a restart marker)
+    pub component: Option<u8>,
+    pub kind: EntropyErrorKind,
+}
+
+impl Fault {
+    /// The fault behind `error`, found decoding MCU `mcu` (and the component at `scan_idx` of
+    /// the scan, if any). None unless `error` is an entropy-coding error.
+    fn new(
+        scan: usize,
+        header: &ScanHeader,
+        layout: &ScanLayout,
+        mcu: usize,
+        scan_idx: Option<usize>,
+        error: &JpegError,
+    ) -> Option<Fault> {
+        let JpegError::Entropy { offset, kind, .. } = error else {
+            return None;
+        };
+        Some(Fault {
+            scan,
+            offset: *offset,
+            mcu_row: mcu / layout.mcus_per_line,
+            mcu_column: mcu % layout.mcus_per_line,
+            component: scan_idx.map(|idx| header.components[idx].selector),
+            kind: *kind,
+        })
+    }
+}
+
+/// An image decoded by `Decoder::recover` despite errors in its data, along with what was lost
+#[derive(Debug)]
+pub struct Recovered<T> {
+    pub image: T,
+    /// Every run of MCUs which was concealed, in the order they were found
+    pub damage: Vec<Damage>,
+    /// The error which stopped the file being read before its end, if any.
+    /// Any scans after it are missing from the image.
+    pub stopped: Option<JpegError>,
+}
+
+/// Default for `Decoder::with_memory_limit`, enough for the coefficients of roughly 350 megapixels
+/// of 4:2:0 YCbCr
+pub const DEFAULT_MEMORY_LIMIT: usize = 1 << 30;
+
+/// Decodes the entropy-coded data of a JPEG held in memory.
+///
+/// `decode` turns sequential and progressive DCT images, Huffman or arithmetic coded, into 8 bit
+/// RGB or greyscale pixels. Four component images come out as CMYK with Adobe's inversion undone,
+/// unless `with_cmyk_to_rgb` asks for RGB, and 12 bit and lossless images need `decode_16`.
+/// The `with_` methods pick a scale, a region and the number of threads beforehand:
+///
+/// ```no_run
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use jpeg_parser::{Decoder, Region, Scale};
+///
+/// let data = std::fs::read("image.jpeg")?;
+/// let thumbnail = Decoder::new(&data).with_scale(Scale::Eighth).decode()?;
+/// let tile = Decoder::new(&data)
+///     .with_region(Region { x: 512, y: 256, width: 256, height: 256 })
+///     .decode()?;
+/// let image = Decoder::new(&data).with_threads(0).decode_16()?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Damaged files can be decoded with `recover`, which conceals what cannot be read and reports
+/// the MCUs lost, or just checked with `check`, which finds where the data first fails without
+/// producing any pixels. Baseline images can be streamed a row at a time with `scanlines`:
+///
+/// ```no_run
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// # use jpeg_parser::Decoder;
+/// # let data = std::fs::read("image.jpeg")?;
+/// let recovered = Decoder::new(&data).recover()?;
+/// for damage in &recovered.damage {
+///     println!("scan {} lost MCUs {:?}: {}", damage.scan, damage.mcus, damage.kind);
+/// }
+/// if let Some(fault) = Decoder::new(&data).check()? {
+///     println!("{} at offset {}", fault.kind, fault.offset);
+/// }
+/// for row in Decoder::new(&data).scanlines()? {
+///     let row = row?;
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct Decoder<'a> {
+    data: &'a [u8],
+    /// The SOFn marker of the frame, along with its offset
+    process: Option<(JpegMarker, usize)>,
+    frame: Option<ImgProps>,
+    quant_tables: [Option<QuantTable>; 4],
+    dc_tables: [Option<HuffmanDecoder>; 4],
+    ac_tables: [Option<HuffmanDecoder>; 4],
+    /// Arithmetic coding conditioning (L, U) of each DC table
+    dc_conditioning: [(u8, u8); 4],
+    /// Arithmetic coding conditioning (Kx) of each AC table
+    ac_conditioning: [u8; 4],
+    /// Number of MCUs in each restart interval, or 0 if restart markers are not used
+    restart_interval: usize,
+    components: Vec<ComponentCoefficients>,
+    /// Samples of each component of a sequential frame decoded at a reduced scale, in which case
+    /// no coefficients are kept in `components`
+    transformed: Vec<ComponentSamples>,
+    /// Samples of each component of a lossless frame
+    planes: Vec<SamplePlane>,
+    /// Whether a JFIF APP0 segment was found, which implies YCbCr
+    jfif: bool,
+    /// The last Adobe APP14 segment, which describes the colour transform of the frame
+    adobe: Option<AdobeSegment>,
+    cmyk_to_rgb: bool,
+    scale: Scale,
+    /// Whether pixels are produced as the scan is read, so that no coefficients are stored
+    streaming: bool,
+    region: Option<Region>,
+    /// The MCUs covering `region`, once the frame is known
+    window: Option<Window>,
+    /// Number of threads to decode with
+    threads: usize,
+    /// Most bytes which may be allocated for the coefficients or samples of the frame
+    memory_limit: usize,
+    /// Whether errors in the entropy-coded data are concealed rather than returned
+    tolerant: bool,
+    /// Number of scans read so far
+    scans: usize,
+    damage: Vec<Damage>,
+    /// The error which stopped a tolerant decode early
+    stopped: Option<JpegError>,
+    /// Where the last entropy-coding error was found
+    fault: Option<Fault>,
+}
+
+/// What a scan codes for each block, following ITU T.81 G.1.1.1
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum ScanKind {
+    /// Every coefficient, in a single pass
+    Sequential,
+    /// The most significant bits of the DC coefficient
+    DcFirst,
+    /// One further bit of the DC coefficient
+    DcRefine,
+    /// The most significant bits of a band of AC coefficients
+    AcFirst,
+    /// One further bit of a band of AC coefficients
+    AcRefine,
+}
+
+impl ScanKind {
+    /// Classify a scan, checking that its parameters are allowed by the coding process
+    fn new(progressive: bool, header: &ScanHeader, offset: usize) -> Result<ScanKind> {
+        if !progressive {
+            return Ok(ScanKind::Sequential);
+        }
+        let malformed = |reason| JpegError::Malformed {
+            offset,
+            marker: JpegMarker::SOS,
+            reason,
+        };
+        let (start, end) = (header.spectral_start, header.spectral_end);
+        if start > end || end > 63 {
+            return Err(malformed("spectral selection is out of range"));
+        }
+        if header.approx_low > 13 {
+            return Err(malformed(
+                "successive approximation bit position is out of range",
+            ));
+        }
+        match (start, header.approx_high) {
+            (0, _) if end != 0 => Err(malformed("DC and AC coefficients cannot share a scan")),
+            (0, 0) => Ok(ScanKind::DcFirst),
+            (0, _) => Ok(ScanKind::DcRefine),
+            _ if header.components.len() != 1 => Err(malformed(
+                "scans of AC coefficients must contain a single component",
+            )),
+            (_, 0) => Ok(ScanKind::AcFirst),
+            _ => Ok(ScanKind::AcRefine),
+        }
+    }
+}
+
+/// The part of a frame decoded when only a region of the image is wanted: a rectangle of MCUs
+/// covering the region, with a margin of one MCU so that upsampling near the edges of the region
+/// uses the same neighbouring samples as when decoding the whole image
+#[derive(Debug, Clone)]
+struct Window {
+    mcu_x: Range<usize>,
+    mcu_y: Range<usize>,
+    /// The wanted region clipped to the image, in decoded (possibly scaled) pixels
+    region: Region,
+}
+
+impl Window {
+    /// The window around `region` of the frame described by `segment`, or an error if the region
+    /// is empty or starts outside the image. Regions reaching beyond the image are clipped to it.
+    fn new(segment: &Segment, props: &ImgProps, region: Region, scale: Scale) -> Result<Window> {
+        let denominator = scale.denominator();
+        let (width, height) = (
+            props.width.div_ceil(denominator),
+            props.height.div_ceil(denominator),
+        );
+        if region.width == 0 || region.height == 0 || region.x >= width || region.y >= height {
+            return Err(JpegError::BadRegion {
+                offset: segment.offset,
+                marker: segment.marker,
+                region,
+                width,
+                height,
+            });
+        }
+        let (x, y) = (region.x, region.y);
+        let region = Region {
+            x,
+            y,
+            width: region.width.min(width - x),
+            height: region.height.min(height - y),
+        };
+        // MCUs covering `len` pixels from `start`, in full size pixels, with a margin
+        let cover = |start: usize, len: usize, mcu_size: usize, count: usize| {
+            let end = ((start + len) * denominator).div_ceil(mcu_size) + 1;
+            let start = (start * denominator / mcu_size).saturating_sub(1);
+            start.min(count)..end.min(count)
+        };
+        Ok(Window {
+            mcu_x: cover(x, region.width, props.mcu_width(), props.mcus_per_line()),
+            mcu_y: cover(y, region.height, props.mcu_height(), props.mcu_rows()),
+            region,
+        })
+    }
+
+    /// Columns and rows of the blocks of the component at `idx` within the window
+    fn blocks(&self, props: &ImgProps, idx: usize) -> (Range<usize>, Range<usize>) {
+        let c = &props.components[idx];
+        // The MCUs of a frame with a single component are single blocks
+        let (h, v) = match props.components.len() {
+            1 => (1, 1),
+            _ => (c.horizontal_sampling as usize, c.vertical_sampling as usize),
+        };
+        (
+            self.mcu_x.start * h..self.mcu_x.end * h,
+            self.mcu_y.start * v..self.mcu_y.end * v,
+        )
+    }
+
+    /// The frame cut down to the window
+    fn props(&self, props: &ImgProps) -> ImgProps {
+        let (mcu_width, mcu_height) = (props.mcu_width(), props.mcu_height());
+        ImgProps {
+            width: props.width.min(self.mcu_x.end * mcu_width) - self.mcu_x.start * mcu_width,
+            height: props.height.min(self.mcu_y.end * mcu_height) - self.mcu_y.start * mcu_height,
+            ..props.clone()
+        }
+    }
+}
+
+/// The MCUs of a scan which are decoded, and where the blocks of each of its components are stored
+struct ScanWindow {
+    mcus_per_line: usize,
+    /// Columns and rows of the MCUs which hold wanted blocks
+    columns: Range<usize>,
+    rows: Range<usize>,
+    /// MCUs from here on are not decoded
+    end: usize,
+    /// Columns and rows of the stored blocks of each scan component
+    blocks: Vec<(Range<usize>, Range<usize>)>,
+}
+
+impl ScanWindow {
+    /// Whether any of `mcus` hold wanted blocks
+    fn wanted(&self, mut mcus: Range<usize>) -> bool {
+        mcus.end = mcus.end.min(self.end);
+        mcus.any(|mcu| {
+            self.columns.contains(&(mcu % self.mcus_per_line))
+                && self.rows.contains(&(mcu / self.mcus_per_line))
+        })
+    }
+
+    /// Index within its component of the stored block at column `x` and row `y` of scan component
+    /// `scan_idx`, or None if it is outside the window
+    fn block(&self, scan_idx: usize, x: usize, y: usize, blocks_per_line: usize) -> Option<usize> {
+        let (columns, rows) = &self.blocks[scan_idx];
+        (columns.contains(&x) && rows.contains(&y))
+            .then(|| (y - rows.start) * blocks_per_line + x - columns.start)
+    }
+
+    /// Where to decode the block at column `x` and row `y` of scan component `scan_idx`, with the
+    /// index of the stored block. Blocks outside the window, and those transformed straight into
+    /// samples, are decoded into `scratch`.
+    fn target<'b>(
+        &self,
+        (scan_idx, x, y): (usize, usize, usize),
+        component: &'b mut ComponentCoefficients,
+        samples: &Option<SampleRows>,
+        scratch: &'b mut [i16; 64],
+    ) -> (Option<usize>, &'b mut [i16; 64]) {
+        let stored = self.block(scan_idx, x, y, component.blocks_per_line);
+        match (stored, samples) {
+            (Some(idx), None) => (stored, &mut component.blocks[idx]),
+            _ => {
+                *scratch = [0; 64];
+                (stored, scratch)
+            }
+        }
+    }
+}
+
+/// The restart intervals of a scan, followed as its MCUs are decoded in order. Restart intervals
+/// are independent, so those holding no MCUs of the window are not decoded.
+struct Intervals<'w> {
+    /// MCUs in each interval, or 0 if the scan has no restart markers
+    length: usize,
+    window: &'w ScanWindow,
+    skipping: bool,
+}
+
+impl<'w> Intervals<'w> {
+    fn new(length: usize, window: &'w ScanWindow) -> Intervals<'w> {
+        Intervals {
+            length,
+            window,
+            skipping: false,
+        }
+    }
+
+    /// Whether an interval begins at `mcu`
+    fn begins(&self, mcu: usize) -> bool {
+        self.length > 0 && mcu.is_multiple_of(self.length)
+    }
+
+    /// Whether the RSTn marker ending the previous interval comes before `mcu`
+    fn restarts(&self, mcu: usize) -> bool {
+        mcu > 0 && self.begins(mcu)
+    }
+
+    /// Whether `mcu` is to be decoded. `skip` is called at the start of each interval which is
+    /// not, to move past its data.
+    fn wanted(&mut self, mcu: usize, skip: impl FnOnce()) -> bool {
+        if self.begins(mcu) {
+            self.skipping = !self.window.wanted(mcu..mcu + self.length);
+            if self.skipping {
+                skip();
+            }
+        }
+        !self.skipping
+    }
+}
+
+/// The order in which the blocks (or for lossless frames, the samples) of a scan are coded
+pub(crate) struct ScanLayout {
+    /// Index within the frame of each component in the scan
+    pub(crate) components: Vec<usize>,
+    pub(crate) mcus_per_line: usize,
+    pub(crate) mcu_rows: usize,
+    /// Sampling factors of each component in the scan. Non-interleaved scans always have a single 1x1 block per MCU.
+    sampling: Vec<(usize, usize)>,
+}
+
+impl ScanLayout {
+    /// Lay out a scan whose MCUs are built from `unit` x `unit` blocks of each component,
+    /// i.e 8 for DCT frames and 1 for lossless frames
+    fn new(
+        props: &ImgProps,
+        header: &ScanHeader,
+        offset: usize,
+        unit: usize,
+    ) -> Result<ScanLayout> {
+        let mut components = Vec::with_capacity(header.components.len());
+        for scan_component in &header.components {
+            match props
+                .components
+                .iter()
+                .position(|c| c.id == scan_component.selector)
+            {
+                Some(idx) if !components.contains(&idx) => components.push(idx),
+                _ => {
+                    return Err(JpegError::Malformed {
+                        offset,
+                        marker: JpegMarker::SOS,
+                        reason: "scan component selector does not match a frame component",
+                    })
+                }
+            }
+        }
+
+        if let [idx] = components[..] {
+            // A non-interleaved scan codes only the blocks which cover the component
+            Ok(ScanLayout {
+                mcus_per_line: props.component_width(idx).div_ceil(unit),
+                mcu_rows: props.component_height(idx).div_ceil(unit),
+                sampling: vec![(1, 1)],
+                components,
+            })
+        } else {
+            Ok(ScanLayout {
+                mcus_per_line: props.width.div_ceil(unit * props.max_horizontal_sampling()),
+                mcu_rows: props.height.div_ceil(unit * props.max_vertical_sampling()),
+                sampling: components
+                    .iter()
+                    .map(|idx| {
+                        let c = &props.components[*idx];
+                        (c.horizontal_sampling as usize, c.vertical_sampling as usize)
+                    })
+                    .collect(),
+                components,
+            })
+        }
+    }
+
+    fn mcu_count(&self) -> usize {
+        self.mcus_per_line * self.mcu_rows
+    }
+
+    /// The blocks making up MCU number `mcu`, as (index within the scan, block column, block row)
+    pub(crate) fn blocks(&self, mcu: usize) -> impl Iterator<Item = (usize, usize, usize)> + '_ {
+        let (mcu_x, mcu_y) = (mcu % self.mcus_per_line, mcu / self.mcus_per_line);
+        self.sampling
+            .iter()
+            .enumerate()
+            .flat_map(move |(idx, &(h, v))| {
+                (0..v).flat_map(move |y| (0..h).map(move |x| (idx, mcu_x * h + x, mcu_y * v + y)))
+            })
+    }
+}
+
+impl<'a> Decoder<'a> {
+    /// Create a decoder over a complete JPEG file
+    pub fn new(data: &'a [u8]) -> Decoder<'a> {
+        Decoder {
+            data,
+            process: None,
+            frame: None,
+            quant_tables: Default::default(),
+            dc_tables: Default::default(),
+            ac_tables: Default::default(),
+            dc_conditioning: [arithmetic::DEFAULT_DC_BOUNDS; 4],
+            ac_conditioning: [arithmetic::DEFAULT_AC_THRESHOLD; 4],
+            restart_interval: 0,
+            components: Vec::new(),
+            transformed: Vec::new(),
+            planes: Vec::new(),
+            jfif: false,
+            adobe: None,
+            cmyk_to_rgb: false,
+            scale: Scale::Full,
+            streaming: false,
+            region: None,
+            window: None,
+            threads: 1,
+            memory_limit: DEFAULT_MEMORY_LIMIT,
+            tolerant: false,
+            scans: 0,
+            damage: Vec::new(),
+            stopped: None,
+            fault: None,
+        }
+    }
+
+    /// Convert CMYK and YCCK images to RGB when decoding them, rather than returning CMYK pixels
+    pub fn with_cmyk_to_rgb(mut self, cmyk_to_rgb: bool) -> Decoder<'a> {
+        self.cmyk_to_rgb = cmyk_to_rgb;
+        self
+    }
+
+    /// Decode DCT images at a fraction of their size, rounding the dimensions up. Smaller scales
+    /// transform only the lowest frequencies of each block, which is much faster than decoding the
+    /// full image. The blocks of sequential images are transformed as they are decoded, keeping
+    /// only their samples rather than their coefficients. Lossless images are always decoded at
+    /// full size.
+    pub fn with_scale(mut self, scale: Scale) -> Decoder<'a> {
+        self.scale = scale;
+        self
+    }
+
+    /// Decode only `region` of the image, in the coordinates of the decoded (possibly scaled) image
+    /// and clipped to it. Decoding fails with `JpegError::BadRegion` if the region is empty or
+    /// starts outside the image. Only the MCUs around the region are kept, MCUs after it are not decoded
+    /// at all, and whole restart intervals before or beside it are skipped without being decoded.
+    /// Regions can only be decoded from sequential DCT images.
+    pub fn with_region(mut self, region: Region) -> Decoder<'a> {
+        self.region = Some(region);
+        self
+    }
+
+    /// Fail with `JpegError::TooLarge`, rather than allocate more than `limit` bytes for the
+    /// coefficients (or lossless samples) of the frame. The limit is checked when the frame header
+    /// is read, before any entropy-coded data, and defaults to `DEFAULT_MEMORY_LIMIT`.
+    pub fn with_memory_limit(mut self, limit: usize) -> Decoder<'a> {
+        self.memory_limit = limit;
+        self
+    }
+
+    /// Decode on up to `threads` threads, or as many as the machine can run at once if it is 0.
+    /// The restart intervals of sequential Huffman coded scans are decoded in parallel, as are the
+    /// transforms and colour conversion of every DCT image. Images without restart markers, regions
+    /// and scanlines are always decoded in order on the calling thread.
+    pub fn with_threads(mut self, threads: usize) -> Decoder<'a> {
+        self.threads = match threads {
+            0 => thread::available_parallelism().map_or(1, |n| n.get()),
+            n => n,
+        };
+        self
+    }
+
+    /// Decode every scan, returning the quantized DCT coefficients of each component.
+    /// Any region or scale set by `with_region` or `with_scale` is ignored.
+    pub fn coefficients(mut self) -> Result<Coefficients> {
+        (self.region, self.scale) = (None, Scale::Full);
+        self.read()?;
+        if let Some((marker @ JpegMarker::SOF(0xC3), offset)) = self.process {
+            // Lossless frames have no coefficients
+            return Err(JpegError::UnsupportedProcess { offset, marker });
+        }
+        self.into_coefficients()
+    }
+
+    /// Decode a sequential, Huffman coded image with 8 bit samples row by row whilst reading its
+    /// scan, returning pixels as `decode` would. Only the couple of MCU rows needed for the
+    /// current row are held, so memory grows with the width of the image rather than its area.
+    /// Every component has to be in the first scan. Any region set by `with_region` is ignored.
+    pub fn scanlines(mut self) -> Result<Scanlines<'a>> {
+        self.region = None;
+        self.streaming = true;
+        let mut segments = SegmentReader::new(self.data).with_stream_len(self.data.len());
+        crate::expect_start(&mut segments)?;
+        let scan = self.next_scan(&mut segments)?;
+        let (props, marker, frame_offset, conversion) = self.check_output()?;
+        let Some((header, entropy, offset)) = scan else {
+            return Err(JpegError::Entropy {
+                offset: segments.offset(),
+                marker: JpegMarker::SOS,
+                kind: EntropyErrorKind::PrematureEnd,
+            });
+        };
+        if !matches!(marker, JpegMarker::SOF(0xC0 | 0xC1)) || props.bit_depth != 8 {
+            return Err(JpegError::UnsupportedProcess {
+                offset: frame_offset,
+                marker,
+            });
+        }
+        let layout = ScanLayout::new(props, &header, offset, 8)?;
+        if layout.components.len() != props.components.len() {
+            // The components are coded in separate scans, so all but the last would need storing
+            return Err(JpegError::UnsupportedProcess {
+                offset,
+                marker: JpegMarker::SOS,
+            });
+        }
+        let mut tables = Vec::with_capacity(layout.components.len());
+        let huffman = huffman_tables(
+            &self.dc_tables,
+            &self.ac_tables,
+            &header,
+            ScanKind::Sequential,
+            offset,
+        )?;
+        for (idx, (dc, ac)) in layout.components.iter().zip(huffman) {
+            let quant = self.quant_table(props, *idx, offset)?;
+            if let (Some(dc), Some(ac)) = (dc, ac) {
+                tables.push((dc.clone(), ac.clone(), quant));
+            }
+        }
+        let reader = BitReader::new(
+            &self.data[..entropy.offset + entropy.length],
+            entropy.offset,
+        );
+        Ok(Scanlines::new(
+            reader,
+            props.clone(),
+            conversion,
+            layout,
+            tables,
+            self.restart_interval,
+        ))
+    }
+
+    /// The coefficients of the frame, or when only a region is wanted those of its window
+    fn into_coefficients(mut self) -> Result<Coefficients> {
+        Ok(Coefficients {
+            props: self.take_props()?,
+            components: self.components,
+        })
+    }
+
+    /// The frame (or its window) and the samples its components were transformed into as they
+    /// were decoded
+    fn into_samples(mut self) -> Result<(ImgProps, Vec<ComponentSamples>)> {
+        Ok((self.take_props()?, self.transformed))
+    }
+
+    /// The frame, or when only a region is wanted its window
+    fn take_props(&mut self) -> Result<ImgProps> {
+        let props = self.frame.take().ok_or(JpegError::MissingFrame {
+            offset: self.data.len(),
+            marker: None,
+        })?;
+        Ok(match &self.window {
+            Some(window) => window.props(&props),
+            None => props,
+        })
+    }
+
+    /// Decode the entropy-coded data of every scan without producing any pixels, to find where a
+    /// file is corrupt. Returns the first place the data fails to decode, or None if every scan
+    /// decodes. Errors in the segments themselves are returned as errors, as `coefficients` would.
+    pub fn check(mut self) -> Result<Option<Fault>> {
+        (self.region, self.scale, self.threads, self.tolerant) = (None, Scale::Full, 1, false);
+        match self.read() {
+            Ok(()) => Ok(None),
+            Err(e @ JpegError::Entropy { .. }) => match self.fault.take() {
+                Some(fault) => Ok(Some(fault)),
+                None => Err(e),
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Decode the image into RGB8 pixels, Gray8 if it has a single component or CMYK8 if it has four
+    /// (unless `with_cmyk_to_rgb` was set). Images must have 8 bit samples (see `decode_16` otherwise).
+    /// Lossless images are converted in the same way, except that three components are only taken
+    /// as YCbCr when a JFIF or Adobe segment says so.
+    pub fn decode(mut self) -> Result<Image> {
+        self.read()?;
+        self.render()
+    }
+
+    /// Decode the image as `decode` would, concealing corrupt or missing data rather than failing.
+    /// Where a Huffman coded scan cannot be decoded, decoding resumes after the next RSTn marker
+    /// (or gives up on the scan at EOI), and each lost MCU is copied from the one above it, or left
+    /// grey in the first row. Any other error found once the frame is known ends the decode early,
+    /// keeping the scans read so far. Restart intervals are always decoded in order.
+    pub fn recover(mut self) -> Result<Recovered<Image>> {
+        self.tolerant = true;
+        self.read()?;
+        let (damage, stopped) = (std::mem::take(&mut self.damage), self.stopped.take());
+        Ok(Recovered {
+            image: self.render()?,
+            damage,
+            stopped,
+        })
+    }
+
+    /// As `recover`, producing pixels as `decode_16` would
+    pub fn recover_16(mut self) -> Result<Recovered<Image16>> {
+        self.tolerant = true;
+        self.read()?;
+        let (damage, stopped) = (std::mem::take(&mut self.damage), self.stopped.take());
+        Ok(Recovered {
+            image: self.render_16()?,
+            damage,
+            stopped,
+        })
+    }
+
+    /// Turn the decoded frame into 8 bit pixels
+    fn render(self) -> Result<Image> {
+        let (props, marker, offset, conversion) = self.check_output()?;
+        if props.bit_depth != 8 {
+            return Err(JpegError::UnsupportedProcess { offset, marker });
+        }
+        if marker != JpegMarker::SOF(0xC3) {
+            let (crop, threads) = (self.crop(), self.threads);
+            let image = if self.transformed.is_empty() {
+                Image::from_coefficients(&self.into_coefficients()?, conversion, threads)
+            } else {
+                let (props, samples) = self.into_samples()?;
+                Image::from_transformed(&props, samples, conversion, threads)
+            };
+            return Ok(match crop {
+                Some(region) => image.crop(region),
+                None => image,
+            });
+        }
+        let image = Image16::from_samples(props, &self.planes, conversion);
+        Image::try_from(image).map_err(|_| JpegError::UnsupportedProcess { offset, marker })
+    }
+
+    /// Decode the image into RGB16, Gray16 or CMYK16 pixels as `decode` would, keeping every bit of
+    /// 12 bit and lossless images
+    pub fn decode_16(mut self) -> Result<Image16> {
+        self.read()?;
+        self.render_16()
+    }
+
+    /// Turn the decoded frame into 16 bit pixels
+    fn render_16(self) -> Result<Image16> {
+        let (props, marker, offset, conversion) = self.check_output()?;
+        if marker == JpegMarker::SOF(0xC3) {
+            return Ok(Image16::from_samples(props, &self.planes, conversion));
+        }
+        let (crop, threads) = (self.crop(), self.threads);
+        let image = match (props.bit_depth, self.transformed.is_empty()) {
+            (8, true) => {
+                Image::from_coefficients(&self.into_coefficients()?, conversion, threads).into()
+            }
+            (12, true) => {
+                Image16::from_coefficients(&self.into_coefficients()?, conversion, threads)
+            }
+            (8, false) => {
+                let (props, samples) = self.into_samples()?;
+                Image::from_transformed(&props, samples, conversion, threads).into()
+            }
+            (12, false) => {
+                let (props, samples) = self.into_samples()?;
+                Image16::from_transformed(&props, samples, conversion, threads)
+            }
+            _ => return Err(JpegError::UnsupportedProcess { offset, marker }),
+        };
+        Ok(match crop {
+            Some(region) => image.crop(region),
+            None => image,
+        })
+    }
+
+    /// Where the wanted region lies within the decoded window, if only a region is wanted
+    fn crop(&self) -> Option<Region> {
+        let (window, props) = (self.window.as_ref()?, self.frame.as_ref()?);
+        let denominator = self.scale.denominator();
+        let region = window.region;
+        Some(Region {
+            x: region.x - window.mcu_x.start * props.mcu_width() / denominator,
+            y: region.y - window.mcu_y.start * props.mcu_height() / denominator,
+            ..region
+        })
+    }
+
+    /// Columns and rows of the blocks of the component at `idx` which are stored
+    fn block_window(&self, props: &ImgProps, idx: usize) -> (Range<usize>, Range<usize>) {
+        match &self.window {
+            Some(window) => window.blocks(props, idx),
+            None => (0..props.blocks_per_line(idx), 0..props.block_rows(idx)),
+        }
+    }
+
+    /// The MCUs of a scan which need to be decoded, and where their blocks are stored
+    fn scan_window(&self, props: &ImgProps, layout: &ScanLayout) -> ScanWindow {
+        let (columns, rows) = match (&self.window, &layout.components[..]) {
+            (Some(window), [idx]) => window.blocks(props, *idx),
+            (Some(window), _) => (window.mcu_x.clone(), window.mcu_y.clone()),
+            (None, _) => (0..layout.mcus_per_line, 0..layout.mcu_rows),
+        };
+        ScanWindow {
+            mcus_per_line: layout.mcus_per_line,
+            end: layout.mcu_count().min(rows.end * layout.mcus_per_line),
+            columns,
+            rows,
+            blocks: layout
+                .components
+                .iter()
+                .map(|idx| self.block_window(props, *idx))
+                .collect(),
+        }
+    }
+
+    /// Check that the decoded frame can be turned into pixels, i.e it has 1, 3 or 4 components
+    /// whose sampling factors divide those of the largest. Returns the frame, its SOFn marker and
+    /// how its components become pixels. Adobe applications store CMYK inverted.
+    fn check_output(&self) -> Result<(&ImgProps, JpegMarker, usize, Conversion)> {
+        let (Some((marker, offset)), Some(props)) = (self.process, &self.frame) else {
+            return Err(JpegError::MissingFrame {
+                offset: self.data.len(),
+                marker: None,
+            });
+        };
+        let (hmax, vmax) = (
+            props.max_horizontal_sampling(),
+            props.max_vertical_sampling(),
+        );
+        let lossless = marker == JpegMarker::SOF(0xC3);
+        let space = ColorSpace::detect(props, lossless, self.jfif, self.adobe.as_ref());
+        let Some(space) = space else {
+            return Err(JpegError::UnsupportedProcess { offset, marker });
+        };
+        if props.components.iter().any(|c| {
+            hmax % c.horizontal_sampling as usize != 0 || vmax % c.vertical_sampling as usize != 0
+        }) {
+            return Err(JpegError::UnsupportedProcess { offset, marker });
+        }
+        let conversion = Conversion {
+            space,
+            scale: self.scale,
+            inverted: self.adobe.is_some(),
+            cmyk_to_rgb: self.cmyk_to_rgb,
+        };
+        Ok((props, marker, offset, conversion))
+    }
+
+    /// Walk the segments of the file, decoding each scan as it is found
+    fn read(&mut self) -> Result<()> {
+        let mut segments = SegmentReader::new(self.data).with_stream_len(self.data.len());
+        crate::expect_start(&mut segments)?;
+        loop {
+            let scan = self.next_scan(&mut segments).and_then(|scan| match scan {
+                Some((header, entropy, offset)) => {
+                    self.decode_scan(&header, &entropy, offset).map(|_| true)
+                }
+                None => Ok(false),
+            });
+            match scan {
+                Ok(true) => self.scans += 1,
+                Ok(false) => break,
+                // Keep whatever was decoded before the error
+                Err(e) if self.tolerant && self.frame.is_some() => {
+                    self.stopped = Some(e);
+                    break;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        if self.frame.is_none() {
+            return Err(JpegError::MissingFrame {
+                offset: segments.offset(),
+                marker: None,
+            });
+        }
+        Ok(())
+    }
+
+    /// Read segments up to the next scan, returning its header, its entropy-coded data and the
+    /// offset of its SOS segment. Returns None once there are no more scans.
+    fn next_scan(
+        &mut self,
+        segments: &mut SegmentReader<&[u8]>,
+    ) -> Result<Option<(ScanHeader, EntropyData, usize)>> {
+        for segment in segments.by_ref() {
+            let segment = segment?;
+            match segment.marker {
+                JpegMarker::APP(0xE0) if segment.payload.starts_with(b"JFIF\0") => {
+                    self.jfif = true;
+                }
+                JpegMarker::APP(0xEE) => {
+                    self.adobe = adobe::parse_adobe(&segment).or(self.adobe);
+                }
+                JpegMarker::DQT => {
+                    for table in quant::parse_quant_tables(&segment)? {
+                        let id = table.id as usize;
+                        self.quant_tables[id] = Some(table);
+                    }
+                }
+                JpegMarker::DHT => {
+                    for table in huffman::parse_huffman_tables(&segment)? {
+                        let decoder = HuffmanDecoder::new(&table);
+                        match table.class {
+                            TableClass::DC => self.dc_tables[table.id as usize] = Some(decoder),
+                            TableClass::AC => self.ac_tables[table.id as usize] = Some(decoder),
+                        }
+                    }
+                }
+                JpegMarker::DAC => {
+                    for table in arithmetic::parse_conditioning_tables(&segment)? {
+                        let id = table.id as usize;
+                        match table.class {
+                            TableClass::DC => self.dc_conditioning[id] = table.dc_bounds(),
+                            TableClass::AC => self.ac_conditioning[id] = table.value,
+                        }
+                    }
+                }
+                JpegMarker::DRI => {
+                    self.restart_interval = scan::parse_restart_interval(&segment)? as usize
+                }
+                JpegMarker::SOF(_) if self.frame.is_some() => {
+                    return Err(JpegError::Malformed {
+                        offset: segment.offset,
+                        marker: segment.marker,
+                        reason: "only a single frame is allowed outside of hierarchical mode",
+                    })
+                }
+                JpegMarker::SOF(b) => {
+                    let props = frame::parse_start_frame(&segment)?;
+                    // Sequential and progressive DCT, with Huffman or arithmetic coding,
+                    // and lossless with Huffman coding
+                    if !matches!(b, 0xC0..=0xC3 | 0xC9 | 0xCA) {
+                        return Err(JpegError::UnsupportedProcess {
+                            offset: segment.offset,
+                            marker: segment.marker,
+                        });
+                    }
+                    if self.region.is_some() && !matches!(b, 0xC0 | 0xC1 | 0xC9) {
+                        // Later scans of progressive and lossless frames depend on every
+                        // block or sample of earlier ones
+                        return Err(JpegError::UnsupportedProcess {
+                            offset: segment.offset,
+                            marker: segment.marker,
+                        });
+                    }
+                    if props.width == 0 {
+                        return Err(JpegError::Malformed {
+                            offset: segment.offset,
+                            marker: segment.marker,
+                            reason: "frame width must not be 0",
+                        });
+                    }
+                    if props.height == 0 {
+                        // The height would be defined by a DNL segment after the first scan
+                        return Err(JpegError::UnsupportedProcess {
+                            offset: segment.offset,
+                            marker: JpegMarker::DNL,
+                        });
+                    }
+                    if b != 0xC3 && !matches!(props.bit_depth, 8 | 12) {
+                        return Err(JpegError::Malformed {
+                            offset: segment.offset,
+                            marker: segment.marker,
+                            reason: "DCT sample precision must be 8 or 12 bits",
+                        });
+                    }
+                    if b == 0xC3 {
+                        if !(2..=16).contains(&props.bit_depth) {
+                            return Err(JpegError::Malformed {
+                                offset: segment.offset,
+                                marker: segment.marker,
+                                reason: "lossless sample precision must be between 2 and 16 bits",
+                            });
+                        }
+                        let sizes: Vec<(usize, usize)> = props
+                            .components
+                            .iter()
+                            .map(|c| {
+                                if props.components.len() == 1 {
+                                    (props.width, props.height)
+                                } else {
+                                    (
+                                        props.width.div_ceil(props.max_horizontal_sampling())
+                                            * c.horizontal_sampling as usize,
+                                        props.height.div_ceil(props.max_vertical_sampling())
+                                            * c.vertical_sampling as usize,
+                                    )
+                                }
+                            })
+                            .collect();
+                        let samples = sizes.iter().map(|&(stride, rows)| stride * rows);
+                        self.check_size(&segment, samples, size_of::<u16>())?;
+                        self.planes = sizes
+                            .into_iter()
+                            .map(|(stride, rows)| SamplePlane::new(stride, rows))
+                            .collect();
+                    } else if !self.streaming {
+                        self.window = self
+                            .region
+                            .map(|region| Window::new(&segment, &props, region, self.scale))
+                            .transpose()?;
+                        // Blocks of sequential frames are complete once decoded, so when scaled
+                        // down they can be transformed straight away into far fewer samples
+                        let transform =
+                            self.scale != Scale::Full && matches!(b, 0xC0 | 0xC1 | 0xC9);
+                        let bytes = (0..props.components.len()).map(|idx| {
+                            let (columns, rows) = self.block_window(&props, idx);
+                            let size = self.scale.component_block_size(&props, idx);
+                            columns
+                                .len()
+                                .saturating_mul(rows.len())
+                                .saturating_mul(match transform {
+                                    true => size * size * size_of::<u16>(),
+                                    false => size_of::<[i16; 64]>(),
+                                })
+                        });
+                        self.check_size(&segment, bytes, 1)?;
+                        self.components = (0..props.components.len())
+                            .map(|idx| {
+                                let (columns, rows) = self.block_window(&props, idx);
+                                let count = if transform {
+                                    0
+                                } else {
+                                    columns.len() * rows.len()
+                                };
+                                ComponentCoefficients {
+                                    id: props.components[idx].id,
+                                    blocks_per_line: columns.len(),
+                                    block_rows: rows.len(),
+                                    blocks: vec![[0i16; 64]; count],
+                                    quant_table: None,
+                                }
+                            })
+                            .collect();
+                        self.transformed = (0..props.components.len())
+                            .filter(|_| transform)
+                            .map(|idx| {
+                                let (columns, rows) = self.block_window(&props, idx);
+                                let size = self.scale.component_block_size(&props, idx);
+                                let grey = 1 << (props.bit_depth - 1);
+                                ComponentSamples {
+                                    blocks_per_line: columns.len(),
+                                    size,
+                                    samples: vec![grey; columns.len() * rows.len() * size * size],
+                                }
+                            })
+                            .collect();
+                    }
+                    self.process = Some((segment.marker, segment.offset));
+                    self.frame = Some(props);
+                }
+                JpegMarker::DHP | JpegMarker::EXP => {
+                    return Err(JpegError::UnsupportedProcess {
+                        offset: segment.offset,
+                        marker: segment.marker,
+                    })
+                }
+                JpegMarker::SOS => {
+                    let header = scan::parse_scan_header(&segment)?;
+                    if let Some(entropy) = segment.entropy {
+                        return Ok(Some((header, entropy, segment.offset)));
+                    }
+                }
+                _ => continue,
+            }
+        }
+        Ok(None)
+    }
+
+    /// Decode a scan into the coefficients of its components.
+    /// Progressive scans add to the coefficients left by earlier scans.
+    fn decode_scan(
+        &mut self,
+        header: &ScanHeader,
+        entropy: &EntropyData,
+        offset: usize,
+    ) -> Result<()> {
+        let (Some(props), Some((JpegMarker::SOF(process), _))) = (&self.frame, self.process) else {
+            return Err(JpegError::MissingFrame {
+                offset,
+                marker: Some(JpegMarker::SOS),
+            });
+        };
+        if process == 0xC3 {
+            let layout = ScanLayout::new(props, header, offset, 1)?;
+            return self.decode_lossless_scan(header, &layout, entropy, offset);
+        }
+        let kind = ScanKind::new(matches!(process, 0xC2 | 0xCA), header, offset)?;
+        let layout = ScanLayout::new(props, header, offset, 8)?;
+        let window = self.scan_window(props, &layout);
+
+        for idx in &layout.components {
+            if self.components[*idx].quant_table.is_none() {
+                self.components[*idx].quant_table = Some(self.quant_table(props, *idx, offset)?);
+            }
+        }
+
+        if matches!(process, 0xC9 | 0xCA) {
+            self.decode_arithmetic_scan(header, kind, &layout, &window, entropy)
+        } else {
+            self.decode_huffman_scan(header, kind, &layout, &window, entropy, offset)
+        }
+    }
+
+    /// Check that `counts` elements of `size` bytes for each component of the frame fit within the
+    /// memory limit, before anything is allocated for them
+    fn check_size(
+        &self,
+        segment: &Segment,
+        counts: impl Iterator<Item = usize>,
+        size: usize,
+    ) -> Result<()> {
+        let total = counts.fold(0usize, |total, count| {
+            total.saturating_add(count.saturating_mul(size))
+        });
+        if total > self.memory_limit {
+            return Err(JpegError::TooLarge {
+                offset: segment.offset,
+                marker: segment.marker,
+                size: total,
+                limit: self.memory_limit,
+            });
+        }
+        Ok(())
+    }
+
+    /// The quantization table (in natural order) used by the component at `idx`
+    fn quant_table(&self, props: &ImgProps, idx: usize, offset: usize) -> Result<[u16; 64]> {
+        let selector = props.components[idx].quant_table as usize;
+        match self.quant_tables.get(selector).and_then(|t| t.as_ref()) {
+            Some(table) => Ok(table.natural()),
+            None => Err(JpegError::Malformed {
+                offset,
+                marker: JpegMarker::SOS,
+                reason: "scan component uses an undefined quantization table",
+            }),
+        }
+    }
+
+    fn decode_huffman_scan(
+        &mut self,
+        header: &ScanHeader,
+        kind: ScanKind,
+        layout: &ScanLayout,
+        window: &ScanWindow,
+        entropy: &EntropyData,
+        offset: usize,
+    ) -> Result<()> {
+        let tables = huffman_tables(&self.dc_tables, &self.ac_tables, header, kind, offset)?;
+        let precision = self.frame.as_ref().map_or(8, |props| props.bit_depth);
+        let mut samples = scan_samples(&mut self.transformed, &self.components, layout, precision);
+        if kind == ScanKind::Sequential
+            && self.threads > 1
+            && self.window.is_none()
+            && !self.tolerant
+        {
+            let decoded = decode_intervals(
+                &mut self.components,
+                &mut samples,
+                self.data,
+                &tables,
+                layout,
+                entropy,
+                self.restart_interval,
+                self.threads,
+            )?;
+            if decoded {
+                return Ok(());
+            }
+        }
+
+        let entropy_error = |reader: &BitReader, kind| JpegError::Entropy {
+            offset: reader.position(),
+            marker: JpegMarker::SOS,
+            kind,
+        };
+        let mut reader = BitReader::new(
+            &self.data[..entropy.offset + entropy.length],
+            entropy.offset,
+        );
+        let mut predictions = [0i32; 4];
+        let mut eob_run = 0;
+        let band = (header.spectral_start, header.spectral_end);
+        let al = header.approx_low;
+        let mut intervals = Intervals::new(self.restart_interval, window);
+        let mut scratch = [0i16; 64];
+        // Set when decoding resumes after an error, the RSTn marker having been read already
+        let mut resumed = false;
+        let mut mcu = 0;
+        while mcu < window.end {
+            if intervals.restarts(mcu) && !resumed {
+                let restart = reader.restart();
+                if let Err(error) = expect_restart(restart, mcu, self.restart_interval) {
+                    if !self.tolerant {
+                        let error = entropy_error(&reader, error);
+                        self.fault = Fault::new(self.scans, header, layout, mcu, None, &error);
+                        return Err(error);
+                    }
+                    let offset = reader.position();
+                    // The interval did not end where it should have, so none of it can be
+                    // trusted, unless the data simply ended after it
+                    let interval = mcu / self.restart_interval - 1;
+                    let lost = match error {
+                        EntropyErrorKind::PrematureEnd => mcu,
+                        _ => interval * self.restart_interval,
+                    };
+                    let resume = conceal(
+                        &mut self.components,
+                        &mut samples,
+                        &mut reader,
+                        restart.ok(),
+                        (interval, self.restart_interval),
+                        kind,
+                        layout,
+                        window,
+                        lost,
+                    );
+                    let damage = Damage::new(self.scans, lost..resume, layout, offset, error);
+                    self.damage.push(damage);
+                    mcu = resume;
+                    resumed = true;
+                    continue;
+                }
+            }
+            if intervals.begins(mcu) {
+                resumed = false;
+                predictions = [0; 4];
+                eob_run = 0;
+            }
+            if !intervals.wanted(mcu, || reader.skip_to_marker()) {
+                mcu += 1;
+                continue;
+            }
+
+            let mut decoded = Ok(());
+            for (scan_idx, x, y) in layout.blocks(mcu) {
+                let component = &mut self.components[layout.components[scan_idx]];
+                let (stored, block) = window.target(
+                    (scan_idx, x, y),
+                    component,
+                    &samples[scan_idx],
+                    &mut scratch,
+                );
+                let (dc, ac) = tables[scan_idx];
+                let prediction = &mut predictions[scan_idx];
+                decoded = match kind {
+                    ScanKind::Sequential => decode_block(&mut reader, dc, ac, prediction, block),
+                    ScanKind::DcFirst => {
+                        progressive::decode_dc_first(&mut reader, dc, prediction, al, block)
+                    }
+                    ScanKind::DcRefine => {
+                        progressive::decode_dc_refine(&mut reader, al, block);
+                        Ok(())
+                    }
+                    ScanKind::AcFirst => {
+                        progressive::decode_ac_first(&mut reader, ac, band, al, &mut eob_run, block)
+                    }
+                    ScanKind::AcRefine => progressive::decode_ac_refine(
+                        &mut reader,
+                        ac,
+                        band,
+                        al,
+                        &mut eob_run,
+                        block,
+                    ),
+                }
+                .and_then(|_| overrun(&reader))
+                .map_err(|error| (scan_idx, error));
+                keep(&mut samples[scan_idx], stored, &scratch);
+                if decoded.is_err() {
+                    break;
+                }
+            }
+            match decoded {
+                Ok(()) => mcu += 1,
+                Err((scan_idx, error)) => {
+                    if !self.tolerant {
+                        let error = entropy_error(&reader, error);
+                        self.fault =
+                            Fault::new(self.scans, header, layout, mcu, Some(scan_idx), &error);
+                        return Err(error);
+                    }
+                    let offset = reader.position();
+                    let interval = mcu.checked_div(self.restart_interval).unwrap_or(0);
+                    // Errors are only found some way past the bits which caused them, so the rest
+                    // of the interval is lost too, unless the data simply ended. Without restart
+                    // markers there is no interval to fall back on, and everything before the MCU
+                    // being decoded is kept.
+                    let lost = match error {
+                        EntropyErrorKind::PrematureEnd => mcu,
+                        _ if self.restart_interval == 0 => mcu,
+                        _ => interval * self.restart_interval,
+                    };
+                    let resume = conceal(
+                        &mut self.components,
+                        &mut samples,
+                        &mut reader,
+                        None,
+                        (interval, self.restart_interval),
+                        kind,
+                        layout,
+                        window,
+                        lost,
+                    );
+                    let damage = Damage::new(self.scans, lost..resume, layout, offset, error);
+                    self.damage.push(damage);
+                    mcu = resume;
+                    resumed = true;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Decode a lossless scan into the samples of its components (ITU T.81 H.1.2).
+    /// Spectral selection start holds the predictor and successive approximation low the point transform.
+    fn decode_lossless_scan(
+        &mut self,
+        header: &ScanHeader,
+        layout: &ScanLayout,
+        entropy: &EntropyData,
+        offset: usize,
+    ) -> Result<()> {
+        let malformed = |reason| JpegError::Malformed {
+            offset,
+            marker: JpegMarker::SOS,
+            reason,
+        };
+        let precision = self.frame.as_ref().map_or(8, |props| props.bit_depth);
+        let (predictor, point_transform) = (header.spectral_start, header.approx_low as usize);
+        if !(1..=7).contains(&predictor) {
+            return Err(malformed("lossless predictor must be between 1 and 7"));
+        }
+        if point_transform >= precision {
+            return Err(malformed(
+                "point transform must be less than the sample precision",
+            ));
+        }
+        if !self.restart_interval.is_multiple_of(layout.mcus_per_line) {
+            return Err(malformed(
+                "lossless restart intervals must be a whole number of MCU rows",
+            ));
+        }
+        let mut tables = Vec::with_capacity(header.components.len());
+        for scan_component in &header.components {
+            match self.dc_tables[scan_component.dc_table as usize].as_ref() {
+                Some(table) => tables.push(table),
+                None => return Err(malformed("scan uses an undefined Huffman table")),
+            }
+        }
+
+        let entropy_error = |reader: &BitReader, kind| JpegError::Entropy {
+            offset: reader.position(),
+            marker: JpegMarker::SOS,
+            kind,
+        };
+        let mut reader = BitReader::new(
+            &self.data[..entropy.offset + entropy.length],
+            entropy.offset,
+        );
+        let initial = 1 << (precision - point_transform - 1);
+        // MCU row at which the current restart interval started
+        let mut top = 0;
+        for mcu in 0..layout.mcu_count() {
+            if self.restart_interval > 0 && mcu > 0 && mcu % self.restart_interval == 0 {
+                if let Err(kind) = expect_restart(reader.restart(), mcu, self.restart_interval) {
+                    let error = entropy_error(&reader, kind);
+                    self.fault = Fault::new(self.scans, header, layout, mcu, None, &error);
+                    return Err(error);
+                }
+                top = mcu / layout.mcus_per_line;
+            }
+
+            for (scan_idx, x, y) in layout.blocks(mcu) {
+                let plane = &mut self.planes[layout.components[scan_idx]];
+                let difference = match lossless::decode_difference(&mut reader, tables[scan_idx])
+                    .and_then(|difference| overrun(&reader).map(|_| difference))
+                {
+                    Ok(difference) => difference,
+                    Err(kind) => {
+                        let error = entropy_error(&reader, kind);
+                        self.fault =
+                            Fault::new(self.scans, header, layout, mcu, Some(scan_idx), &error);
+                        return Err(error);
+                    }
+                };
+                let prediction =
+                    plane.predict(x, y, top * layout.sampling[scan_idx].1, predictor, initial);
+                // Reconstruction is modulo 2^16
+                plane.samples[y * plane.stride + x] = (prediction + difference) as u16;
+            }
+        }
+
+        // Undo the point transform, discarding any bits beyond the precision of the frame
+        let mask = (1u32 << (precision - point_transform)) - 1;
+        for idx in &layout.components {
+            for sample in &mut self.planes[*idx].samples {
+                *sample = ((*sample as u32 & mask) << point_transform) as u16;
+            }
+        }
+        Ok(())
+    }
+
+    /// Decode an arithmetic coded scan. Every table has a default conditioning, so unlike
+    /// Huffman coded scans none need to have been defined.
+    fn decode_arithmetic_scan(
+        &mut self,
+        header: &ScanHeader,
+        kind: ScanKind,
+        layout: &ScanLayout,
+        window: &ScanWindow,
+        entropy: &EntropyData,
+    ) -> Result<()> {
+        let entropy_error = |scan: &ArithmeticScan, kind| JpegError::Entropy {
+            offset: scan.position(),
+            marker: JpegMarker::SOS,
+            kind,
+        };
+        let end = entropy.offset + entropy.length;
+        let mut scan =
+            ArithmeticScan::new(&self.data[..end], entropy.offset, end < self.data.len());
+        let band = (header.spectral_start, header.spectral_end);
+        let al = header.approx_low;
+        let precision = self.frame.as_ref().map_or(8, |props| props.bit_depth);
+        let mut samples = scan_samples(&mut self.transformed, &self.components, layout, precision);
+        let mut intervals = Intervals::new(self.restart_interval, window);
+        let mut scratch = [0i16; 64];
+        for mcu in 0..window.end {
+            if intervals.restarts(mcu) {
+                // Finding the marker also skips the data of an interval which was not decoded
+                if let Err(kind) = expect_restart(scan.restart(), mcu, self.restart_interval) {
+                    let error = entropy_error(&scan, kind);
+                    self.fault = Fault::new(self.scans, header, layout, mcu, None, &error);
+                    return Err(error);
+                }
+            }
+            if !intervals.wanted(mcu, || ()) {
+                continue;
+            }
+
+            for (scan_idx, x, y) in layout.blocks(mcu) {
+                let component = &mut self.components[layout.components[scan_idx]];
+                let (stored, block) = window.target(
+                    (scan_idx, x, y),
+                    component,
+                    &samples[scan_idx],
+                    &mut scratch,
+                );
+                let dc = header.components[scan_idx].dc_table as usize;
+                let ac = header.components[scan_idx].ac_table as usize;
+                let (bounds, threshold) = (self.dc_conditioning[dc], self.ac_conditioning[ac]);
+                let decoded = match kind {
+                    ScanKind::Sequential => scan
+                        .decode_dc(scan_idx, dc, bounds, 0, block)
+                        .and_then(|_| scan.decode_ac(ac, threshold, (1, 63), 0, block)),
+                    ScanKind::DcFirst => scan.decode_dc(scan_idx, dc, bounds, al, block),
+                    ScanKind::DcRefine => {
+                        scan.decode_dc_refine(al, block);
+                        Ok(())
+                    }
+                    ScanKind::AcFirst => scan.decode_ac(ac, threshold, band, al, block),
+                    ScanKind::AcRefine => scan.decode_ac_refine(ac, band, al, block),
+                };
+                // Decisions decoded from beyond the end of a truncated file are meaningless
+                let decoded = match scan.overrun() {
+                    true => Err(EntropyErrorKind::PrematureEnd),
+                    false => decoded,
+                };
+                keep(&mut samples[scan_idx], stored, &scratch);
+                if let Err(kind) = decoded {
+                    let error = entropy_error(&scan, kind);
+                    self.fault =
+                        Fault::new(self.scans, header, layout, mcu, Some(scan_idx), &error);
+                    return Err(error);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The DC and AC tables of each component of a Huffman coded scan. Only the tables needed
+/// by the kind of scan have to be defined.
+fn huffman_tables<'t>(
+    dc_tables: &'t [Option<HuffmanDecoder>; 4],
+    ac_tables: &'t [Option<HuffmanDecoder>; 4],
+    header: &ScanHeader,
+    kind: ScanKind,
+    offset: usize,
+) -> Result<Vec<(Option<&'t HuffmanDecoder>, Option<&'t HuffmanDecoder>)>> {
+    let mut tables = Vec::with_capacity(header.components.len());
+    for scan_component in &header.components {
+        let dc = dc_tables[scan_component.dc_table as usize].as_ref();
+        let ac = ac_tables[scan_component.ac_table as usize].as_ref();
+        let needed = match kind {
+            ScanKind::Sequential => (true, true),
+            ScanKind::DcFirst => (true, false),
+            ScanKind::DcRefine => (false, false),
+            ScanKind::AcFirst | ScanKind::AcRefine => (false, true),
+        };
+        if (needed.0 && dc.is_none()) || (needed.1 && ac.is_none()) {
+            return Err(JpegError::Malformed {
+                offset,
+                marker: JpegMarker::SOS,
+                reason: "scan uses an undefined Huffman table",
+            });
+        }
+        tables.push((dc, ac));
+    }
+    Ok(tables)
+}
+
+/// Decode the restart intervals of a sequential Huffman coded scan on up to `threads` threads,
+/// writing each block straight into `components` (or transforming it into `samples`). Each thread decodes a run of consecutive
+/// intervals beginning and ending on MCU row boundaries, so that every run fills rows of blocks of
+/// its own. Returns false, having decoded nothing, if the scan has no restart markers or they are
+/// not where they should be, in which case it is left to be decoded in order so that any error is
+/// found where it would be otherwise.
+#[allow(clippy::too_many_arguments)]
+fn decode_intervals(
+    components: &mut [ComponentCoefficients],
+    samples: &mut [Option<SampleRows>],
+    data: &[u8],
+    tables: &[(Option<&HuffmanDecoder>, Option<&HuffmanDecoder>)],
+    layout: &ScanLayout,
+    entropy: &EntropyData,
+    restart_interval: usize,
+    threads: usize,
+) -> Result<bool> {
+    let mcu_count = layout.mcu_count();
+    if restart_interval == 0 || mcu_count <= restart_interval {
+        return Ok(false);
+    }
+    let count = mcu_count.div_ceil(restart_interval);
+    let Some(starts) = restart_positions(data, entropy, count) else {
+        return Ok(false);
+    };
+
+    // Share the intervals out evenly, moving each boundary on to an interval which starts a row
+    let mcus_per_line = layout.mcus_per_line;
+    let mut bounds = vec![0];
+    for thread in 1..threads {
+        let from = (count * thread / threads).max(bounds[bounds.len() - 1] + 1);
+        match (from..count)
+            .find(|interval| (interval * restart_interval).is_multiple_of(mcus_per_line))
+        {
+            Some(interval) => bounds.push(interval),
+            None => break,
+        }
+    }
+    bounds.push(count);
+
+    // Split the blocks of each component of the scan into the rows filled by each run
+    let blocks_per_line: Vec<usize> = layout
+        .components
+        .iter()
+        .map(|idx| components[*idx].blocks_per_line)
+        .collect();
+    let mut rest: Vec<&mut [[i16; 64]]> = layout
+        .components
+        .iter()
+        .map(|_| Default::default())
+        .collect();
+    for (idx, component) in components.iter_mut().enumerate() {
+        if let Some(scan_idx) = layout.components.iter().position(|c| *c == idx) {
+            rest[scan_idx] = &mut component.blocks[..];
+        }
+    }
+    let mut runs = Vec::with_capacity(bounds.len() - 1);
+    for pair in bounds.windows(2) {
+        let (first_row, end_row) = (
+            pair[0] * restart_interval / mcus_per_line,
+            (pair[1] * restart_interval).div_ceil(mcus_per_line),
+        );
+        let mut blocks = Vec::with_capacity(rest.len());
+        let mut run_samples = Vec::with_capacity(rest.len());
+        for (scan_idx, remaining) in rest.iter_mut().enumerate() {
+            let rows = (end_row - first_row) * layout.sampling[scan_idx].1;
+            let len = (rows * blocks_per_line[scan_idx]).min(remaining.len());
+            let (run, after) = std::mem::take(remaining).split_at_mut(len);
+            blocks.push(run);
+            *remaining = after;
+            run_samples.push(samples[scan_idx].as_mut().map(|s| s.split_rows(rows)));
+        }
+        runs.push((pair[0]..pair[1], first_row, blocks, run_samples));
+    }
+
+    let data = &data[..entropy.offset + entropy.length];
+    let entropy_error = |reader: &BitReader, kind| JpegError::Entropy {
+        offset: reader.position(),
+        marker: JpegMarker::SOS,
+        kind,
+    };
+    let results = parallel::map_each(runs, |(intervals, first_row, mut blocks, mut samples)| {
+        for interval in intervals {
+            let mut reader = BitReader::new(data, starts[interval]);
+            let mut predictions = [0i32; 4];
+            let mcus =
+                interval * restart_interval..((interval + 1) * restart_interval).min(mcu_count);
+            for mcu in mcus.clone() {
+                for (scan_idx, x, y) in layout.blocks(mcu) {
+                    let y = y - first_row * layout.sampling[scan_idx].1;
+                    let n = y * blocks_per_line[scan_idx] + x;
+                    let (dc, ac) = tables[scan_idx];
+                    let prediction = &mut predictions[scan_idx];
+                    match &mut samples[scan_idx] {
+                        Some(samples) => {
+                            let mut block = [0i16; 64];
+                            decode_block(&mut reader, dc, ac, prediction, &mut block)
+                                .map_err(|kind| entropy_error(&reader, kind))?;
+                            samples.store(n, &block);
+                        }
+                        None => {
+                            decode_block(&mut reader, dc, ac, prediction, &mut blocks[scan_idx][n])
+                                .map_err(|kind| entropy_error(&reader, kind))?
+                        }
+                    }
+                }
+                overrun(&reader).map_err(|kind| entropy_error(&reader, kind))?;
+            }
+            if mcus.end < mcu_count {
+                expect_restart(reader.restart(), mcus.end, restart_interval)
+                    .map_err(|kind| entropy_error(&reader, kind))?;
+            }
+        }
+        Ok(())
+    });
+    // Runs are in order, so the first error is the one decoding in order would have found
+    results.into_iter().collect::<Result<()>>().map(|_| true)
+}
+
+/// The samples that the blocks of each component of a scan are transformed into, for components
+/// which are transformed as they are decoded
+fn scan_samples<'s>(
+    transformed: &'s mut [ComponentSamples],
+    components: &[ComponentCoefficients],
+    layout: &ScanLayout,
+    precision: usize,
+) -> Vec<Option<SampleRows<'s>>> {
+    let mut samples: Vec<_> = layout.components.iter().map(|_| None).collect();
+    for (idx, component) in transformed.iter_mut().enumerate() {
+        if let Some(scan_idx) = layout.components.iter().position(|c| *c == idx) {
+            samples[scan_idx] = Some(SampleRows {
+                samples: &mut component.samples,
+                blocks_per_line: component.blocks_per_line,
+                size: component.size,
+                quant: components[idx].quant_table.unwrap_or([1; 64]),
+                precision,
+            });
+        }
+    }
+    samples
+}
+
+/// Transform a block decoded into `scratch` into `samples`, if its component is transformed as it
+/// is decoded and the block is stored. Whatever was decoded is kept, as it would be in the
+/// coefficients.
+fn keep(samples: &mut Option<SampleRows>, stored: Option<usize>, scratch: &[i16; 64]) {
+    if let (Some(idx), Some(samples)) = (stored, samples) {
+        samples.store(idx, scratch);
+    }
+}
+
+/// Where the entropy-coded data of each restart interval begins: the start of the scan, then
+/// after each RSTn marker. None unless there are exactly `count` intervals, numbered in order.
+fn restart_positions(data: &[u8], entropy: &EntropyData, count: usize) -> Option<Vec<usize>> {
+    if entropy.restart_markers + 1 != count {
+        return None;
+    }
+    let mut starts = Vec::with_capacity(count);
+    starts.push(entropy.offset);
+    let end = entropy.offset + entropy.length;
+    let mut position = entropy.offset;
+    while let Some(found) = data[position..end].iter().position(|b| *b == 0xFF) {
+        position += found + 1;
+        match data.get(position) {
+            Some(&b @ 0xD0..=0xD7) if position < end => {
+                if (b - 0xD0) as usize != (starts.len() - 1) % 8 {
+                    return None;
+                }
+                starts.push(position + 1);
+            }
+            _ => {}
+        }
+    }
+    (starts.len() == count).then_some(starts)
+}
+
+/// Recover from an error in restart interval `interval.0` of `interval.1` MCUs by moving `reader`
+/// past the next RSTn marker (unless `restart` holds the number of one just read), and concealing
+/// the MCUs from `lost` up to the start of the interval it begins. Returns that MCU, or the end of
+/// the scan if there is no later marker.
+#[allow(clippy::too_many_arguments)]
+fn conceal(
+    components: &mut [ComponentCoefficients],
+    samples: &mut [Option<SampleRows>],
+    reader: &mut BitReader,
+    restart: Option<u8>,
+    (interval, restart_interval): (usize, usize),
+    kind: ScanKind,
+    layout: &ScanLayout,
+    window: &ScanWindow,
+    lost: usize,
+) -> usize {
+    let restart = match restart {
+        Some(n) => Some(n),
+        None if restart_interval > 0 => {
+            reader.skip_to_marker();
+            reader.restart().ok()
+        }
+        None => None,
+    };
+    // Markers are numbered modulo 8, so take the first interval from here which it could end
+    let resume = restart.map_or(window.end, |n| {
+        let ended = interval + (n as usize + 8 - interval % 8) % 8;
+        ((ended + 1) * restart_interval).min(window.end)
+    });
+
+    // Only the first scan of a block sets its lowest frequencies, so later progressive scans
+    // leave whatever earlier scans found
+    if matches!(kind, ScanKind::Sequential | ScanKind::DcFirst) {
+        for mcu in lost..resume {
+            let above = mcu.checked_sub(layout.mcus_per_line);
+            for (n, (scan_idx, x, y)) in layout.blocks(mcu).enumerate() {
+                let component = &mut components[layout.components[scan_idx]];
+                let blocks_per_line = component.blocks_per_line;
+                let Some(idx) = window.block(scan_idx, x, y, blocks_per_line) else {
+                    continue;
+                };
+                let source = above
+                    .and_then(|above| layout.blocks(above).nth(n))
+                    .and_then(|(_, x, y)| window.block(scan_idx, x, y, blocks_per_line));
+                match &mut samples[scan_idx] {
+                    Some(samples) => samples.copy(idx, source),
+                    None => {
+                        component.blocks[idx] =
+                            source.map_or([0; 64], |source| component.blocks[source]);
+                    }
+                }
+            }
+        }
+    }
+    resume
+}
+
+/// Whether `reader` has run out of data, which is an error if it happens part way through an MCU
+fn overrun(reader: &BitReader) -> std::result::Result<(), EntropyErrorKind> {
+    match (reader.overrun(), reader.marker()) {
+        (false, _) => Ok(()),
+        (true, Some(marker)) => Err(EntropyErrorKind::UnexpectedMarker(marker)),
+        (true, None) => Err(EntropyErrorKind::PrematureEnd),
+    }
+}
+
+/// Check that the restart marker read after `mcu` MCUs carries the expected number
+pub(crate) fn expect_restart(
+    restart: std::result::Result<u8, EntropyErrorKind>,
+    mcu: usize,
+    interval: usize,
+) -> std::result::Result<(), EntropyErrorKind> {
+    let expected = ((mcu / interval - 1) % 8) as u8;
+    match restart? {
+        n if n == expected => Ok(()),
+        n => Err(EntropyErrorKind::UnexpectedMarker(JpegMarker::RST(
+            0xD0 + n,
+        ))),
+    }
+}
+
+/// Decode the DC difference and AC coefficients of a single block (ITU T.81 F.2.2)
+pub(crate) fn decode_block(
+    reader: &mut BitReader,
+    dc: Option<&HuffmanDecoder>,
+    ac: Option<&HuffmanDecoder>,
+    prediction: &mut i32,
+    block: &mut [i16; 64],
+) -> std::result::Result<(), EntropyErrorKind> {
+    let size = dc
+        .and_then(|dc| dc.decode(reader))
+        .ok_or(EntropyErrorKind::InvalidCode)?;
+    if size > 16 {
+        return Err(EntropyErrorKind::InvalidCode);
+    }
+    *prediction += reader.receive_extend(size as u32);
+    block[0] = i16::try_from(*prediction).map_err(|_| EntropyErrorKind::CoefficientOverflow)?;
+
+    let mut k = 1;
+    while k < 64 {
+        let rs = ac
+            .and_then(|ac| ac.decode(reader))
+            .ok_or(EntropyErrorKind::InvalidCode)?;
+        let (run, size) = ((rs >> 4) as usize, (rs & 0x0F) as u32);
+        if size == 0 {
+            if run == 15 {
+                // ZRL, a run of 16 zeros
+                k += 16;
+                continue;
+            }
+            // EOB
+            break;
+        }
+        k += run;
+        if k > 63 {
+            return Err(EntropyErrorKind::CoefficientOverflow);
+        }
+        block[ZIGZAG[k]] = reader.receive_extend(size) as i16;
+        k += 1;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitreader::tests::pack;
+    use crate::color;
+    use crate::frame::Component;
+    use crate::image::PixelFormat;
+    use crate::scan::ScanComponent;
+    use crate::segment::Segment;
+
+    /// Payload of a DHT segment defining DC table 0 with the two bit codes 00, 01, 10 and 11 for
+    /// differences of 0 to 3 bits, and AC table 0 with 00 for EOB, 01 for ZRL, 10 for a single
+    /// 1 bit coefficient and 110 for a 2 bit coefficient after a run of two zeros. AC codes
+    /// starting 111 are left unused.
+    const TABLES: [u8; 42] = [
+        0x00, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 2, 3, //
+        0x10, 0, 3, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x00, 0xF0, 0x01, 0x22,
+    ];
+
+    /// The DC and AC tables of `TABLES`
+    fn tables() -> (HuffmanDecoder, HuffmanDecoder) {
+        let segment = Segment {
+            marker: JpegMarker::DHT,
+            offset: 0,
+            length: TABLES.len(),
+            payload: TABLES.to_vec(),
+            entropy: None,
+        };
+        let tables = huffman::parse_huffman_tables(&segment).unwrap();
+        (
+            HuffmanDecoder::new(&tables[0]),
+            HuffmanDecoder::new(&tables[1]),
+        )
+    }
+
+    /// A greyscale baseline JPEG of `width` x `height` using `TABLES` and quantizing every
+    /// coefficient by 8, with restart markers every `restart_interval` MCUs (if any) and `entropy`
+    /// as its scan data. Zero bytes code blocks of mid grey.
+    fn frame(width: u16, height: u16, restart_interval: u16, entropy: &[u8]) -> Vec<u8> {
+        let mut data = vec![0xFF, 0xD8];
+        data.extend([0xFF, 0xDB, 0x00, 0x43, 0x00]);
+        data.extend([8; 64]);
+        data.extend([0xFF, 0xC4, 0x00, 2 + TABLES.len() as u8]);
+        data.extend(TABLES);
+        data.extend([0xFF, 0xC0, 0x00, 0x0B, 0x08]);
+        data.extend(height.to_be_bytes());
+        data.extend(width.to_be_bytes());
+        data.extend([0x01, 0x01, 0x11, 0x00]);
+        if restart_interval > 0 {
+            data.extend([0xFF, 0xDD, 0x00, 0x04]);
+            data.extend(restart_interval.to_be_bytes());
+        }
+        data.extend([0xFF, 0xDA, 0x00, 0x08, 0x01, 0x01, 0x00, 0x00, 0x3F, 0x00]);
+        data.extend(entropy);
+        data.extend([0xFF, 0xD9]);
+        data
+    }
+
+    #[test]
+    fn decode_block_places_coefficients_in_natural_order() {
+        let (dc, ac) = tables();
+        let data = pack(&[
+            // DC difference of -2 (2 bits, 01)
+            (0b10, 2),
+            (0b01, 2),
+            // +1 in zig-zag position 1, then 16 zeros
+            (0b10, 2),
+            (0b1, 1),
+            (0b01, 2),
+            // Two more zeros, then -3 (2 bits, 00) in zig-zag position 20
+            (0b110, 3),
+            (0b00, 2),
+            (0b00, 2),
+        ]);
+        let mut reader = BitReader::new(&data, 0);
+        let (mut prediction, mut block) = (5, [0i16; 64]);
+        decode_block(
+            &mut reader,
+            Some(&dc),
+            Some(&ac),
+            &mut prediction,
+            &mut block,
+        )
+        .unwrap();
+        let mut expected = [0i16; 64];
+        (expected[0], expected[1], expected[40]) = (3, 1, -3);
+        assert_eq!(block, expected);
+        assert_eq!(prediction, 3);
+        assert_eq!(reader.position(), 2);
+    }
+
+    #[test]
+    fn decode_block_rejects_bad_blocks() {
+        let (dc, ac) = tables();
+        let decode = |codes: &[(u32, u32)]| {
+            let data = pack(codes);
+            let mut reader = BitReader::new(&data, 0);
+            decode_block(&mut reader, Some(&dc), Some(&ac), &mut 0, &mut [0; 64])
+        };
+        assert_eq!(
+            decode(&[(0b00, 2), (0b111, 3)]),
+            Err(EntropyErrorKind::InvalidCode)
+        );
+        // 48 zeros and 13 coefficients fill zig-zag positions 1 to 61, leaving no room for a run of two
+        let mut codes = vec![(0b00, 2), (0b01, 2), (0b01, 2), (0b01, 2)];
+        codes.extend([(0b101, 3); 13]);
+        codes.extend([(0b110, 3), (0b11, 2)]);
+        assert_eq!(decode(&codes), Err(EntropyErrorKind::CoefficientOverflow));
+    }
+
+    #[test]
+    fn dc_prediction_resets_at_each_restart_marker() {
+        // Two blocks, one above the other, each in its own interval with a DC difference of 3
+        let interval = pack(&[(0b10, 2), (0b11, 2), (0b00, 2)]);
+        let mut entropy = interval.clone();
+        entropy.extend([0xFF, 0xD0]);
+        entropy.extend(&interval);
+        let data = frame(8, 16, 1, &entropy);
+
+        let coefficients = Decoder::new(&data).coefficients().unwrap();
+        let blocks = &coefficients.components[0].blocks;
+        assert_eq!((blocks[0][0], blocks[1][0]), (3, 3));
+        // A DC coefficient of 3 quantized by 8 is 3 above mid grey in every sample
+        for threads in [1, 2] {
+            let image = Decoder::new(&data).with_threads(threads).decode().unwrap();
+            assert_eq!(image.data, [131; 128]);
+        }
+        let image = Decoder::new(&data)
+            .with_scale(Scale::Eighth)
+            .decode()
+            .unwrap();
+        assert_eq!(image.data, [131; 2]);
+        let rows: Vec<Vec<u8>> = Decoder::new(&data)
+            .scanlines()
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(rows, vec![vec![131; 8]; 16]);
+    }
+
+    #[test]
+    fn recovery_without_restart_markers_keeps_the_mcus_before_the_error() {
+        // 64 blocks, the first with a DC difference of 3 and the rest with none, but an invalid AC
+        // code in the 61st
+        let mut codes = vec![(0b10, 2), (0b11, 2), (0b00, 2)];
+        codes.extend([(0b00, 2); 59 * 2]);
+        codes.extend([(0b00, 2), (0b111, 3)]);
+        let data = frame(64, 64, 0, &pack(&codes));
+
+        let recovered = Decoder::new(&data).recover().unwrap();
+        assert_eq!(recovered.damage.len(), 1);
+        let damage = &recovered.damage[0];
+        assert_eq!((damage.scan, damage.mcus.clone()), (0, 60..64));
+        assert_eq!(damage.kind, EntropyErrorKind::InvalidCode);
+        // The blocks decoded before the error are kept, and those lost are copied from above them
+        assert_eq!(recovered.image.data, [131; 64 * 64]);
+        assert!(recovered.stopped.is_none());
+    }
+
+    #[test]
+    fn regions_must_start_within_the_image() {
+        let data = frame(64, 64, 0, &[0; 32]);
+        let decode = |region, scale| {
+            Decoder::new(&data)
+                .with_region(region)
+                .with_scale(scale)
+                .decode()
+        };
+        let region = |x, y, width, height| Region {
+            x,
+            y,
+            width,
+            height,
+        };
+        for (bad, scale, size) in [
+            (region(100, 0, 5, 5), Scale::Full, (64, 64)),
+            (region(0, 64, 5, 5), Scale::Full, (64, 64)),
+            (region(10, 10, 0, 5), Scale::Full, (64, 64)),
+            (region(10, 10, 5, 0), Scale::Full, (64, 64)),
+            (region(32, 0, 5, 5), Scale::Half, (32, 32)),
+        ] {
+            match decode(bad, scale) {
+                Err(JpegError::BadRegion {
+                    offset,
+                    marker,
+                    region,
+                    width,
+                    height,
+                }) => {
+                    assert_eq!((offset, marker), (117, JpegMarker::SOF(0xC0)));
+                    assert_eq!((region, (width, height)), (bad, size));
+                }
+                other => panic!("expected BadRegion, got {:?}", other.map(|_| ())),
+            }
+        }
+        // Regions reaching beyond the image are clipped to it
+        let image = decode(region(60, 30, 10, 50), Scale::Full).unwrap();
+        assert_eq!((image.width, image.height), (4, 34));
+        assert_eq!(image.data, [128; 4 * 34]);
+    }
+
+    #[test]
+    fn oversized_frame_is_rejected_before_allocating() {
+        let data = frame(65535, 65535, 0, &[]);
+        match Decoder::new(&data).decode() {
+            Err(JpegError::TooLarge {
+                offset,
+                marker,
+                size,
+                limit,
+            }) => {
+                assert_eq!(offset, 117);
+                assert_eq!(marker, JpegMarker::SOF(0xC0));
+                assert_eq!(size, 8192 * 8192 * 128);
+                assert_eq!(limit, DEFAULT_MEMORY_LIMIT);
+            }
+            other => panic!("expected TooLarge, got {:?}", other.map(|_| ())),
+        }
+
+        let data = frame(64, 64, 0, &[0; 32]);
+        assert!(matches!(
+            Decoder::new(&data).with_memory_limit(63 * 128).decode(),
+            Err(JpegError::TooLarge { size: 8192, .. })
+        ));
+    }
+
+    #[test]
+    fn scaled_sequential_frame_keeps_only_samples() {
+        // 64 blocks of one sample each at 1/8 scale, rather than 64 coefficients each
+        let data = frame(64, 64, 0, &[0; 32]);
+        let image = Decoder::new(&data)
+            .with_scale(Scale::Eighth)
+            .with_memory_limit(64 * 2)
+            .decode()
+            .unwrap();
+        assert_eq!((image.width, image.height), (8, 8));
+        assert_eq!(image.data, [128; 64]);
+        assert!(matches!(
+            Decoder::new(&data)
+                .with_scale(Scale::Half)
+                .with_memory_limit(64 * 32 - 1)
+                .decode(),
+            Err(JpegError::TooLarge { size: 2048, .. })
+        ));
+    }
+
+    /// A scan header for the components with the given selectors and the given Ss, Se, Ah and Al
+    fn scan_header(selectors: &[u8], start: u8, end: u8, high: u8, low: u8) -> ScanHeader {
+        ScanHeader {
+            components: selectors
+                .iter()
+                .map(|&selector| ScanComponent {
+                    selector,
+                    dc_table: 0,
+                    ac_table: 0,
+                })
+                .collect(),
+            spectral_start: start,
+            spectral_end: end,
+            approx_high: high,
+            approx_low: low,
+        }
+    }
+
+    #[test]
+    fn progressive_scans_are_classified_by_band_and_approximation() {
+        for (selectors, start, end, high, low, kind) in [
+            (&[1, 2, 3][..], 0, 0, 0, 1, ScanKind::DcFirst),
+            (&[1, 2, 3], 0, 0, 1, 0, ScanKind::DcRefine),
+            (&[1], 1, 5, 0, 2, ScanKind::AcFirst),
+            (&[2], 6, 63, 2, 1, ScanKind::AcRefine),
+            (&[1], 63, 63, 0, 13, ScanKind::AcFirst),
+        ] {
+            let header = scan_header(selectors, start, end, high, low);
+            assert_eq!(ScanKind::new(true, &header, 30).unwrap(), kind);
+        }
+        // Sequential scans are not checked against the progressive rules
+        let header = scan_header(&[1, 2], 0, 63, 0, 0);
+        assert_eq!(
+            ScanKind::new(false, &header, 30).unwrap(),
+            ScanKind::Sequential
+        );
+    }
+
+    #[test]
+    fn progressive_scan_parameters_are_checked() {
+        for (selectors, start, end, high, low) in [
+            // Ss after Se, or Se beyond the last coefficient
+            (&[1][..], 6, 5, 0, 0),
+            (&[1], 1, 64, 0, 0),
+            // Al beyond the largest point transform of a 16 bit coefficient
+            (&[1], 1, 63, 0, 14),
+            // DC and AC coefficients together
+            (&[1], 0, 5, 0, 0),
+            // AC coefficients of more than one component
+            (&[1, 2], 1, 63, 0, 0),
+            (&[1, 2], 1, 63, 1, 0),
+        ] {
+            let header = scan_header(selectors, start, end, high, low);
+            match ScanKind::new(true, &header, 30) {
+                Err(JpegError::Malformed { offset, marker, .. }) => {
+                    assert_eq!((offset, marker), (30, JpegMarker::SOS));
+                }
+                other => panic!("expected Malformed for {:?}, got {:?}", header, other),
+            }
+        }
+    }
+
+    #[test]
+    fn scan_components_must_be_distinct_frame_components() {
+        let props = ImgProps {
+            width: 61,
+            height: 45,
+            bit_depth: 8,
+            components: [(1, 2, 2), (2, 1, 1), (3, 1, 1)]
+                .map(|(id, h, v)| Component {
+                    id,
+                    horizontal_sampling: h,
+                    vertical_sampling: v,
+                    quant_table: 0,
+                })
+                .to_vec(),
+        };
+
+        let layout = ScanLayout::new(&props, &scan_header(&[3, 1], 0, 63, 0, 0), 30, 8).unwrap();
+        assert_eq!(layout.components, [2, 0]);
+        assert_eq!((layout.mcus_per_line, layout.mcu_rows), (4, 3));
+        assert_eq!(layout.sampling, [(1, 1), (2, 2)]);
+        // A single component is coded block by block, covering only the component itself
+        let layout = ScanLayout::new(&props, &scan_header(&[2], 0, 63, 0, 0), 30, 8).unwrap();
+        assert_eq!((layout.mcus_per_line, layout.mcu_rows), (4, 3));
+        let layout = ScanLayout::new(&props, &scan_header(&[1], 0, 63, 0, 0), 30, 8).unwrap();
+        assert_eq!((layout.mcus_per_line, layout.mcu_rows), (8, 6));
+        assert_eq!(layout.sampling, [(1, 1)]);
+
+        for selectors in [&[4][..], &[1, 1], &[1, 2, 1]] {
+            assert!(matches!(
+                ScanLayout::new(&props, &scan_header(selectors, 0, 63, 0, 0), 30, 8),
+                Err(JpegError::Malformed { offset: 30, .. })
+            ));
+        }
+    }
+
+    /// A 1x1 lossless JPEG of four components holding `samples`, coded with predictor 1 as
+    /// differences from 128 using the DC table of `TABLES`, with `segment` before its frame
+    fn lossless_cmyk(samples: [u8; 4], segment: &[u8]) -> Vec<u8> {
+        let mut data = vec![0xFF, 0xD8];
+        data.extend(segment);
+        data.extend([0xFF, 0xC4, 0x00, 2 + TABLES.len() as u8]);
+        data.extend(TABLES);
+        data.extend([0xFF, 0xC3, 0x00, 0x14, 0x08, 0x00, 0x01, 0x00, 0x01, 0x04]);
+        for id in 1..=4 {
+            data.extend([id, 0x11, 0x00]);
+        }
+        data.extend([0xFF, 0xDA, 0x00, 0x0E, 0x04]);
+        for id in 1..=4 {
+            data.extend([id, 0x00]);
+        }
+        data.extend([0x01, 0x00, 0x00]);
+        let codes: Vec<(u32, u32)> = samples
+            .iter()
+            .flat_map(|sample| {
+                let diff = *sample as i32 - 128;
+                let size = 32 - diff.unsigned_abs().leading_zeros();
+                let bits = if diff < 0 {
+                    diff + (1 << size) - 1
+                } else {
+                    diff
+                };
+                [(size, 2), (bits as u32, size)]
+            })
+            .collect();
+        data.extend(pack(&codes));
+        data.extend([0xFF, 0xD9]);
+        data
+    }
+
+    #[test]
+    fn lossless_cmyk_is_converted_like_dct_cmyk() {
+        let samples = [121, 130, 135, 125];
+        let adobe = |transform: u8| {
+            let mut segment = vec![0xFF, 0xEE, 0x00, 0x0E];
+            segment.extend(b"Adobe\0\x64\0\0\0\0");
+            segment.push(transform);
+            segment
+        };
+        let decode = |data: &[u8], cmyk_to_rgb: bool| {
+            let image = Decoder::new(data)
+                .with_cmyk_to_rgb(cmyk_to_rgb)
+                .decode()
+                .unwrap();
+            (image.format, image.data)
+        };
+
+        // Without an Adobe segment the samples are taken as they are stored
+        let data = lossless_cmyk(samples, &[]);
+        assert_eq!(decode(&data, false), (PixelFormat::Cmyk8, samples.to_vec()));
+        // Adobe applications store CMYK inverted
+        let data = lossless_cmyk(samples, &adobe(0));
+        let cmyk = samples.map(|s| 255 - s);
+        assert_eq!(decode(&data, false), (PixelFormat::Cmyk8, cmyk.to_vec()));
+        let rgb = color::inverted_cmyk_to_rgb(samples, 8);
+        assert_eq!(decode(&data, true), (PixelFormat::Rgb8, rgb.to_vec()));
+        // YCCK becomes CMYK stored inverted in the same way
+        let data = lossless_cmyk(samples, &adobe(2));
+        let [y, cb, cr, k] = samples;
+        let inverted = color::ycck_to_cmyk(y, cb, cr, k, 8);
+        let cmyk = inverted.map(|s| 255 - s);
+        assert_eq!(decode(&data, false), (PixelFormat::Cmyk8, cmyk.to_vec()));
+        let rgb = color::inverted_cmyk_to_rgb(inverted, 8);
+        assert_eq!(decode(&data, true), (PixelFormat::Rgb8, rgb.to_vec()));
+    }
+}
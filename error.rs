@@ -0,0 +1,193 @@
+use std::{error, fmt, io};
+
+use crate::{image::Region, marker::JpegMarker};
+
+/// Ways in which the entropy-coded data of a scan can fail to decode
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum EntropyErrorKind {
+    /// The bits did not form a code in the Huffman table being used
+    InvalidCode,
+    /// A decoded coefficient (or run of coefficients) fell outside the range allowed by the frame
+    CoefficientOverflow,
+    /// A marker other than the expected RSTn was found within the data
+    UnexpectedMarker(JpegMarker),
+    /// A restart interval ended, but no RSTn marker followed it
+    MissingRestart,
+    /// The data ended before every MCU of the scan was decoded
+    PrematureEnd,
+}
+
+impl fmt::Display for EntropyErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EntropyErrorKind::InvalidCode => write!(f, "invalid Huffman code"),
+            EntropyErrorKind::CoefficientOverflow => write!(f, "coefficient overflow"),
+            EntropyErrorKind::UnexpectedMarker(marker) => write!(f, "unexpected {} marker", marker),
+            EntropyErrorKind::MissingRestart => write!(f, "missing restart marker"),
+            EntropyErrorKind::PrematureEnd => write!(f, "premature end of data"),
+        }
+    }
+}
+
+/// Everything that can go wrong whilst reading a JPEG. Each variant carries the file offset
+/// (and where known, the marker) at which the problem was found.
+#[derive(Debug)]
+pub enum JpegError {
+    /// The stream did not start with a Start of Image marker. Contains the marker found instead, if any
+    NotJpeg {
+        offset: usize,
+        marker: Option<JpegMarker>,
+    },
+
+    /// The stream ended part way through a segment
+    Truncated { offset: usize, marker: JpegMarker },
+
+    /// A segment's length field is impossible, or does not match the contents of the segment
+    BadLength {
+        offset: usize,
+        marker: JpegMarker,
+        length: usize,
+    },
+
+    /// A segment contains a value which is out of range (e.g a sampling factor of 0)
+    Malformed {
+        offset: usize,
+        marker: JpegMarker,
+        reason: &'static str,
+    },
+
+    /// No Start of Frame segment was found before the marker which required one (or the end of the stream)
+    MissingFrame {
+        offset: usize,
+        marker: Option<JpegMarker>,
+    },
+
+    /// The entropy-coded data following a SOS segment could not be decoded
+    Entropy {
+        offset: usize,
+        marker: JpegMarker,
+        kind: EntropyErrorKind,
+    },
+
+    /// The image uses a coding process which is not supported
+    UnsupportedProcess { offset: usize, marker: JpegMarker },
+
+    /// Decoding the frame would need more memory (in bytes) than the decoder's limit allows
+    TooLarge {
+        offset: usize,
+        marker: JpegMarker,
+        size: usize,
+        limit: usize,
+    },
+
+    /// The region asked for is empty, or lies outside the (possibly scaled) image of `width` x
+    /// `height` pixels described by the frame
+    BadRegion {
+        offset: usize,
+        marker: JpegMarker,
+        region: Region,
+        width: usize,
+        height: usize,
+    },
+
+    /// The underlying reader failed
+    Io {
+        offset: usize,
+        marker: Option<JpegMarker>,
+        source: io::Error,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, JpegError>;
+
+impl JpegError {
+    /// File offset at which the error occurred
+    pub fn offset(&self) -> usize {
+        match self {
+            JpegError::NotJpeg { offset, .. }
+            | JpegError::Truncated { offset, .. }
+            | JpegError::BadLength { offset, .. }
+            | JpegError::Malformed { offset, .. }
+            | JpegError::MissingFrame { offset, .. }
+            | JpegError::Entropy { offset, .. }
+            | JpegError::UnsupportedProcess { offset, .. }
+            | JpegError::TooLarge { offset, .. }
+            | JpegError::BadRegion { offset, .. }
+            | JpegError::Io { offset, .. } => *offset,
+        }
+    }
+
+    /// Marker of the segment in which the error occurred
+    pub fn marker(&self) -> Option<JpegMarker> {
+        match self {
+            JpegError::NotJpeg { marker, .. }
+            | JpegError::MissingFrame { marker, .. }
+            | JpegError::Io { marker, .. } => *marker,
+            JpegError::Truncated { marker, .. }
+            | JpegError::BadLength { marker, .. }
+            | JpegError::Malformed { marker, .. }
+            | JpegError::Entropy { marker, .. }
+            | JpegError::UnsupportedProcess { marker, .. }
+            | JpegError::TooLarge { marker, .. }
+            | JpegError::BadRegion { marker, .. } => Some(*marker),
+        }
+    }
+}
+
+impl fmt::Display for JpegError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JpegError::NotJpeg { .. } => write!(f, "not a valid JPEG image"),
+            JpegError::Truncated { marker, .. } => write!(f, "{} segment was truncated", marker),
+            JpegError::BadLength { marker, length, .. } => {
+                write!(f, "{} segment has an invalid length of {}", marker, length)
+            }
+            JpegError::Malformed { marker, reason, .. } => {
+                write!(f, "{} segment is malformed: {}", marker, reason)
+            }
+            JpegError::MissingFrame {
+                marker: Some(marker),
+                ..
+            } => write!(f, "no Start of Frame segment was found before {}", marker),
+            JpegError::MissingFrame { .. } => write!(f, "no Start of Frame segment was found"),
+            JpegError::Entropy { marker, kind, .. } => {
+                write!(f, "{} in the data following {}", kind, marker)
+            }
+            JpegError::UnsupportedProcess { marker, .. } => {
+                write!(f, "{} (0x{:X}) is not supported", marker, marker.to_u8())
+            }
+            JpegError::TooLarge {
+                marker,
+                size,
+                limit,
+                ..
+            } => write!(
+                f,
+                "{} segment describes a frame needing {} bytes, more than the limit of {}",
+                marker, size, limit
+            ),
+            JpegError::BadRegion {
+                marker,
+                region,
+                width,
+                height,
+                ..
+            } => write!(
+                f,
+                "region of {}x{} at {},{} is empty or outside the {}x{} image described by {}",
+                region.width, region.height, region.x, region.y, width, height, marker
+            ),
+            JpegError::Io { source, .. } => write!(f, "{}", source),
+        }?;
+        write!(f, " (at offset {})", self.offset())
+    }
+}
+
+impl error::Error for JpegError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            JpegError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,362 @@
+use std::fmt;
+
+use crate::{
+    error::{JpegError, Result},
+    segment::Segment,
+};
+
+/// A single component of a frame (e.g Y, Cb or Cr), as listed in its Start of Frame segment
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Component {
+    /// Component identifier (Ci)
+    pub id: u8,
+    /// Horizontal sampling factor (Hi), 1 -> 4
+    pub horizontal_sampling: u8,
+    /// Vertical sampling factor (Vi), 1 -> 4
+    pub vertical_sampling: u8,
+    /// Quantization table destination selector (Tqi)
+    pub quant_table: u8,
+}
+
+/// Chroma subsampling of a frame, derived from the sampling factors of its first three components
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ChromaSubsampling {
+    /// No subsampling
+    S444,
+    /// Chroma halved horizontally
+    S422,
+    /// Chroma halved horizontally and vertically
+    S420,
+    /// Chroma quartered horizontally
+    S411,
+    /// Chroma halved vertically
+    S440,
+}
+
+impl fmt::Display for ChromaSubsampling {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ChromaSubsampling::S444 => "4:4:4",
+            ChromaSubsampling::S422 => "4:2:2",
+            ChromaSubsampling::S420 => "4:2:0",
+            ChromaSubsampling::S411 => "4:1:1",
+            ChromaSubsampling::S440 => "4:4:0",
+        })
+    }
+}
+
+/// Properties of a frame, as read from its Start of Frame (SOFn) segment
+#[derive(Debug, Clone)]
+pub struct ImgProps {
+    pub width: usize,
+    pub height: usize,
+    pub bit_depth: usize,
+    pub components: Vec<Component>,
+}
+
+impl ImgProps {
+    /// Largest horizontal sampling factor of any component (Hmax)
+    pub fn max_horizontal_sampling(&self) -> usize {
+        self.components
+            .iter()
+            .map(|c| c.horizontal_sampling as usize)
+            .max()
+            .unwrap_or(1)
+    }
+
+    /// Largest vertical sampling factor of any component (Vmax)
+    pub fn max_vertical_sampling(&self) -> usize {
+        self.components
+            .iter()
+            .map(|c| c.vertical_sampling as usize)
+            .max()
+            .unwrap_or(1)
+    }
+
+    /// Width in pixels of an MCU. Frames with a single component are never interleaved, so use 8x8 MCUs.
+    pub fn mcu_width(&self) -> usize {
+        if self.components.len() == 1 {
+            8
+        } else {
+            8 * self.max_horizontal_sampling()
+        }
+    }
+
+    /// Height in pixels of an MCU
+    pub fn mcu_height(&self) -> usize {
+        if self.components.len() == 1 {
+            8
+        } else {
+            8 * self.max_vertical_sampling()
+        }
+    }
+
+    /// Width and height in samples of an MCU of a lossless frame, whose MCUs hold a sample
+    /// rather than a block for each unit of sampling
+    pub fn lossless_mcu_size(&self) -> (usize, usize) {
+        if self.components.len() == 1 {
+            (1, 1)
+        } else {
+            (self.max_horizontal_sampling(), self.max_vertical_sampling())
+        }
+    }
+
+    /// Number of MCUs needed to cover a row of the image
+    pub fn mcus_per_line(&self) -> usize {
+        self.width.div_ceil(self.mcu_width())
+    }
+
+    /// Number of MCU rows needed to cover the image
+    pub fn mcu_rows(&self) -> usize {
+        self.height.div_ceil(self.mcu_height())
+    }
+
+    /// Width in samples of the component at `idx`, i.e the image width scaled by its horizontal sampling factor
+    pub fn component_width(&self, idx: usize) -> usize {
+        (self.width * self.components[idx].horizontal_sampling as usize)
+            .div_ceil(self.max_horizontal_sampling())
+    }
+
+    /// Height in samples of the component at `idx`
+    pub fn component_height(&self, idx: usize) -> usize {
+        (self.height * self.components[idx].vertical_sampling as usize)
+            .div_ceil(self.max_vertical_sampling())
+    }
+
+    /// Number of 8x8 blocks in each row of the component at `idx`, padded to a whole number of MCUs
+    pub fn blocks_per_line(&self, idx: usize) -> usize {
+        if self.components.len() == 1 {
+            self.component_width(idx).div_ceil(8)
+        } else {
+            self.mcus_per_line() * self.components[idx].horizontal_sampling as usize
+        }
+    }
+
+    /// Number of rows of 8x8 blocks in the component at `idx`, padded to a whole number of MCUs
+    pub fn block_rows(&self, idx: usize) -> usize {
+        if self.components.len() == 1 {
+            self.component_height(idx).div_ceil(8)
+        } else {
+            self.mcu_rows() * self.components[idx].vertical_sampling as usize
+        }
+    }
+
+    /// Chroma subsampling of the image, if it has at least three components whose
+    /// sampling factors correspond to one of the common schemes
+    pub fn subsampling(&self) -> Option<ChromaSubsampling> {
+        let [luma, cb, cr, ..] = self.components.as_slice() else {
+            return None;
+        };
+        if (cb.horizontal_sampling, cb.vertical_sampling)
+            != (cr.horizontal_sampling, cr.vertical_sampling)
+            || luma.horizontal_sampling % cb.horizontal_sampling != 0
+            || luma.vertical_sampling % cb.vertical_sampling != 0
+        {
+            return None;
+        }
+        match (
+            luma.horizontal_sampling / cb.horizontal_sampling,
+            luma.vertical_sampling / cb.vertical_sampling,
+        ) {
+            (1, 1) => Some(ChromaSubsampling::S444),
+            (2, 1) => Some(ChromaSubsampling::S422),
+            (2, 2) => Some(ChromaSubsampling::S420),
+            (4, 1) => Some(ChromaSubsampling::S411),
+            (1, 2) => Some(ChromaSubsampling::S440),
+            _ => None,
+        }
+    }
+}
+
+pub(crate) fn parse_start_frame(segment: &Segment) -> Result<ImgProps> {
+    let frame = &segment.payload;
+    let bad_length = JpegError::BadLength {
+        offset: segment.offset,
+        marker: segment.marker,
+        length: segment.length,
+    };
+    if frame.len() < 6 {
+        return Err(bad_length);
+    }
+
+    let conv = |idx: usize| -> usize { u16::from_be_bytes([frame[idx], frame[idx + 1]]) as usize };
+    let component_count = frame[5] as usize;
+    // Each component is described by 3 bytes: Ci, Hi << 4 | Vi and Tqi
+    let specs = &frame[6..];
+    if component_count == 0 || specs.len() != component_count * 3 {
+        return Err(bad_length);
+    }
+
+    let mut components = Vec::with_capacity(component_count);
+    for spec in specs.chunks_exact(3) {
+        let component = Component {
+            id: spec[0],
+            horizontal_sampling: spec[1] >> 4,
+            vertical_sampling: spec[1] & 0x0F,
+            quant_table: spec[2],
+        };
+        if !(1..=4).contains(&component.horizontal_sampling)
+            || !(1..=4).contains(&component.vertical_sampling)
+        {
+            return Err(JpegError::Malformed {
+                offset: segment.offset,
+                marker: segment.marker,
+                reason: "sampling factors must be between 1 and 4",
+            });
+        }
+        components.push(component);
+    }
+
+    Ok(ImgProps {
+        bit_depth: frame[0] as usize,
+        height: conv(1),
+        width: conv(3),
+        components,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::marker::JpegMarker;
+
+    /// A SOF0 segment for an 8 bit frame of `width` x `height` with a component for each of
+    /// the given sampling factors, written as Hi << 4 | Vi
+    fn segment(width: u16, height: u16, sampling: &[u8]) -> Segment {
+        let mut payload = vec![8];
+        payload.extend(height.to_be_bytes());
+        payload.extend(width.to_be_bytes());
+        payload.push(sampling.len() as u8);
+        for (id, factors) in (1..).zip(sampling) {
+            payload.extend([id, *factors, (id > 1) as u8]);
+        }
+        Segment {
+            marker: JpegMarker::SOF(0xC0),
+            offset: 20,
+            length: payload.len(),
+            payload,
+            entropy: None,
+        }
+    }
+
+    #[test]
+    fn components_and_dimensions_are_read() {
+        let props = parse_start_frame(&segment(61, 45, &[0x22, 0x11, 0x11])).unwrap();
+        assert_eq!((props.width, props.height, props.bit_depth), (61, 45, 8));
+        assert_eq!(
+            props.components,
+            [
+                Component {
+                    id: 1,
+                    horizontal_sampling: 2,
+                    vertical_sampling: 2,
+                    quant_table: 0,
+                },
+                Component {
+                    id: 2,
+                    horizontal_sampling: 1,
+                    vertical_sampling: 1,
+                    quant_table: 1,
+                },
+                Component {
+                    id: 3,
+                    horizontal_sampling: 1,
+                    vertical_sampling: 1,
+                    quant_table: 1,
+                },
+            ]
+        );
+        assert_eq!((props.mcu_width(), props.mcu_height()), (16, 16));
+        assert_eq!((props.mcus_per_line(), props.mcu_rows()), (4, 3));
+        assert_eq!(
+            (props.component_width(1), props.component_height(1)),
+            (31, 23)
+        );
+        assert_eq!((props.blocks_per_line(0), props.block_rows(0)), (8, 6));
+        assert_eq!((props.blocks_per_line(2), props.block_rows(2)), (4, 3));
+    }
+
+    #[test]
+    fn single_components_use_8x8_mcus_whatever_their_sampling_factors() {
+        let props = parse_start_frame(&segment(61, 45, &[0x22])).unwrap();
+        assert_eq!((props.mcu_width(), props.mcu_height()), (8, 8));
+        assert_eq!((props.mcus_per_line(), props.mcu_rows()), (8, 6));
+        assert_eq!((props.blocks_per_line(0), props.block_rows(0)), (8, 6));
+        assert_eq!(props.subsampling(), None);
+    }
+
+    #[test]
+    fn lossless_mcus_hold_a_sample_for_each_unit_of_sampling() {
+        let props = parse_start_frame(&segment(61, 45, &[0x22, 0x11, 0x11])).unwrap();
+        assert_eq!(props.lossless_mcu_size(), (2, 2));
+        let props = parse_start_frame(&segment(61, 45, &[0x22])).unwrap();
+        assert_eq!(props.lossless_mcu_size(), (1, 1));
+    }
+
+    #[test]
+    fn sampling_factors_must_be_between_1_and_4() {
+        for factors in [0x01, 0x10, 0x51, 0x15, 0x00, 0xFF] {
+            match parse_start_frame(&segment(16, 16, &[0x11, factors, 0x11])) {
+                Err(JpegError::Malformed { offset, marker, .. }) => {
+                    assert_eq!((offset, marker), (20, JpegMarker::SOF(0xC0)));
+                }
+                other => panic!("expected Malformed for {:#04x}, got {:?}", factors, other),
+            }
+        }
+        for factors in [0x11, 0x14, 0x41, 0x44] {
+            assert!(parse_start_frame(&segment(16, 16, &[factors])).is_ok());
+        }
+    }
+
+    #[test]
+    fn component_specifications_must_fill_the_segment() {
+        let mut short = segment(16, 16, &[0x11, 0x11]);
+        short.payload.pop();
+        let mut long = segment(16, 16, &[0x11, 0x11]);
+        long.payload.push(0);
+        let none = segment(16, 16, &[]);
+        let mut truncated = segment(16, 16, &[]);
+        truncated.payload.truncate(5);
+        for bad in [short, long, none, truncated] {
+            assert!(
+                matches!(
+                    parse_start_frame(&bad),
+                    Err(JpegError::BadLength { offset: 20, .. })
+                ),
+                "payload {:?}",
+                bad.payload
+            );
+        }
+    }
+
+    #[test]
+    fn subsampling_is_named_from_the_chroma_sampling_factors() {
+        for (sampling, name) in [
+            ([0x11, 0x11, 0x11], "4:4:4"),
+            ([0x21, 0x11, 0x11], "4:2:2"),
+            ([0x22, 0x11, 0x11], "4:2:0"),
+            ([0x41, 0x11, 0x11], "4:1:1"),
+            ([0x12, 0x11, 0x11], "4:4:0"),
+            ([0x42, 0x21, 0x21], "4:2:0"),
+            ([0x22, 0x22, 0x22], "4:4:4"),
+        ] {
+            let props = parse_start_frame(&segment(16, 16, &sampling)).unwrap();
+            let subsampling = props.subsampling().map(|s| s.to_string());
+            assert_eq!(subsampling.as_deref(), Some(name), "{:02x?}", sampling);
+        }
+
+        // Chroma components which differ, or which do not divide the luma sampling factors,
+        // match none of the common schemes
+        for sampling in [
+            [0x22, 0x21, 0x11],
+            [0x31, 0x21, 0x21],
+            [0x11, 0x21, 0x21],
+            [0x44, 0x11, 0x11],
+        ] {
+            let props = parse_start_frame(&segment(16, 16, &sampling)).unwrap();
+            assert_eq!(props.subsampling(), None, "{:02x?}", sampling);
+        }
+        let props = parse_start_frame(&segment(16, 16, &[0x22, 0x11])).unwrap();
+        assert_eq!(props.subsampling(), None);
+    }
+}
@@ -0,0 +1,325 @@
+use crate::{
+    bitreader::BitReader,
+    error::{JpegError, Result},
+    segment::Segment,
+};
+
+/// Whether a Huffman or arithmetic conditioning table is used for DC differences or AC coefficients (Tc)
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TableClass {
+    DC,
+    AC,
+}
+
+/// A canonical Huffman code, as generated by the procedure in ITU T.81 Annex C
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct HuffmanCode {
+    /// The code itself, right-aligned in `length` bits
+    pub code: u16,
+    pub length: u8,
+    pub symbol: u8,
+}
+
+/// A Huffman table, as defined by a DHT segment
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HuffmanTable {
+    pub class: TableClass,
+    /// Huffman table destination identifier (Th), 0 -> 3
+    pub id: u8,
+    /// Number of codes of each length from 1 to 16 bits (Li)
+    pub counts: [u8; 16],
+    /// Symbol values in order of increasing code length (Vi,j)
+    pub symbols: Vec<u8>,
+    /// The canonical code assigned to each symbol, in the same order as `symbols`
+    pub codes: Vec<HuffmanCode>,
+}
+
+/// Generate the canonical codes for a table, returning None if the code lengths are over-subscribed
+fn canonical_codes(counts: &[u8; 16], symbols: &[u8]) -> Option<Vec<HuffmanCode>> {
+    let mut codes = Vec::with_capacity(symbols.len());
+    let mut symbols = symbols.iter();
+    let mut code = 0u32;
+    for (idx, count) in counts.iter().enumerate() {
+        let length = idx as u8 + 1;
+        for _ in 0..*count {
+            // Every code must fit within its length
+            if code >= 1 << length {
+                return None;
+            }
+            codes.push(HuffmanCode {
+                code: code as u16,
+                length,
+                symbol: *symbols.next()?,
+            });
+            code += 1;
+        }
+        code <<= 1;
+    }
+    Some(codes)
+}
+
+/// Parse every Huffman table defined in a DHT segment
+pub(crate) fn parse_huffman_tables(segment: &Segment) -> Result<Vec<HuffmanTable>> {
+    let bad_length = || JpegError::BadLength {
+        offset: segment.offset,
+        marker: segment.marker,
+        length: segment.length,
+    };
+    let malformed = |reason| JpegError::Malformed {
+        offset: segment.offset,
+        marker: segment.marker,
+        reason,
+    };
+
+    let mut tables = Vec::new();
+    let mut data = segment.payload.as_slice();
+    while let Some((&tc_th, rest)) = data.split_first() {
+        let class = match tc_th >> 4 {
+            0 => TableClass::DC,
+            1 => TableClass::AC,
+            _ => return Err(malformed("Huffman table class must be 0 or 1")),
+        };
+        let id = tc_th & 0x0F;
+        if id > 3 {
+            return Err(malformed("Huffman table destination must be 0 -> 3"));
+        }
+
+        if rest.len() < 16 {
+            return Err(bad_length());
+        }
+        let (lengths, rest) = rest.split_at(16);
+        let mut counts = [0u8; 16];
+        counts.copy_from_slice(lengths);
+        let total: usize = counts.iter().map(|c| *c as usize).sum();
+        if total > 256 {
+            return Err(malformed("Huffman table defines more than 256 symbols"));
+        }
+        if rest.len() < total {
+            return Err(bad_length());
+        }
+        let (symbols, rest) = rest.split_at(total);
+
+        let codes = canonical_codes(&counts, symbols)
+            .ok_or_else(|| malformed("Huffman table is over-subscribed"))?;
+        tables.push(HuffmanTable {
+            class,
+            id,
+            counts,
+            symbols: symbols.to_vec(),
+            codes,
+        });
+        data = rest;
+    }
+    Ok(tables)
+}
+
+/// Number of bits looked up at once when decoding; longer codes fall back to a search
+const LOOKAHEAD: u32 = 9;
+
+/// A Huffman table prepared for decoding (ITU T.81 F.2.2.3, with a lookup table for short codes)
+#[derive(Debug, Clone)]
+pub(crate) struct HuffmanDecoder {
+    /// For each `LOOKAHEAD` bit prefix, the length and symbol of the code it starts with (length 0 if the code is longer)
+    lookup: Vec<(u8, u8)>,
+    /// Largest code of each length, or -1 if there are no codes of that length
+    maxcode: [i32; 17],
+    /// Added to a code of each length to give the index of its symbol
+    valoffset: [i32; 17],
+    symbols: Vec<u8>,
+}
+
+impl HuffmanDecoder {
+    pub(crate) fn new(table: &HuffmanTable) -> HuffmanDecoder {
+        let mut lookup = vec![(0u8, 0u8); 1 << LOOKAHEAD];
+        let mut maxcode = [-1i32; 17];
+        let mut valoffset = [0i32; 17];
+        for (idx, code) in table.codes.iter().enumerate() {
+            let length = code.length as u32;
+            if maxcode[length as usize] == -1 {
+                valoffset[length as usize] = idx as i32 - code.code as i32;
+            }
+            maxcode[length as usize] = code.code as i32;
+            if length <= LOOKAHEAD {
+                let first = (code.code as usize) << (LOOKAHEAD - length);
+                for entry in &mut lookup[first..first + (1 << (LOOKAHEAD - length))] {
+                    *entry = (code.length, code.symbol);
+                }
+            }
+        }
+        HuffmanDecoder {
+            lookup,
+            maxcode,
+            valoffset,
+            symbols: table.symbols.clone(),
+        }
+    }
+
+    /// Decode the next symbol, or None if the bits do not form a code in this table
+    pub(crate) fn decode(&self, reader: &mut BitReader) -> Option<u8> {
+        let (length, symbol) = self.lookup[reader.peek(LOOKAHEAD) as usize];
+        if length > 0 {
+            reader.consume(length as u32);
+            return Some(symbol);
+        }
+        let bits = reader.peek(16);
+        for length in LOOKAHEAD + 1..=16 {
+            let code = (bits >> (16 - length)) as i32;
+            if code <= self.maxcode[length as usize] {
+                reader.consume(length);
+                return self
+                    .symbols
+                    .get((code + self.valoffset[length as usize]) as usize)
+                    .copied();
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitreader::tests::pack;
+    use crate::marker::JpegMarker;
+
+    /// A table of `class` with `counts` codes of each length, for `symbols`
+    fn table(class: TableClass, counts: [u8; 16], symbols: &[u8]) -> HuffmanTable {
+        HuffmanTable {
+            class,
+            id: 0,
+            counts,
+            symbols: symbols.to_vec(),
+            codes: canonical_codes(&counts, symbols).unwrap(),
+        }
+    }
+
+    fn segment(payload: &[u8]) -> Segment {
+        Segment {
+            marker: JpegMarker::DHT,
+            offset: 20,
+            length: payload.len(),
+            payload: payload.to_vec(),
+            entropy: None,
+        }
+    }
+
+    #[test]
+    fn canonical_codes_follow_annex_k() {
+        // The luminance DC table of ITU T.81 Table K.3
+        let counts = [0, 1, 5, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0];
+        let symbols: Vec<u8> = (0..12).collect();
+        let codes = canonical_codes(&counts, &symbols).unwrap();
+        let expected = [
+            (0b00, 2),
+            (0b010, 3),
+            (0b011, 3),
+            (0b100, 3),
+            (0b101, 3),
+            (0b110, 3),
+            (0b1110, 4),
+            (0b11110, 5),
+            (0b111110, 6),
+            (0b1111110, 7),
+            (0b11111110, 8),
+            (0b111111110, 9),
+        ];
+        for (code, (bits, length)) in codes.iter().zip(expected) {
+            assert_eq!(
+                (code.code, code.length),
+                (bits, length),
+                "symbol {}",
+                code.symbol
+            );
+        }
+    }
+
+    #[test]
+    fn over_subscribed_lengths_have_no_codes() {
+        let mut counts = [0; 16];
+        counts[0] = 3;
+        assert_eq!(canonical_codes(&counts, &[1, 2, 3]), None);
+        // Too few symbols for the counts
+        assert_eq!(canonical_codes(&counts[..].try_into().unwrap(), &[1]), None);
+    }
+
+    #[test]
+    fn every_table_of_a_segment_is_read() {
+        // DC table 0 with four 2 bit codes, then AC table 1 with three codes of 2 and 3 bits
+        let mut payload = vec![0x00, 0, 4];
+        payload.extend([0; 14]);
+        payload.extend([0, 1, 2, 3]);
+        payload.extend([0x11, 0, 2, 1]);
+        payload.extend([0; 13]);
+        payload.extend([0x00, 0xF0, 0x22]);
+        let tables = parse_huffman_tables(&segment(&payload)).unwrap();
+        assert_eq!(tables.len(), 2);
+        assert_eq!((tables[0].class, tables[0].id), (TableClass::DC, 0));
+        assert_eq!((tables[1].class, tables[1].id), (TableClass::AC, 1));
+        assert_eq!(tables[1].symbols, [0x00, 0xF0, 0x22]);
+        let codes: Vec<_> = tables[1]
+            .codes
+            .iter()
+            .map(|c| (c.code, c.length, c.symbol))
+            .collect();
+        assert_eq!(codes, [(0b00, 2, 0x00), (0b01, 2, 0xF0), (0b100, 3, 0x22)]);
+    }
+
+    #[test]
+    fn bad_tables_are_rejected() {
+        let mut table = vec![0x00, 0, 1];
+        table.extend([0; 14]);
+        table.push(5);
+        // Class 2 and destination 4
+        for tc_th in [0x20, 0x04] {
+            table[0] = tc_th;
+            assert!(matches!(
+                parse_huffman_tables(&segment(&table)),
+                Err(JpegError::Malformed { offset: 20, .. })
+            ));
+        }
+        // The symbol is missing
+        table[0] = 0x00;
+        assert!(matches!(
+            parse_huffman_tables(&segment(&table[..17])),
+            Err(JpegError::BadLength { offset: 20, .. })
+        ));
+        // Three one bit codes
+        let mut payload = vec![0x00, 3];
+        payload.extend([0; 15]);
+        payload.extend([1, 2, 3]);
+        assert!(matches!(
+            parse_huffman_tables(&segment(&payload)),
+            Err(JpegError::Malformed { .. })
+        ));
+    }
+
+    #[test]
+    fn decodes_codes_shorter_and_longer_than_the_lookahead() {
+        // One code of each length from 1 to 12 bits: 0, 10, 110, ... 111111111110
+        let mut counts = [0; 16];
+        counts[..12].fill(1);
+        let symbols: Vec<u8> = (1..=12).map(|n| n * 0x10).collect();
+        let decoder = HuffmanDecoder::new(&table(TableClass::AC, counts, &symbols));
+        let codes: Vec<(u32, u32)> = (1..=12).rev().map(|n| ((1 << n) - 2, n)).collect();
+        let data = pack(&codes);
+        let mut reader = BitReader::new(&data, 0);
+        for n in (1..=12).rev() {
+            assert_eq!(decoder.decode(&mut reader), Some(n * 0x10));
+        }
+        assert!(!reader.overrun());
+    }
+
+    #[test]
+    fn bits_which_are_no_code_are_rejected() {
+        // Codes 00, 01 and 10 leave every prefix 11 unused
+        let mut counts = [0; 16];
+        counts[1] = 3;
+        let decoder = HuffmanDecoder::new(&table(TableClass::DC, counts, &[4, 5, 6]));
+        let data = pack(&[(0b10, 2), (0b01, 2), (0b11, 2)]);
+        let mut reader = BitReader::new(&data, 0);
+        assert_eq!(decoder.decode(&mut reader), Some(6));
+        assert_eq!(decoder.decode(&mut reader), Some(5));
+        assert_eq!(decoder.decode(&mut reader), None);
+    }
+}
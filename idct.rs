@@ -0,0 +1,188 @@
+use crate::image::Sample;
+
+/// Fixed point representation of a constant, scaled by 2^13
+const fn fix(x: f64) -> i64 {
+    (x * 8192.0 + 0.5) as i64
+}
+
+/// One dimensional 8 point IDCT (the islow algorithm from the IJG's jidctint.c).
+/// Returns the even part (x0 -> x3) and odd part (t0 -> t3), which are combined by the caller.
+/// 64 bit arithmetic is used so that corrupt coefficients cannot overflow.
+#[inline(always)]
+fn idct_1d(s: [i64; 8]) -> ([i64; 4], [i64; 4]) {
+    let p1 = (s[2] + s[6]) * fix(0.5411961);
+    let t2 = p1 + s[6] * -fix(1.847759065);
+    let t3 = p1 + s[2] * fix(0.765366865);
+    let t0 = (s[0] + s[4]) << 13;
+    let t1 = (s[0] - s[4]) << 13;
+    let even = [t0 + t3, t1 + t2, t1 - t2, t0 - t3];
+
+    let (t0, t1, t2, t3) = (s[7], s[5], s[3], s[1]);
+    let p3 = t0 + t2;
+    let p4 = t1 + t3;
+    let p1 = t0 + t3;
+    let p2 = t1 + t2;
+    let p5 = (p3 + p4) * fix(1.175875602);
+    let p1 = p5 + p1 * -fix(0.899976223);
+    let p2 = p5 + p2 * -fix(2.562915447);
+    let p3 = p3 * -fix(1.961570560);
+    let p4 = p4 * -fix(0.390180644);
+    let odd = [
+        t0 * fix(0.298631336) + p1 + p3,
+        t1 * fix(2.053119869) + p2 + p4,
+        t2 * fix(3.072711026) + p2 + p3,
+        t3 * fix(1.501321110) + p1 + p4,
+    ];
+    (even, odd)
+}
+
+/// Dequantize a block of coefficients (natural order) and transform it into 8x8 samples,
+/// written to `out` with rows `stride` apart. Samples are level shifted and clamped to `precision` bits.
+pub(crate) fn idct_block<T: Sample>(
+    coefficients: &[i16; 64],
+    quant: &[u16; 64],
+    out: &mut [T],
+    stride: usize,
+    precision: usize,
+) {
+    // As in the IJG's code, 12 bit samples keep 1 extra bit between the passes rather than 2
+    // so that the larger coefficients cannot overflow 32 bit arithmetic
+    let pass1_bits = if precision > 8 { 1 } else { 2 };
+    let mut workspace = [0i64; 64];
+
+    // Columns, keeping `pass1_bits` extra bits of precision
+    for col in 0..8 {
+        let s: [i64; 8] = std::array::from_fn(|row| {
+            coefficients[row * 8 + col] as i64 * quant[row * 8 + col] as i64
+        });
+        if s[1..].iter().all(|v| *v == 0) {
+            // A column with only a DC term is flat
+            for row in 0..8 {
+                workspace[row * 8 + col] = s[0] << pass1_bits;
+            }
+            continue;
+        }
+        let (even, odd) = idct_1d(s);
+        let shift = 13 - pass1_bits;
+        for i in 0..4 {
+            let x = even[i] + (1 << (shift - 1));
+            workspace[i * 8 + col] = (x + odd[3 - i]) >> shift;
+            workspace[(7 - i) * 8 + col] = (x - odd[3 - i]) >> shift;
+        }
+    }
+
+    // Rows. The constants scaled by 2^13, the first pass left 2^pass1_bits and both passes
+    // together scale by 8 (2^3), so those bits are removed whilst rounding and level shifting.
+    let shift = 13 + pass1_bits + 3;
+    let (center, max) = (1i64 << (precision - 1), (1i64 << precision) - 1);
+    for row in 0..8 {
+        let s: [i64; 8] = std::array::from_fn(|col| workspace[row * 8 + col]);
+        let (even, odd) = idct_1d(s);
+        let out = &mut out[row * stride..row * stride + 8];
+        for i in 0..4 {
+            let x = even[i] + (1 << (shift - 1)) + (center << shift);
+            out[i] = T::from_u32(((x + odd[3 - i]) >> shift).clamp(0, max) as u32);
+            out[7 - i] = T::from_u32(((x - odd[3 - i]) >> shift).clamp(0, max) as u32);
+        }
+    }
+}
+
+/// Divide by 2^`shift`, rounding to nearest
+#[inline(always)]
+fn descale(value: i64, shift: u32) -> i64 {
+    (value + (1 << (shift - 1))) >> shift
+}
+
+/// As `idct_block`, producing `size` x `size` samples (8, 4, 2 or 1) from the lowest frequencies
+/// of the block. Smaller sizes use the reduced IDCTs of the IJG's jidctred.c, which scale the
+/// block down by 8 / `size` for far less work than a full transform.
+pub(crate) fn idct_block_scaled<T: Sample>(
+    coefficients: &[i16; 64],
+    quant: &[u16; 64],
+    out: &mut [T],
+    stride: usize,
+    precision: usize,
+    size: usize,
+) {
+    if size == 8 {
+        return idct_block(coefficients, quant, out, stride, precision);
+    }
+    let pass1_bits = if precision > 8 { 1 } else { 2 };
+    let (center, max) = (1i64 << (precision - 1), (1i64 << precision) - 1);
+    let sample = |value: i64| T::from_u32((value + center).clamp(0, max) as u32);
+    let column = |col: usize| -> [i64; 8] {
+        std::array::from_fn(|row| coefficients[row * 8 + col] as i64 * quant[row * 8 + col] as i64)
+    };
+    // Both reduced transforms skip the columns whose outputs the row pass ignores
+    match size {
+        4 => {
+            let mut workspace = [[0i64; 8]; 4];
+            for col in [0, 1, 2, 3, 5, 6, 7] {
+                for (row, value) in idct_4(column(col), 13 - pass1_bits + 1)
+                    .into_iter()
+                    .enumerate()
+                {
+                    workspace[row][col] = value;
+                }
+            }
+            for (row, s) in workspace.into_iter().enumerate() {
+                let values = idct_4(s, 13 + pass1_bits + 3 + 1);
+                for (x, value) in values.into_iter().enumerate() {
+                    out[row * stride + x] = sample(value);
+                }
+            }
+        }
+        2 => {
+            let mut workspace = [[0i64; 8]; 2];
+            for col in [0, 1, 3, 5, 7] {
+                for (row, value) in idct_2(column(col), 13 - pass1_bits + 2)
+                    .into_iter()
+                    .enumerate()
+                {
+                    workspace[row][col] = value;
+                }
+            }
+            for (row, s) in workspace.into_iter().enumerate() {
+                let values = idct_2(s, 13 + pass1_bits + 3 + 2);
+                out[row * stride] = sample(values[0]);
+                out[row * stride + 1] = sample(values[1]);
+            }
+        }
+        _ => out[0] = sample(descale(column(0)[0], 3)),
+    }
+}
+
+/// 4 point reduced IDCT (jpeg_idct_4x4 in the IJG's jidctred.c), descaled by `shift` bits
+#[inline(always)]
+fn idct_4(s: [i64; 8], shift: u32) -> [i64; 4] {
+    let t0 = s[0] << 14;
+    let t2 = s[2] * fix(1.847759065) + s[6] * -fix(0.765366865);
+    let (t10, t12) = (t0 + t2, t0 - t2);
+
+    let (z1, z2, z3, z4) = (s[7], s[5], s[3], s[1]);
+    let t0 = z1 * -fix(0.211164243)
+        + z2 * fix(1.451774981)
+        + z3 * -fix(2.172734803)
+        + z4 * fix(1.061594337);
+    let t2 = z1 * -fix(0.509795579)
+        + z2 * -fix(0.601344887)
+        + z3 * fix(0.899976223)
+        + z4 * fix(2.562915447);
+    [
+        descale(t10 + t2, shift),
+        descale(t12 + t0, shift),
+        descale(t12 - t0, shift),
+        descale(t10 - t2, shift),
+    ]
+}
+
+/// 2 point reduced IDCT (jpeg_idct_2x2 in the IJG's jidctred.c), descaled by `shift` bits
+#[inline(always)]
+fn idct_2(s: [i64; 8], shift: u32) -> [i64; 2] {
+    let t10 = s[0] << 15;
+    let t0 = s[7] * -fix(0.720959822)
+        + s[5] * fix(0.850430095)
+        + s[3] * -fix(1.272758580)
+        + s[1] * fix(3.624509785);
+    [descale(t10 + t0, shift), descale(t10 - t0, shift)]
+}
@@ -0,0 +1,516 @@
+use crate::{
+    color::{self, ColorSpace},
+    decoder::{Coefficients, ComponentSamples},
+    frame::ImgProps,
+    idct::idct_block_scaled,
+    lossless::SamplePlane,
+    parallel,
+    upsample::Plane,
+};
+
+/// Layout of the samples of a decoded `Image`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PixelFormat {
+    /// One 8 bit sample per pixel
+    Gray8,
+    /// Three 8 bit samples per pixel, in the order red, green, blue
+    Rgb8,
+    /// One sample of up to 16 bits per pixel
+    Gray16,
+    /// Three samples of up to 16 bits per pixel, in the order red, green, blue
+    Rgb16,
+    /// Four 8 bit samples per pixel, in the order cyan, magenta, yellow, black, where 0 is no ink
+    Cmyk8,
+    /// Four samples of up to 16 bits per pixel, in the order cyan, magenta, yellow, black
+    Cmyk16,
+}
+
+impl PixelFormat {
+    /// Number of samples per pixel
+    pub fn channels(self) -> usize {
+        match self {
+            PixelFormat::Gray8 | PixelFormat::Gray16 => 1,
+            PixelFormat::Rgb8 | PixelFormat::Rgb16 => 3,
+            PixelFormat::Cmyk8 | PixelFormat::Cmyk16 => 4,
+        }
+    }
+
+    /// The format with `channels` samples per pixel, each either 8 or 16 bits wide
+    pub(crate) fn with_channels(channels: usize, wide: bool) -> PixelFormat {
+        match (channels, wide) {
+            (1, false) => PixelFormat::Gray8,
+            (1, true) => PixelFormat::Gray16,
+            (4, false) => PixelFormat::Cmyk8,
+            (4, true) => PixelFormat::Cmyk16,
+            (_, false) => PixelFormat::Rgb8,
+            (_, true) => PixelFormat::Rgb16,
+        }
+    }
+}
+
+/// Size at which a DCT image is decoded, relative to its full size
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum Scale {
+    #[default]
+    Full,
+    Half,
+    Quarter,
+    /// Only the DC coefficient of each block is used
+    Eighth,
+}
+
+impl Scale {
+    /// The scale reducing each dimension by `denominator` (1, 2, 4 or 8)
+    pub fn from_denominator(denominator: usize) -> Option<Scale> {
+        match denominator {
+            1 => Some(Scale::Full),
+            2 => Some(Scale::Half),
+            4 => Some(Scale::Quarter),
+            8 => Some(Scale::Eighth),
+            _ => None,
+        }
+    }
+
+    /// How many times smaller each dimension of the image becomes
+    pub fn denominator(self) -> usize {
+        match self {
+            Scale::Full => 1,
+            Scale::Half => 2,
+            Scale::Quarter => 4,
+            Scale::Eighth => 8,
+        }
+    }
+
+    /// Width and height of the decoded samples of each 8x8 block
+    fn block_size(self) -> usize {
+        8 / self.denominator()
+    }
+
+    /// Width and height of the decoded samples of each block of the component at `idx`.
+    /// As in libjpeg, subsampled components are scaled down less (by transforming larger blocks)
+    /// rather than upsampled, where their sampling factors allow it.
+    pub(crate) fn component_block_size(self, props: &ImgProps, idx: usize) -> usize {
+        let (hmax, vmax) = (
+            props.max_horizontal_sampling(),
+            props.max_vertical_sampling(),
+        );
+        let c = &props.components[idx];
+        let (h, v) = (c.horizontal_sampling as usize, c.vertical_sampling as usize);
+        let scaled = self.block_size();
+        let mut size = scaled;
+        while size < 8
+            && (hmax * scaled).is_multiple_of(h * size * 2)
+            && (vmax * scaled).is_multiple_of(v * size * 2)
+        {
+            size *= 2;
+        }
+        size
+    }
+}
+
+/// A rectangle of pixels within a decoded image
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Region {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+/// How the components of a DCT image are turned into pixels
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) struct Conversion {
+    pub(crate) space: ColorSpace,
+    pub(crate) scale: Scale,
+    /// Whether CMYK (or YCCK) samples are stored inverted, as Adobe applications write them
+    pub(crate) inverted: bool,
+    /// Convert CMYK (or YCCK) samples to RGB rather than outputting CMYK
+    pub(crate) cmyk_to_rgb: bool,
+}
+
+impl Conversion {
+    /// Number of samples in each output pixel
+    pub(crate) fn channels(&self) -> usize {
+        match self.space {
+            ColorSpace::Grayscale => 1,
+            ColorSpace::YCbCr | ColorSpace::Rgb => 3,
+            ColorSpace::Cmyk | ColorSpace::Ycck if self.cmyk_to_rgb => 3,
+            ColorSpace::Cmyk | ColorSpace::Ycck => 4,
+        }
+    }
+
+    /// Width and height of the output image, rounded up when scaled down
+    pub(crate) fn size(&self, props: &ImgProps) -> (usize, usize) {
+        let denominator = self.scale.denominator();
+        (
+            props.width.div_ceil(denominator),
+            props.height.div_ceil(denominator),
+        )
+    }
+
+    /// Wrap the transformed samples of the component at `idx`, starting with its first row
+    pub(crate) fn plane<T>(
+        &self,
+        props: &ImgProps,
+        idx: usize,
+        data: Vec<T>,
+        stride: usize,
+    ) -> Plane<T> {
+        let (hmax, vmax) = (
+            props.max_horizontal_sampling(),
+            props.max_vertical_sampling(),
+        );
+        let c = &props.components[idx];
+        let (h, v) = (c.horizontal_sampling as usize, c.vertical_sampling as usize);
+        let (scaled, size) = (
+            self.scale.block_size(),
+            self.scale.component_block_size(props, idx),
+        );
+        let width = (props.width * h * size).div_ceil(hmax * 8);
+        Plane {
+            data,
+            stride,
+            first_row: 0,
+            width,
+            height: (props.height * v * size).div_ceil(vmax * 8),
+            // As in libjpeg, single samples per block are too coarse to interpolate, and
+            // so are rows of two samples
+            fancy: scaled > 1 && width > 2,
+            scale: ((hmax * scaled) / (h * size), (vmax * scaled) / (v * size)),
+        }
+    }
+
+    /// Convert a row of upsampled samples of each component into a row of output pixels
+    pub(crate) fn convert_row<T: Sample>(&self, rows: &[Vec<T>], out: &mut [T], precision: usize) {
+        let channels = self.channels();
+        let max = (1u32 << precision) - 1;
+        match self.space {
+            ColorSpace::Grayscale => out.copy_from_slice(&rows[0]),
+            ColorSpace::YCbCr => {
+                for (x, pixel) in out.chunks_exact_mut(3).enumerate() {
+                    let rgb = color::ycbcr_to_rgb(rows[0][x], rows[1][x], rows[2][x], precision);
+                    pixel.copy_from_slice(&rgb);
+                }
+            }
+            ColorSpace::Rgb => {
+                for (x, pixel) in out.chunks_exact_mut(3).enumerate() {
+                    pixel.copy_from_slice(&[rows[0][x], rows[1][x], rows[2][x]]);
+                }
+            }
+            ColorSpace::Cmyk | ColorSpace::Ycck => {
+                for (x, pixel) in out.chunks_exact_mut(channels).enumerate() {
+                    let (c, m, y, k) = (rows[0][x], rows[1][x], rows[2][x], rows[3][x]);
+                    let cmyk = if self.space == ColorSpace::Ycck {
+                        color::ycck_to_cmyk(c, m, y, k, precision)
+                    } else {
+                        [c, m, y, k]
+                    };
+                    // Work with inverted samples, so that 0 is full coverage of ink
+                    let inverted = if self.inverted {
+                        cmyk
+                    } else {
+                        cmyk.map(|s| T::from_u32(max - s.into()))
+                    };
+                    if self.cmyk_to_rgb {
+                        pixel.copy_from_slice(&color::inverted_cmyk_to_rgb(inverted, precision));
+                    } else {
+                        pixel.copy_from_slice(&inverted.map(|s| T::from_u32(max - s.into())));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// A decoded image, with pixels stored row by row without padding
+#[derive(Debug, Clone)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub format: PixelFormat,
+    pub data: Vec<u8>,
+}
+
+/// A decoded image with samples wider than 8 bits (e.g from a lossless or 12 bit image),
+/// with pixels stored row by row without padding
+#[derive(Debug, Clone)]
+pub struct Image16 {
+    pub width: usize,
+    pub height: usize,
+    pub format: PixelFormat,
+    /// Number of significant bits in each sample
+    pub bit_depth: usize,
+    pub data: Vec<u16>,
+}
+
+impl From<Image> for Image16 {
+    fn from(image: Image) -> Image16 {
+        Image16 {
+            width: image.width,
+            height: image.height,
+            format: PixelFormat::with_channels(image.format.channels(), true),
+            bit_depth: 8,
+            data: image.data.into_iter().map(u16::from).collect(),
+        }
+    }
+}
+
+/// Narrow an image to 8 bit samples, giving it back unchanged unless its bit depth is 8
+impl TryFrom<Image16> for Image {
+    type Error = Image16;
+
+    fn try_from(image: Image16) -> Result<Image, Image16> {
+        if image.bit_depth != 8 {
+            return Err(image);
+        }
+        Ok(Image {
+            width: image.width,
+            height: image.height,
+            format: PixelFormat::with_channels(image.format.channels(), false),
+            data: image.data.into_iter().map(|sample| sample as u8).collect(),
+        })
+    }
+}
+
+impl Image16 {
+    /// The pixels of `region`, which lies within the image
+    pub(crate) fn crop(self, region: Region) -> Image16 {
+        Image16 {
+            width: region.width,
+            height: region.height,
+            data: crop(&self.data, self.width, self.format.channels(), region),
+            ..self
+        }
+    }
+
+    /// As `Image::from_coefficients`, for images with 12 bit samples
+    pub(crate) fn from_coefficients(
+        coefficients: &Coefficients,
+        conversion: Conversion,
+        threads: usize,
+    ) -> Image16 {
+        let (width, height) = conversion.size(&coefficients.props);
+        Image16 {
+            width,
+            height,
+            format: PixelFormat::with_channels(conversion.channels(), true),
+            bit_depth: coefficients.props.bit_depth,
+            data: render(coefficients, conversion, threads),
+        }
+    }
+
+    /// As `Image::from_transformed`, for images with 12 bit samples
+    pub(crate) fn from_transformed(
+        props: &ImgProps,
+        components: Vec<ComponentSamples>,
+        conversion: Conversion,
+        threads: usize,
+    ) -> Image16 {
+        let (width, height) = conversion.size(props);
+        Image16 {
+            width,
+            height,
+            format: PixelFormat::with_channels(conversion.channels(), true),
+            bit_depth: props.bit_depth,
+            data: render_transformed(props, components, conversion, threads),
+        }
+    }
+
+    /// Gather the samples of a lossless image into pixels, replicating those of subsampled
+    /// components, and convert them as `conversion` says just as the samples of DCT images are.
+    /// The caller has already checked that the image has 1, 3 or 4 components whose sampling
+    /// factors divide the largest.
+    pub(crate) fn from_samples(
+        props: &ImgProps,
+        planes: &[SamplePlane],
+        conversion: Conversion,
+    ) -> Image16 {
+        let (hmax, vmax) = (
+            props.max_horizontal_sampling(),
+            props.max_vertical_sampling(),
+        );
+        let channels = conversion.channels();
+        let mut rows = vec![vec![0; props.width]; planes.len()];
+        let mut data = vec![0; props.width * props.height * channels];
+        for (y, out) in data.chunks_exact_mut(props.width * channels).enumerate() {
+            for ((plane, component), row) in planes.iter().zip(&props.components).zip(&mut rows) {
+                let h = hmax / component.horizontal_sampling as usize;
+                let v = vmax / component.vertical_sampling as usize;
+                let samples = &plane.samples[(y / v) * plane.stride..];
+                for (x, sample) in row.iter_mut().enumerate() {
+                    *sample = samples[x / h];
+                }
+            }
+            conversion.convert_row(&rows, out, props.bit_depth);
+        }
+
+        Image16 {
+            width: props.width,
+            height: props.height,
+            format: PixelFormat::with_channels(channels, true),
+            bit_depth: props.bit_depth,
+            data,
+        }
+    }
+}
+
+impl Image {
+    /// The pixels of `region`, which lies within the image
+    pub(crate) fn crop(self, region: Region) -> Image {
+        Image {
+            width: region.width,
+            height: region.height,
+            data: crop(&self.data, self.width, self.format.channels(), region),
+            ..self
+        }
+    }
+
+    /// Dequantize and transform the coefficients of each component, upsample them to the
+    /// (possibly scaled) size of the image and convert them as described by `conversion`.
+    /// The caller has already checked that the image has 1, 3 or 4 components with 8 bit samples.
+    /// The work is shared between `threads` threads.
+    pub(crate) fn from_coefficients(
+        coefficients: &Coefficients,
+        conversion: Conversion,
+        threads: usize,
+    ) -> Image {
+        let (width, height) = conversion.size(&coefficients.props);
+        Image {
+            width,
+            height,
+            format: PixelFormat::with_channels(conversion.channels(), false),
+            data: render(coefficients, conversion, threads),
+        }
+    }
+
+    /// Upsample and convert the samples of components which were transformed as they were
+    /// decoded, as `from_coefficients` would their coefficients
+    pub(crate) fn from_transformed(
+        props: &ImgProps,
+        components: Vec<ComponentSamples>,
+        conversion: Conversion,
+        threads: usize,
+    ) -> Image {
+        let (width, height) = conversion.size(props);
+        Image {
+            width,
+            height,
+            format: PixelFormat::with_channels(conversion.channels(), false),
+            data: render_transformed(props, components, conversion, threads),
+        }
+    }
+}
+
+/// Copy the samples of `region` out of pixels `width` wide, each of `channels` samples
+fn crop<T: Copy>(data: &[T], width: usize, channels: usize, region: Region) -> Vec<T> {
+    let mut cropped = Vec::with_capacity(region.width * region.height * channels);
+    for y in region.y..region.y + region.height {
+        let start = (y * width + region.x) * channels;
+        cropped.extend_from_slice(&data[start..start + region.width * channels]);
+    }
+    cropped
+}
+
+/// A sample type which decoded pixels can be stored in, wide enough for the precision of the frame
+pub(crate) trait Sample: Copy + Default + Into<u32> + Send + Sync {
+    /// Convert a value already clamped to the precision of the frame
+    fn from_u32(value: u32) -> Self;
+}
+
+impl Sample for u8 {
+    #[inline(always)]
+    fn from_u32(value: u32) -> u8 {
+        value as u8
+    }
+}
+
+impl Sample for u16 {
+    #[inline(always)]
+    fn from_u32(value: u32) -> u16 {
+        value as u16
+    }
+}
+
+/// Transform, upsample and colour convert the coefficients of an image, sharing the work between
+/// `threads` threads
+fn render<T: Sample>(
+    coefficients: &Coefficients,
+    conversion: Conversion,
+    threads: usize,
+) -> Vec<T> {
+    let props = &coefficients.props;
+    let precision = props.bit_depth;
+    let planes: Vec<Plane<T>> = coefficients
+        .components
+        .iter()
+        .enumerate()
+        .map(|(idx, component)| {
+            let size = conversion.scale.component_block_size(props, idx);
+            let stride = component.blocks_per_line * size;
+            let mut data = vec![T::default(); stride * component.block_rows * size];
+            let quant = component.quant_table.unwrap_or([1; 64]);
+            // Each run of block rows is transformed on its own thread
+            parallel::for_each_run(&mut data, stride * size, threads, |first, strip| {
+                let rows = strip.len() / (stride * size);
+                let blocks = &component.blocks[first * component.blocks_per_line..];
+                for (n, block) in blocks[..rows * component.blocks_per_line]
+                    .iter()
+                    .enumerate()
+                {
+                    let (x, y) = (n % component.blocks_per_line, n / component.blocks_per_line);
+                    let out = &mut strip[y * size * stride + x * size..];
+                    idct_block_scaled(block, &quant, out, stride, precision, size);
+                }
+            });
+            conversion.plane(props, idx, data, stride)
+        })
+        .collect();
+    convert(props, &planes, conversion, threads)
+}
+
+/// As `render`, for components whose blocks were transformed as they were decoded
+fn render_transformed<T: Sample>(
+    props: &ImgProps,
+    components: Vec<ComponentSamples>,
+    conversion: Conversion,
+    threads: usize,
+) -> Vec<T> {
+    let planes: Vec<Plane<T>> = components
+        .into_iter()
+        .enumerate()
+        .map(|(idx, component)| {
+            let stride = component.stride();
+            let data = component
+                .samples
+                .into_iter()
+                .map(|sample| T::from_u32(sample.into()))
+                .collect();
+            conversion.plane(props, idx, data, stride)
+        })
+        .collect();
+    convert(props, &planes, conversion, threads)
+}
+
+/// Upsample the transformed samples of each component to the size of the image and colour
+/// convert them, sharing the work between `threads` threads
+fn convert<T: Sample>(
+    props: &ImgProps,
+    planes: &[Plane<T>],
+    conversion: Conversion,
+    threads: usize,
+) -> Vec<T> {
+    let precision = props.bit_depth;
+    let (width, height) = conversion.size(props);
+    let channels = conversion.channels();
+    let mut data = vec![T::default(); width * height * channels];
+    parallel::for_each_run(&mut data, width * channels, threads, |first, run| {
+        let mut rows = vec![vec![T::default(); width]; planes.len()];
+        for (y, out) in run.chunks_exact_mut(width * channels).enumerate() {
+            for (plane, row) in planes.iter().zip(rows.iter_mut()) {
+                plane.upsample_row(first + y, row);
+            }
+            conversion.convert_row(&rows, out, precision);
+        }
+    });
+    data
+}
@@ -0,0 +1,253 @@
+//! A small, low-memory parser for JPEG files.
+//!
+//! `parse_jpeg` walks the marker segments of an image (see `SegmentReader`) and
+//! returns the properties of its frame, every segment it found and any metadata
+//! carried in APPn and COM segments.
+
+mod adobe;
+mod arithmetic;
+mod bitreader;
+mod color;
+mod decoder;
+mod error;
+mod frame;
+mod huffman;
+mod idct;
+mod image;
+mod lossless;
+mod marker;
+mod output;
+mod parallel;
+mod progressive;
+mod quant;
+mod scan;
+mod segment;
+mod stream;
+mod upsample;
+
+use std::{fs::File, io::Read, path::Path};
+
+pub use adobe::{AdobeSegment, AdobeTransform};
+pub use arithmetic::ConditioningTable;
+pub use color::ColorSpace;
+pub use decoder::{
+    Coefficients, ComponentCoefficients, Damage, Decoder, Fault, Recovered, DEFAULT_MEMORY_LIMIT,
+};
+pub use error::{EntropyErrorKind, JpegError, Result};
+pub use frame::{ChromaSubsampling, Component, ImgProps};
+pub use huffman::{HuffmanCode, HuffmanTable, TableClass};
+pub use image::{Image, Image16, PixelFormat, Region, Scale};
+pub use marker::JpegMarker;
+pub use quant::{QuantTable, ZIGZAG};
+pub use scan::{Scan, ScanComponent, ScanHeader};
+pub use segment::{EntropyData, Segment, SegmentReader, READ_BUF_SIZE};
+pub use stream::Scanlines;
+
+/// Location of a marker segment within the file. The payload itself is not retained.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SegmentInfo {
+    pub marker: JpegMarker,
+    /// Offset of the 0xFF which introduced the marker
+    pub offset: usize,
+    /// Length of the payload (i.e excluding the marker and the two length bytes)
+    pub length: usize,
+}
+
+/// An APPn segment, identified by the NULL terminated string at the start of its payload (e.g "JFIF" or "Exif")
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AppSegment {
+    /// The byte following 0xFF, e.g 0xE0 for APP0
+    pub marker: u8,
+    pub identifier: String,
+    pub length: usize,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Metadata {
+    pub app_segments: Vec<AppSegment>,
+    /// The last Adobe APP14 segment, if any
+    pub adobe: Option<AdobeSegment>,
+    /// Contents of any COM segments
+    pub comments: Vec<String>,
+}
+
+/// Everything `parse_jpeg` learnt about an image
+#[derive(Debug, Clone)]
+pub struct JpegInfo {
+    /// Properties of the primary image
+    pub props: ImgProps,
+    /// Every SOFn segment encountered, along with the byte following its 0xFF (e.g 0xC0)
+    pub frames: Vec<(u8, ImgProps)>,
+    /// Every quantization table defined, in the order they were defined. Later tables may redefine earlier ones.
+    pub quant_tables: Vec<QuantTable>,
+    /// Every Huffman table defined, in the order they were defined. Later tables may redefine earlier ones.
+    pub huffman_tables: Vec<HuffmanTable>,
+    /// Every arithmetic coding conditioning table defined, in the order they were defined
+    pub conditioning_tables: Vec<ConditioningTable>,
+    /// Every scan, in the order they appear
+    pub scans: Vec<Scan>,
+    /// Number of MCUs in each restart interval, as defined by the last DRI segment (0 if restart markers are not used)
+    pub restart_interval: u16,
+    pub segments: Vec<SegmentInfo>,
+    pub metadata: Metadata,
+    /// Number of bytes between segments which did not belong to any marker
+    pub extraneous_bytes: usize,
+}
+
+impl JpegInfo {
+    /// The colour space of the primary image, inferred from its components along with
+    /// any JFIF and Adobe segments. None if it has 2 or more than 4 components.
+    pub fn color_space(&self) -> Option<ColorSpace> {
+        // Lossless processes, including the differential ones of hierarchical images
+        let lossless = self
+            .frames
+            .first()
+            .is_some_and(|(b, _)| matches!(b, 0xC3 | 0xC7 | 0xCB | 0xCF));
+        let jfif = self
+            .metadata
+            .app_segments
+            .iter()
+            .any(|app| app.marker == 0xE0 && app.identifier == "JFIF");
+        ColorSpace::detect(&self.props, lossless, jfif, self.metadata.adobe.as_ref())
+    }
+}
+
+/// Parse the JPEG file at `path`
+pub fn parse_file<P: AsRef<Path>>(path: P) -> Result<JpegInfo> {
+    let io_error = |source| JpegError::Io {
+        offset: 0,
+        marker: None,
+        source,
+    };
+    let file = File::open(path).map_err(io_error)?;
+    let stream_len = file.metadata().map_err(io_error)?.len() as usize;
+    parse_segments(SegmentReader::new(file).with_stream_len(stream_len))
+}
+
+/// Parse a JPEG image from `reader`, stopping at the End of Image marker
+pub fn parse_jpeg<R: Read>(reader: R) -> Result<JpegInfo> {
+    parse_segments(SegmentReader::new(reader))
+}
+
+/// Number of payload bytes `parse_jpeg` needs from each marker. Everything else is skipped.
+fn retained_payload(marker: JpegMarker) -> usize {
+    match marker {
+        // Only the identifier at the start of an APPn segment is of interest
+        JpegMarker::APP(_) => APP_IDENTIFIER_LEN,
+        JpegMarker::JPG | JpegMarker::JPGn(_) | JpegMarker::RES(_) => 0,
+        _ => usize::MAX,
+    }
+}
+
+/// Maximum number of bytes retained from the start of an APPn payload
+const APP_IDENTIFIER_LEN: usize = 64;
+
+/// Read the Start of Image marker which must begin the stream
+pub(crate) fn expect_start<R: Read>(segments: &mut SegmentReader<R>) -> Result<()> {
+    let start = segments.read_start().map_err(|source| JpegError::Io {
+        offset: 0,
+        marker: None,
+        source,
+    })?;
+    match start {
+        Some(JpegMarker::START) => Ok(()),
+        marker => Err(JpegError::NotJpeg { offset: 0, marker }),
+    }
+}
+
+fn parse_segments<R: Read>(segments: SegmentReader<R>) -> Result<JpegInfo> {
+    let mut segments = segments.with_retain(retained_payload);
+    expect_start(&mut segments)?;
+
+    let mut frames: Vec<(u8, ImgProps)> = Vec::new();
+    let mut segment_list = vec![SegmentInfo {
+        marker: JpegMarker::START,
+        offset: 0,
+        length: 0,
+    }];
+    let mut quant_tables = Vec::new();
+    let mut huffman_tables = Vec::new();
+    let mut conditioning_tables = Vec::new();
+    let mut scans = Vec::new();
+    let mut restart_interval = 0;
+    let mut metadata = Metadata::default();
+
+    let mut hierarchy = None;
+    let mut last_marker = None;
+    for segment in segments.by_ref() {
+        let segment = segment?;
+        last_marker = Some(segment.marker);
+        segment_list.push(SegmentInfo {
+            marker: segment.marker,
+            offset: segment.offset,
+            length: segment.length,
+        });
+        match segment.marker {
+            JpegMarker::APP(b) => {
+                if b == 0xEE {
+                    metadata.adobe = adobe::parse_adobe(&segment).or(metadata.adobe);
+                }
+                metadata.app_segments.push(AppSegment {
+                    marker: b,
+                    identifier: segment
+                        .payload
+                        .iter()
+                        .take_while(|b| **b != 0)
+                        .map(|b| char::from(*b))
+                        .collect(),
+                    length: segment.length,
+                })
+            }
+            JpegMarker::COM => metadata
+                .comments
+                .push(String::from_utf8_lossy(&segment.payload).into_owned()),
+            JpegMarker::DQT => quant_tables.extend(quant::parse_quant_tables(&segment)?),
+            JpegMarker::DHT => huffman_tables.extend(huffman::parse_huffman_tables(&segment)?),
+            JpegMarker::DAC => {
+                conditioning_tables.extend(arithmetic::parse_conditioning_tables(&segment)?)
+            }
+            JpegMarker::DRI => restart_interval = scan::parse_restart_interval(&segment)?,
+            JpegMarker::SOF(b) => frames.push((b, frame::parse_start_frame(&segment)?)),
+            // DHP shares its layout with SOFn, describing the image as a whole
+            JpegMarker::DHP => hierarchy = Some(frame::parse_start_frame(&segment)?),
+            JpegMarker::SOS if frames.is_empty() => {
+                return Err(JpegError::MissingFrame {
+                    offset: segment.offset,
+                    marker: Some(segment.marker),
+                })
+            }
+            JpegMarker::SOS => {
+                let header = scan::parse_scan_header(&segment)?;
+                if let Some(entropy) = segment.entropy {
+                    scans.push(Scan { header, entropy });
+                }
+            }
+            _ => continue,
+        }
+    }
+
+    // Hierarchical images describe their final size in the DHP segment, otherwise
+    // the primary image is described by the first (and only) frame
+    let props = match hierarchy.or_else(|| frames.first().map(|(_, props)| props.clone())) {
+        Some(props) => props,
+        None => {
+            return Err(JpegError::MissingFrame {
+                offset: segments.offset(),
+                marker: last_marker,
+            })
+        }
+    };
+
+    Ok(JpegInfo {
+        props,
+        frames,
+        quant_tables,
+        huffman_tables,
+        conditioning_tables,
+        scans,
+        restart_interval,
+        segments: segment_list,
+        metadata,
+        extraneous_bytes: segments.extraneous_bytes(),
+    })
+}
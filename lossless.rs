@@ -0,0 +1,66 @@
+use crate::{bitreader::BitReader, error::EntropyErrorKind, huffman::HuffmanDecoder};
+
+/// Reconstructed samples of a single component of a lossless image
+#[derive(Debug, Clone)]
+pub(crate) struct SamplePlane {
+    /// Samples in raster order, including any padding needed to complete the final MCU
+    pub(crate) samples: Vec<u16>,
+    pub(crate) stride: usize,
+}
+
+impl SamplePlane {
+    pub(crate) fn new(stride: usize, rows: usize) -> SamplePlane {
+        SamplePlane {
+            samples: vec![0; stride * rows],
+            stride,
+        }
+    }
+
+    /// Prediction (Px) for the sample at (`x`, `y`), following ITU T.81 H.1.2.1.
+    /// `top` is the first row of the current restart interval, which can only be predicted from
+    /// the sample to its left, and `initial` is the prediction for the first sample of that row.
+    pub(crate) fn predict(
+        &self,
+        x: usize,
+        y: usize,
+        top: usize,
+        predictor: u8,
+        initial: i32,
+    ) -> i32 {
+        let at = |x: usize, y: usize| self.samples[y * self.stride + x] as i32;
+        match (x, y == top) {
+            (0, true) => initial,
+            (_, true) => at(x - 1, y),
+            (0, false) => at(0, y - 1),
+            _ => predict(predictor, at(x - 1, y), at(x, y - 1), at(x - 1, y - 1)),
+        }
+    }
+}
+
+/// Combine the neighbouring samples Ra (left), Rb (above) and Rc (above left) as
+/// selected by the predictor of a scan (ITU T.81 Table H.1)
+fn predict(predictor: u8, a: i32, b: i32, c: i32) -> i32 {
+    match predictor {
+        1 => a,
+        2 => b,
+        3 => c,
+        4 => a + b - c,
+        5 => a + ((b - c) >> 1),
+        6 => b + ((a - c) >> 1),
+        _ => (a + b) >> 1,
+    }
+}
+
+/// Decode the difference between a sample and its prediction (ITU T.81 H.1.2.2).
+/// Unlike DCT coefficients, a difference may be 16 bits long, in which case it is always 32768
+/// and no further bits follow.
+pub(crate) fn decode_difference(
+    reader: &mut BitReader,
+    table: &HuffmanDecoder,
+) -> Result<i32, EntropyErrorKind> {
+    match table.decode(reader).ok_or(EntropyErrorKind::InvalidCode)? {
+        16 => Ok(32768),
+        size @ 0..=15 => Ok(reader.receive_extend(size as u32)),
+        _ => Err(EntropyErrorKind::InvalidCode),
+    }
+}
@@ -1,10 +1,14 @@
-use clap::{self, Parser};
+use clap::{self, Parser, ValueEnum};
+use jpeg_parser::{
+    ConditioningTable, Decoder, HuffmanTable, Image, JpegInfo, JpegMarker, QuantTable, Region,
+    Scale, Scan, TableClass,
+};
 use std::{
-    fs::File,
-    io::{self, BufReader, Error, Read},
-    path::PathBuf,
+    error::Error,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
     process::exit,
-    str,
 };
 
 #[derive(Parser)]
@@ -17,50 +21,81 @@ struct Args {
     /// Display verbose output (e.g specific marker types)
     #[clap(short)]
     verbose: bool,
-}
 
-#[derive(Debug, Copy, Clone)]
-struct ImgProps {
-    width: usize,
-    height: usize,
-    bit_depth: usize,
-    components: usize,
-}
+    /// Decode the entropy-coded data of each file and report where it first fails, if anywhere.
+    /// Exits with 0 if every file decodes, 2 if the entropy-coded data of any is corrupt,
+    /// or 3 if any could not be read at all (e.g a malformed segment).
+    #[clap(short, long, conflicts_with = "decode")]
+    check: bool,
 
-#[derive(PartialEq, Eq, Debug)]
-enum JpegMarker {
-    /// Start of Image (0xD8)
-    START,
+    /// Decode the image and write its pixels to OUTPUT ("-" for standard output) rather than describing it
+    #[clap(short, long, value_name = "OUTPUT")]
+    decode: Option<PathBuf>,
 
-    /// Indicative of a potential marker. E.g [0xFF, 0xC0] -> [JpegMarker::INDICATOR, JpegMarker::SOF(0xC0)]
-    INDICATOR,
+    /// Format of the decoded image. By default this is guessed from the extension of OUTPUT,
+    /// or raw samples when writing to standard output.
+    #[clap(short, long, value_enum, requires = "decode")]
+    format: Option<OutputFormat>,
 
-    /// Application data. Contains the type of byte (e.g 0xE0 or 0xE1)
-    APP(u8),
+    /// Decode at a fraction of the full size: 1/2, 1/4 or 1/8 (or just the denominator)
+    #[clap(short, long, value_name = "SCALE", value_parser = parse_scale, requires = "decode")]
+    scale: Option<Scale>,
 
-    /// Start of Frame (0xC0 -> 0xC2). Contains the type of byte (e.g 0xC0)
-    SOF(u8),
+    /// Decode only the rectangle at X,Y of WIDTH x HEIGHT pixels (after any scaling)
+    #[clap(short, long, value_name = "X,Y,WIDTH,HEIGHT", value_parser = parse_region, requires = "decode")]
+    region: Option<Region>,
 
-    /// End of Image (0xD9)
-    END,
+    /// Number of threads to decode with, 0 for one per CPU
+    #[clap(
+        short = 'j',
+        long,
+        value_name = "THREADS",
+        default_value_t = 1,
+        requires = "decode"
+    )]
+    threads: usize,
 
-    /// Not a Marker, contains the byte
-    None(u8),
+    /// Conceal corrupt or missing data rather than failing, listing the MCUs lost on standard error
+    #[clap(long, requires = "decode")]
+    recover: bool,
 }
 
-impl JpegMarker {
-    fn from_u8(marker: u8) -> JpegMarker {
-        match marker {
-            0xFF => JpegMarker::INDICATOR,
-            0xD8 => JpegMarker::START,
-            0xD9 => JpegMarker::END,
-            0xE0 | 0xE1 | 0xE2 => JpegMarker::APP(marker),
-            0xC0..=0xC2 => JpegMarker::SOF(marker),
-            _ => JpegMarker::None(marker),
-        }
+fn parse_region(value: &str) -> Result<Region, String> {
+    let values: Vec<usize> = value
+        .split(',')
+        .map(|v| v.trim().parse())
+        .collect::<Result<_, _>>()
+        .map_err(|e| format!("{}", e))?;
+    match values[..] {
+        [x, y, width, height] => Ok(Region {
+            x,
+            y,
+            width,
+            height,
+        }),
+        _ => Err("expected X,Y,WIDTH,HEIGHT".to_string()),
     }
 }
 
+fn parse_scale(value: &str) -> Result<Scale, String> {
+    let denominator = value.strip_prefix("1/").unwrap_or(value);
+    denominator
+        .parse()
+        .ok()
+        .and_then(Scale::from_denominator)
+        .ok_or_else(|| "expected 1/2, 1/4 or 1/8".to_string())
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    /// Binary PGM (greyscale) or PPM (RGB)
+    Pnm,
+    Bmp,
+    Png,
+    /// Interleaved samples without a header, big endian if wider than 8 bits
+    Raw,
+}
+
 fn main() -> io::Result<()> {
     let args = Args::parse();
     if args.file.is_none() {
@@ -73,12 +108,44 @@ fn main() -> io::Result<()> {
     }
 
     let filenames = args.file.unwrap();
+    if let Some(output) = &args.decode {
+        let [filename] = &filenames[..] else {
+            eprintln!("Please provide a single JPEG image to decode!");
+            exit(1);
+        };
+        if let Err(e) = decode(
+            filename,
+            output,
+            args.format,
+            args.scale.unwrap_or_default(),
+            args.region,
+            args.threads,
+            args.recover,
+        ) {
+            eprintln!("Error: {} {}", filename.display(), e);
+            exit(1);
+        }
+        return Ok(());
+    }
+    if args.check {
+        let status = filenames
+            .iter()
+            .map(|filename| check(filename))
+            .max()
+            .unwrap_or(CHECK_OK);
+        exit(status);
+    }
     if args.verbose {
         println!("Attempting to parse {} file(s).", filenames.len());
     }
     for filename in &filenames {
-        if let Err(e) = parse_jpeg(filename.to_str().unwrap_or(""), args.verbose) {
-            println!("Error: {}", Error::to_string(&e));
+        let filename = filename.to_str().unwrap_or("");
+        if args.verbose {
+            println!("Parsing file {}", filename);
+        }
+        match jpeg_parser::parse_file(filename) {
+            Ok(info) => print_info(filename, &info, args.verbose),
+            Err(e) => println!("Error: {} {}", filename, e),
         }
     }
 
@@ -88,118 +155,358 @@ fn main() -> io::Result<()> {
     Ok(())
 }
 
-fn parse_jpeg(filename: &str, verbose: bool) -> Result<(), io::Error> {
-    if verbose {
-        println!("Parsing file {}", filename);
-    }
-
-    let file = File::open(filename)?;
-    let mut breader = BufReader::new(file);
-    let mut buf = [0u8; 8192];
-    let mut is_first_read = true;
-
-    let mut sof_segments: Vec<(u8, ImgProps)> = Vec::new();
-    let mut skip_bytes = 0usize;
-    let mut ident: &str = "";
-
-    while let Ok(amnt) = breader.read(&mut buf) {
-        if amnt == 0 {
-            break;
-        } else if is_first_read && amnt > 1 {
-            if JpegMarker::from_u8(buf[1]) != JpegMarker::START {
-                return Err(Error::new(
-                    io::ErrorKind::InvalidData,
-                    filename.to_owned() + " is not a valid JPEG image!",
-                ));
-            }
-            is_first_read = false;
+/// Exit statuses of `--check`, which exits with the worst found across every file
+const CHECK_OK: i32 = 0;
+const CHECK_CORRUPT: i32 = 2;
+const CHECK_UNREADABLE: i32 = 3;
+
+/// Walk the entropy-coded data of `filename`, printing where it first fails. Returns the exit status.
+fn check(filename: &Path) -> i32 {
+    let data = match fs::read(filename) {
+        Ok(data) => data,
+        Err(e) => {
+            println!("{}: error: {}", filename.display(), e);
+            return CHECK_UNREADABLE;
+        }
+    };
+    match Decoder::new(&data).check() {
+        Ok(None) => {
+            println!("{}: ok", filename.display());
+            CHECK_OK
+        }
+        Ok(Some(fault)) => {
+            let component = match fault.component {
+                Some(id) => format!("component {}", id),
+                None => "restart marker".to_string(),
+            };
+            println!(
+                "{}: corrupt at offset {} (scan {}, MCU row {} column {}, {}): {}",
+                filename.display(),
+                fault.offset,
+                fault.scan,
+                fault.mcu_row,
+                fault.mcu_column,
+                component,
+                fault.kind
+            );
+            CHECK_CORRUPT
+        }
+        Err(e) => {
+            println!("{}: error: {}", filename.display(), e);
+            CHECK_UNREADABLE
         }
+    }
+}
 
-        // Indice count
-        let buf_len = buf.len() - 1;
-        for idx in 0..buf_len {
-            if skip_bytes > 0 {
-                skip_bytes -= 1;
-                continue;
+/// Decode `filename` (or just `region` of it), scaled down by `scale` on `threads` threads, and write its pixels to `output`.
+/// With `recover` set, damaged data is concealed and reported rather than failing the decode. CMYK images are converted to RGB,
+/// and images with more than 8 bits per sample keep them where the format allows.
+fn decode(
+    filename: &Path,
+    output: &Path,
+    format: Option<OutputFormat>,
+    scale: Scale,
+    region: Option<Region>,
+    threads: usize,
+    recover: bool,
+) -> Result<(), Box<dyn Error>> {
+    let to_stdout = output == Path::new("-");
+    let format = match format {
+        Some(format) => format,
+        None if to_stdout => OutputFormat::Raw,
+        None => match output.extension().and_then(|ext| ext.to_str()) {
+            Some("ppm" | "pgm" | "pnm") => OutputFormat::Pnm,
+            Some("bmp") => OutputFormat::Bmp,
+            Some("png") => OutputFormat::Png,
+            Some("raw" | "rgb") => OutputFormat::Raw,
+            _ => {
+                return Err("cannot tell the output format from its extension, use --format".into())
             }
+        },
+    };
 
-            let byte = JpegMarker::from_u8(buf[idx]);
-            // 0xFF 0x00 is byte stuffing.
-            if byte == JpegMarker::INDICATOR {
-                match JpegMarker::from_u8(buf[(idx + 1).min(buf_len)]) {
-                    JpegMarker::APP(b) => {
-                        let size: usize =
-                            (buf[(idx + 2).min(buf_len)] + buf[(idx + 3).min(buf_len)] - 2).into();
-                        ident = match b {
-                            0xE0 => "JFIF",
-                            0xE1 => "EXIF",
-                            _ => ident,
-                        };
-                        if verbose {
-                            println!("APP Marker - 0x{:X}\nSize of APP Section (excluding initial 0xFF 0x{:X}): {} bytes", b, b, size);
-                            print!("NULL Terminated String: ");
-                            let mut idx = idx + 4;
-                            while buf[idx] != 0 {
-                                print!("{}", char::from_u32(buf[idx] as u32).unwrap());
-                                idx += 1;
-                                skip_bytes += 1;
-                            }
-                            println!("")
-                        }
-                    }
-                    JpegMarker::SOF(b) => {
-                        let size: usize =
-                            (buf[(idx + 2).min(buf_len)] + buf[(idx + 3).min(buf_len)] - 2).into();
+    let data = fs::read(filename)?;
+    let decoder = Decoder::new(&data)
+        .with_cmyk_to_rgb(true)
+        .with_scale(scale)
+        .with_threads(threads);
+    let decoder = match region {
+        Some(region) => decoder.with_region(region),
+        None => decoder,
+    };
+    let (image, damage, stopped) = if recover {
+        let recovered = decoder.recover_16()?;
+        (recovered.image, recovered.damage, recovered.stopped)
+    } else {
+        (decoder.decode_16()?, Vec::new(), None)
+    };
+    let image = Image::try_from(image);
+    if image.is_err() && matches!(format, OutputFormat::Bmp) {
+        return Err("BMP output needs 8 bit samples".into());
+    }
 
-                        let mut start_frame = Vec::new();
-                        start_frame.extend(buf[idx + 4..(idx + 4 + size)].iter().cloned());
+    // Only create the output once the image has decoded
+    let mut writer: Box<dyn Write> = if to_stdout {
+        Box::new(io::stdout().lock())
+    } else {
+        Box::new(BufWriter::new(File::create(output)?))
+    };
+    match image {
+        Ok(image) => match format {
+            OutputFormat::Pnm => image.write_pnm(&mut writer)?,
+            OutputFormat::Bmp => image.write_bmp(&mut writer)?,
+            OutputFormat::Png => image.write_png(&mut writer)?,
+            OutputFormat::Raw => writer.write_all(&image.data)?,
+        },
+        Err(image) => match format {
+            OutputFormat::Pnm => image.write_pnm(&mut writer)?,
+            OutputFormat::Bmp => unreachable!("BMP output of 16 bit samples is refused above"),
+            OutputFormat::Png => image.write_png(&mut writer)?,
+            OutputFormat::Raw => image.write_raw(&mut writer)?,
+        },
+    }
+    writer.flush()?;
 
-                        let parsed_frame = parse_start_frame(start_frame);
-                        sof_segments.push((b, parsed_frame.clone()));
+    for damage in &damage {
+        let (first, last) = (damage.mcus.start, damage.mcus.end - 1);
+        let per_line = damage.mcus_per_line;
+        eprintln!(
+            "Concealed: scan {} MCUs {}-{} (row {} column {} to row {} column {}), {} at offset {}",
+            damage.scan,
+            first,
+            last,
+            first / per_line,
+            first % per_line,
+            last / per_line,
+            last % per_line,
+            damage.kind,
+            damage.offset
+        );
+    }
+    if let Some(e) = stopped {
+        eprintln!("Stopped early: {}", e);
+    }
+    Ok(())
+}
 
-                        if verbose {
-                            println!("SOF Marker - 0x{:X}\nSize of SOF Section (excluding initial 0xFF 0x{:X}): {} bytes. Frame was {:?}", b, b, size, parsed_frame)
+fn print_info(filename: &str, info: &JpegInfo, verbose: bool) {
+    if verbose {
+        let mut app_segments = info.metadata.app_segments.iter();
+        let mut frames = info.frames.iter();
+        let mut quant_tables = info.quant_tables.iter();
+        let mut huffman_tables = info.huffman_tables.iter();
+        let mut conditioning_tables = info.conditioning_tables.iter();
+        let mut scans = info.scans.iter();
+        for segment in &info.segments {
+            match segment.marker {
+                JpegMarker::APP(b) => {
+                    println!("APP Marker - 0x{:X}\nSize of APP Section (excluding initial 0xFF 0x{:X}): {} bytes", b, b, segment.length);
+                    if let Some(app) = app_segments.next() {
+                        println!("NULL Terminated String: {}", app.identifier);
+                    }
+                    if let (0xEE, Some(adobe)) = (b, &info.metadata.adobe) {
+                        println!(
+                            "Adobe Version {}, Flags 0x{:04X} 0x{:04X}, Transform {}",
+                            adobe.version, adobe.flags0, adobe.flags1, adobe.transform
+                        );
+                    }
+                }
+                JpegMarker::SOF(b) => {
+                    if let Some((_, frame)) = frames.next() {
+                        println!("SOF Marker - 0x{:X}\nSize of SOF Section (excluding initial 0xFF 0x{:X}): {} bytes. Frame was {}x{}, Bit Depth {}", b, b, segment.length, frame.width, frame.height, frame.bit_depth);
+                        for component in &frame.components {
+                            println!(
+                                "Component {} - Sampling {}x{}, Quantization Table {}",
+                                component.id,
+                                component.horizontal_sampling,
+                                component.vertical_sampling,
+                                component.quant_table
+                            );
                         }
+                        let (width, height) = if matches!(b, 0xC3 | 0xC7 | 0xCB | 0xCF) {
+                            frame.lossless_mcu_size()
+                        } else {
+                            (frame.mcu_width(), frame.mcu_height())
+                        };
+                        println!(
+                            "MCU Size {}x{} ({}x{} MCUs)",
+                            width,
+                            height,
+                            frame.width.div_ceil(width),
+                            frame.height.div_ceil(height)
+                        );
+                    }
+                }
+                JpegMarker::DQT => {
+                    println!(
+                        "DQT Marker - 0x{:X} at offset {} ({} bytes)",
+                        JpegMarker::DQT.to_u8(),
+                        segment.offset,
+                        segment.length
+                    );
+                    // A DQT segment holds as many tables as fit in its payload
+                    let mut remaining = segment.length;
+                    while remaining > 0 {
+                        let Some(table) = quant_tables.next() else {
+                            break;
+                        };
+                        remaining = remaining.saturating_sub(1 + table.precision as usize * 8);
+                        print_quant_table(table);
+                    }
+                }
+                JpegMarker::DHT => {
+                    println!(
+                        "DHT Marker - 0x{:X} at offset {} ({} bytes)",
+                        JpegMarker::DHT.to_u8(),
+                        segment.offset,
+                        segment.length
+                    );
+                    let mut remaining = segment.length;
+                    while remaining > 0 {
+                        let Some(table) = huffman_tables.next() else {
+                            break;
+                        };
+                        remaining = remaining.saturating_sub(17 + table.symbols.len());
+                        print_huffman_table(table);
+                    }
+                }
+                JpegMarker::DAC => {
+                    println!(
+                        "DAC Marker - 0x{:X} at offset {} ({} bytes)",
+                        JpegMarker::DAC.to_u8(),
+                        segment.offset,
+                        segment.length
+                    );
+                    // Each conditioning table takes 2 bytes
+                    for table in conditioning_tables.by_ref().take(segment.length / 2) {
+                        print_conditioning_table(table);
+                    }
+                }
+                JpegMarker::SOS => {
+                    println!(
+                        "SOS Marker - 0x{:X} at offset {} ({} bytes)",
+                        JpegMarker::SOS.to_u8(),
+                        segment.offset,
+                        segment.length
+                    );
+                    if let Some(scan) = scans.next() {
+                        print_scan(scan);
                     }
-                    _ => continue,
                 }
+                marker if marker.has_length() => {
+                    println!(
+                        "{:?} Marker - 0x{:X} at offset {} ({} bytes)",
+                        marker,
+                        marker.to_u8(),
+                        segment.offset,
+                        segment.length
+                    )
+                }
+                _ => continue,
             }
         }
+        if info.extraneous_bytes > 0 {
+            println!(
+                "Skipped {} extraneous bytes between segments",
+                info.extraneous_bytes
+            );
+        }
     }
 
-    print!("File ({}) ", filename);
-    print!("[{}] ", ident);
-    sof_segments.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
-    let parsed_frame = &sof_segments.last().unwrap().1;
+    // The last APP0 (JFIF) or APP1 (EXIF) segment wins
+    let ident = info
+        .metadata
+        .app_segments
+        .iter()
+        .rev()
+        .find_map(|app| match app.marker {
+            0xE0 => Some("JFIF"),
+            0xE1 => Some("EXIF"),
+            _ => None,
+        })
+        .unwrap_or("");
+
+    let props = &info.props;
     print!(
-        "{}x{} Bit Depth {}, Components {}",
-        parsed_frame.width, parsed_frame.height, parsed_frame.bit_depth, parsed_frame.components
+        "File ({}) [{}] {}x{}",
+        filename, ident, props.width, props.height
     );
-    println!("");
+    if let Some(color_space) = info.color_space() {
+        print!(" {},", color_space);
+    }
+    print!(
+        " Bit Depth {}, Components {}",
+        props.bit_depth,
+        props.components.len()
+    );
+    if let Some(subsampling) = props.subsampling() {
+        print!(", Subsampling {}", subsampling);
+    }
+    if info.scans.len() > 1 {
+        print!(", Scans {}", info.scans.len());
+    }
+    println!();
+}
 
-    Ok(())
+fn print_quant_table(table: &QuantTable) {
+    println!(
+        "Quantization Table {} ({}-bit precision)",
+        table.id, table.precision
+    );
+    for row in table.natural().chunks_exact(8) {
+        let row: Vec<String> = row.iter().map(|v| format!("{:>5}", v)).collect();
+        println!("{}", row.join(""));
+    }
 }
 
-fn parse_start_frame(frame: Vec<u8>) -> ImgProps {
-    let mut frame = frame.into_iter();
-    // Skip the first byte
-    let bit_depth = frame.next().unwrap() as usize;
+fn print_huffman_table(table: &HuffmanTable) {
+    println!(
+        "Huffman Table {:?} {} ({} symbols)",
+        table.class,
+        table.id,
+        table.symbols.len()
+    );
+    let mut codes = table.codes.iter();
+    for (idx, count) in table.counts.iter().enumerate() {
+        if *count == 0 {
+            continue;
+        }
+        let symbols: Vec<String> = codes
+            .by_ref()
+            .take(*count as usize)
+            .map(|c| format!("{:0width$b}={:02X}", c.code, c.symbol, width = idx + 1))
+            .collect();
+        println!("Length {:>2}: {}", idx + 1, symbols.join(" "));
+    }
+}
 
-    let mut conv = || -> usize {
-        vec![frame.next().unwrap(), frame.next().unwrap()]
-            .iter()
-            .fold(0, |acc, v| {
-                if acc == 0 {
-                    (*v as usize) << 8
-                } else {
-                    acc + *v as usize
-                }
-            })
-    };
-    ImgProps {
-        bit_depth,
-        height: conv(),
-        width: conv(),
-        components: frame.next().unwrap() as usize,
+fn print_conditioning_table(table: &ConditioningTable) {
+    match table.class {
+        TableClass::DC => {
+            let (lower, upper) = table.dc_bounds();
+            println!(
+                "Conditioning Table DC {} - L {}, U {}",
+                table.id, lower, upper
+            );
+        }
+        TableClass::AC => println!("Conditioning Table AC {} - Kx {}", table.id, table.value),
     }
 }
+
+fn print_scan(scan: &Scan) {
+    let header = &scan.header;
+    for component in &header.components {
+        println!(
+            "Scan Component {} - DC Table {}, AC Table {}",
+            component.selector, component.dc_table, component.ac_table
+        );
+    }
+    println!(
+        "Spectral Selection {}..={}, Successive Approximation {}/{}",
+        header.spectral_start, header.spectral_end, header.approx_high, header.approx_low
+    );
+    println!(
+        "Entropy-coded data at offset {} ({} bytes, {} restart markers)",
+        scan.entropy.offset, scan.entropy.length, scan.entropy.restart_markers
+    );
+}
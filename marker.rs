@@ -0,0 +1,150 @@
+use std::fmt;
+
+/// Every marker defined by ITU T.81 (Table B.1), keyed by the byte that follows the 0xFF prefix.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum JpegMarker {
+    /// Start of Image (0xD8)
+    START,
+
+    /// Indicative of a potential marker. E.g [0xFF, 0xC0] -> [JpegMarker::INDICATOR, JpegMarker::SOF(0xC0)]
+    INDICATOR,
+
+    /// Application data (0xE0 -> 0xEF). Contains the type of byte (e.g 0xE0 or 0xE1)
+    APP(u8),
+
+    /// Start of Frame (0xC0 -> 0xCF, excluding 0xC4, 0xC8 and 0xCC). Contains the type of byte (e.g 0xC0)
+    SOF(u8),
+
+    /// Define Huffman Table(s) (0xC4)
+    DHT,
+
+    /// Reserved for JPEG extensions (0xC8)
+    JPG,
+
+    /// Define Arithmetic Coding conditioning(s) (0xCC)
+    DAC,
+
+    /// Restart with modulo 8 count (0xD0 -> 0xD7). Contains the type of byte (e.g 0xD0)
+    RST(u8),
+
+    /// End of Image (0xD9)
+    END,
+
+    /// Start of Scan (0xDA)
+    SOS,
+
+    /// Define Quantization Table(s) (0xDB)
+    DQT,
+
+    /// Define Number of Lines (0xDC)
+    DNL,
+
+    /// Define Restart Interval (0xDD)
+    DRI,
+
+    /// Define Hierarchical Progression (0xDE)
+    DHP,
+
+    /// Expand Reference Component(s) (0xDF)
+    EXP,
+
+    /// Reserved for JPEG extensions (0xF0 -> 0xFD). Contains the type of byte (e.g 0xF7)
+    JPGn(u8),
+
+    /// Comment (0xFE)
+    COM,
+
+    /// For temporary private use in arithmetic coding (0x01)
+    TEM,
+
+    /// Reserved (0x02 -> 0xBF). Contains the type of byte
+    RES(u8),
+
+    /// Not a Marker, contains the byte (e.g the 0x00 of a stuffed 0xFF 0x00)
+    None(u8),
+}
+
+impl JpegMarker {
+    pub fn from_u8(marker: u8) -> JpegMarker {
+        match marker {
+            0xFF => JpegMarker::INDICATOR,
+            0xD8 => JpegMarker::START,
+            0xD9 => JpegMarker::END,
+            0xE0..=0xEF => JpegMarker::APP(marker),
+            0xC4 => JpegMarker::DHT,
+            0xC8 => JpegMarker::JPG,
+            0xCC => JpegMarker::DAC,
+            0xC0..=0xCF => JpegMarker::SOF(marker),
+            0xD0..=0xD7 => JpegMarker::RST(marker),
+            0xDA => JpegMarker::SOS,
+            0xDB => JpegMarker::DQT,
+            0xDC => JpegMarker::DNL,
+            0xDD => JpegMarker::DRI,
+            0xDE => JpegMarker::DHP,
+            0xDF => JpegMarker::EXP,
+            0xF0..=0xFD => JpegMarker::JPGn(marker),
+            0xFE => JpegMarker::COM,
+            0x01 => JpegMarker::TEM,
+            0x02..=0xBF => JpegMarker::RES(marker),
+            _ => JpegMarker::None(marker),
+        }
+    }
+
+    /// The byte following 0xFF which encodes this marker
+    pub fn to_u8(self) -> u8 {
+        match self {
+            JpegMarker::INDICATOR => 0xFF,
+            JpegMarker::START => 0xD8,
+            JpegMarker::END => 0xD9,
+            JpegMarker::DHT => 0xC4,
+            JpegMarker::JPG => 0xC8,
+            JpegMarker::DAC => 0xCC,
+            JpegMarker::SOS => 0xDA,
+            JpegMarker::DQT => 0xDB,
+            JpegMarker::DNL => 0xDC,
+            JpegMarker::DRI => 0xDD,
+            JpegMarker::DHP => 0xDE,
+            JpegMarker::EXP => 0xDF,
+            JpegMarker::COM => 0xFE,
+            JpegMarker::TEM => 0x01,
+            JpegMarker::APP(b)
+            | JpegMarker::SOF(b)
+            | JpegMarker::RST(b)
+            | JpegMarker::JPGn(b)
+            | JpegMarker::RES(b)
+            | JpegMarker::None(b) => b,
+        }
+    }
+
+    /// Standalone markers (SOI, EOI, RSTn and TEM) are not followed by a length or payload
+    pub fn is_standalone(self) -> bool {
+        matches!(
+            self,
+            JpegMarker::START | JpegMarker::END | JpegMarker::RST(_) | JpegMarker::TEM
+        )
+    }
+
+    /// Whether the marker is followed by a big-endian 16-bit length (which includes itself)
+    pub fn has_length(self) -> bool {
+        !self.is_standalone() && !matches!(self, JpegMarker::INDICATOR | JpegMarker::None(_))
+    }
+}
+
+impl fmt::Display for JpegMarker {
+    /// Formats the marker using its ITU T.81 mnemonic, e.g SOF2 or APP14
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            JpegMarker::START => write!(f, "SOI"),
+            JpegMarker::END => write!(f, "EOI"),
+            JpegMarker::INDICATOR => write!(f, "FILL"),
+            JpegMarker::APP(b) => write!(f, "APP{}", b - 0xE0),
+            JpegMarker::SOF(b) => write!(f, "SOF{}", b - 0xC0),
+            JpegMarker::RST(b) => write!(f, "RST{}", b - 0xD0),
+            JpegMarker::JPGn(b) => write!(f, "JPG{}", b - 0xF0),
+            JpegMarker::RES(b) => write!(f, "RES(0x{:X})", b),
+            JpegMarker::None(b) => write!(f, "0x{:X}", b),
+            marker => write!(f, "{:?}", marker),
+        }
+    }
+}
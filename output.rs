@@ -0,0 +1,527 @@
+use std::io::{self, Write};
+
+use crate::image::{Image, Image16, PixelFormat};
+
+fn unsupported(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, reason)
+}
+
+/// Check that an image of `width` x `height` pixels laid out as `format` holds `len` samples.
+/// None of the formats written can hold an image without any pixels.
+fn check_dimensions(
+    width: usize,
+    height: usize,
+    format: PixelFormat,
+    len: usize,
+) -> io::Result<()> {
+    if width == 0 || height == 0 {
+        return Err(unsupported(
+            "images must be at least one pixel wide and high",
+        ));
+    }
+    match width
+        .checked_mul(height)
+        .and_then(|pixels| pixels.checked_mul(format.channels()))
+    {
+        Some(samples) if samples == len => Ok(()),
+        _ => Err(unsupported("image data does not match its dimensions")),
+    }
+}
+
+/// Magic number of the binary PNM variant holding `format`
+fn pnm_magic(format: PixelFormat) -> io::Result<&'static str> {
+    match format.channels() {
+        1 => Ok("P5"),
+        3 => Ok("P6"),
+        _ => Err(unsupported("PNM images must be greyscale or RGB")),
+    }
+}
+
+/// PNG colour type of `format`
+fn png_color_type(format: PixelFormat) -> io::Result<u8> {
+    match format.channels() {
+        1 => Ok(0),
+        3 => Ok(2),
+        _ => Err(unsupported("PNG images must be greyscale or RGB")),
+    }
+}
+
+impl Image {
+    /// Write the image as a binary PGM (greyscale) or PPM (RGB) file
+    pub fn write_pnm<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        let magic = pnm_magic(self.format)?;
+        self.check_dimensions()?;
+        write!(writer, "{}\n{} {}\n255\n", magic, self.width, self.height)?;
+        writer.write_all(&self.data)
+    }
+
+    /// Write the image as an uncompressed BMP file, with 24 bit pixels for RGB images and
+    /// a greyscale palette for single component images
+    pub fn write_bmp<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        let channels = self.format.channels();
+        if !matches!(channels, 1 | 3) {
+            return Err(unsupported("BMP images must be greyscale or RGB"));
+        }
+        self.check_dimensions()?;
+        let palette_len = if channels == 1 { 256 * 4 } else { 0 };
+        // Rows are padded to a multiple of 4 bytes
+        let row_len = (self.width * channels).next_multiple_of(4);
+        let offset = 14 + 40 + palette_len;
+        let size = offset + row_len * self.height;
+        let to_u32 = |value: usize| {
+            u32::try_from(value).map_err(|_| unsupported("image is too large for a BMP file"))
+        };
+
+        // BITMAPFILEHEADER
+        writer.write_all(b"BM")?;
+        writer.write_all(&to_u32(size)?.to_le_bytes())?;
+        writer.write_all(&[0; 4])?;
+        writer.write_all(&to_u32(offset)?.to_le_bytes())?;
+        // BITMAPINFOHEADER. A positive height stores rows bottom up.
+        writer.write_all(&40u32.to_le_bytes())?;
+        writer.write_all(&to_u32(self.width)?.to_le_bytes())?;
+        writer.write_all(&to_u32(self.height)?.to_le_bytes())?;
+        writer.write_all(&1u16.to_le_bytes())?;
+        writer.write_all(&(channels as u16 * 8).to_le_bytes())?;
+        writer.write_all(&[0; 4])?; // BI_RGB, i.e no compression
+        writer.write_all(&to_u32(row_len * self.height)?.to_le_bytes())?;
+        writer.write_all(&2835u32.to_le_bytes())?; // 72 DPI
+        writer.write_all(&2835u32.to_le_bytes())?;
+        let colors: u32 = if channels == 1 { 256 } else { 0 };
+        writer.write_all(&colors.to_le_bytes())?;
+        writer.write_all(&[0; 4])?;
+        for i in 0..colors {
+            writer.write_all(&[i as u8, i as u8, i as u8, 0])?;
+        }
+
+        let mut row = vec![0u8; row_len];
+        for pixels in self.data.chunks_exact(self.width * channels).rev() {
+            if channels == 1 {
+                row[..pixels.len()].copy_from_slice(pixels);
+            } else {
+                for (out, pixel) in row.chunks_exact_mut(3).zip(pixels.chunks_exact(3)) {
+                    out.copy_from_slice(&[pixel[2], pixel[1], pixel[0]]);
+                }
+            }
+            writer.write_all(&row)?;
+        }
+        Ok(())
+    }
+
+    /// Write the image as a PNG file. The pixels are stored without compression.
+    pub fn write_png<W: Write>(&self, writer: W) -> io::Result<()> {
+        let color_type = png_color_type(self.format)?;
+        self.check_dimensions()?;
+        write_png(
+            writer,
+            (self.width, self.height),
+            (8, color_type),
+            None,
+            self.data.chunks_exact(self.width * self.format.channels()),
+        )
+    }
+
+    fn check_dimensions(&self) -> io::Result<()> {
+        check_dimensions(self.width, self.height, self.format, self.data.len())
+    }
+}
+
+impl Image16 {
+    /// Write the image as a binary PGM (greyscale) or PPM (RGB) file, with a maximum value
+    /// matching its bit depth. Samples wider than 8 bits are stored big endian.
+    pub fn write_pnm<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        let magic = pnm_magic(self.format)?;
+        self.check_dimensions()?;
+        let max = (1u32 << self.bit_depth) - 1;
+        write!(
+            writer,
+            "{}\n{} {}\n{}\n",
+            magic, self.width, self.height, max
+        )?;
+        if max < 256 {
+            let data: Vec<u8> = self.data.iter().map(|sample| *sample as u8).collect();
+            return writer.write_all(&data);
+        }
+        self.write_raw(writer)
+    }
+
+    /// Write the image as a PNG file with 16 bit samples, each shifted left from its bit depth
+    /// with an sBIT chunk recording that depth. The pixels are stored without compression.
+    pub fn write_png<W: Write>(&self, writer: W) -> io::Result<()> {
+        let color_type = png_color_type(self.format)?;
+        self.check_dimensions()?;
+        let shift = 16 - self.bit_depth;
+        let rows = self
+            .data
+            .chunks_exact(self.width * self.format.channels())
+            .map(|row| {
+                row.iter()
+                    .flat_map(|sample| (sample << shift).to_be_bytes())
+                    .collect::<Vec<u8>>()
+            });
+        write_png(
+            writer,
+            (self.width, self.height),
+            (16, color_type),
+            Some(self.bit_depth as u8),
+            rows,
+        )
+    }
+
+    /// Write every sample, big endian, without any header
+    pub fn write_raw<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        let data: Vec<u8> = self.data.iter().flat_map(|s| s.to_be_bytes()).collect();
+        writer.write_all(&data)
+    }
+
+    fn check_dimensions(&self) -> io::Result<()> {
+        if !(1..=16).contains(&self.bit_depth) {
+            return Err(unsupported("bit depth must be between 1 and 16"));
+        }
+        check_dimensions(self.width, self.height, self.format, self.data.len())
+    }
+}
+
+/// Largest amount of data a stored deflate block can hold
+const STORED_BLOCK_LEN: usize = 65535;
+
+/// Write a PNG file holding `rows`, each already laid out as PNG samples.
+/// The image data is a zlib stream of stored (uncompressed) deflate blocks.
+fn write_png<W: Write>(
+    mut writer: W,
+    (width, height): (usize, usize),
+    (bit_depth, color_type): (u8, u8),
+    significant_bits: Option<u8>,
+    rows: impl Iterator<Item = impl AsRef<[u8]>>,
+) -> io::Result<()> {
+    writer.write_all(b"\x89PNG\r\n\x1a\n")?;
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&png_u32(width)?.to_be_bytes());
+    header.extend_from_slice(&png_u32(height)?.to_be_bytes());
+    // Bit depth, colour type, then default compression, filtering and no interlacing
+    header.extend_from_slice(&[bit_depth, color_type, 0, 0, 0]);
+    write_chunk(&mut writer, b"IHDR", &header)?;
+    let channels = if color_type == 0 { 1 } else { 3 };
+    if let Some(bits) = significant_bits {
+        write_chunk(&mut writer, b"sBIT", &vec![bits; channels])?;
+    }
+
+    // Each row is preceded by its filter type, 0 for none
+    let row_len = width * channels * bit_depth as usize / 8;
+    let mut data = ImageData::new(&mut writer, height * (1 + row_len))?;
+    for row in rows {
+        data.write(&[0])?;
+        data.write(row.as_ref())?;
+    }
+    write_chunk(&mut writer, b"IEND", &[])
+}
+
+fn png_u32(value: usize) -> io::Result<u32> {
+    u32::try_from(value).map_err(|_| unsupported("image is too large for a PNG file"))
+}
+
+/// The IDAT chunk of a PNG file, written as the rows of the image are produced so that only
+/// the stored block being filled is held in memory rather than the whole image
+struct ImageData<W: Write> {
+    writer: W,
+    /// Data of the block being filled
+    block: Vec<u8>,
+    /// Number of bytes of image data yet to be added to a block
+    remaining: usize,
+    /// CRC of the chunk so far, without the final inversion
+    crc: u32,
+    /// Adler-32 of the image data in the blocks written so far
+    adler: u32,
+}
+
+impl<W: Write> ImageData<W> {
+    /// Start an IDAT chunk which will hold `len` bytes of image data, which must not be empty
+    fn new(mut writer: W, len: usize) -> io::Result<ImageData<W>> {
+        // The zlib header and Adler-32, and the header of each block
+        let blocks = len.div_ceil(STORED_BLOCK_LEN);
+        writer.write_all(&png_u32(2 + len + blocks * 5 + 4)?.to_be_bytes())?;
+        let mut data = ImageData {
+            writer,
+            block: Vec::with_capacity(len.min(STORED_BLOCK_LEN)),
+            remaining: len,
+            crc: !0,
+            adler: 1,
+        };
+        data.put(b"IDAT")?;
+        data.put(&[0x78, 0x01])?;
+        Ok(data)
+    }
+
+    /// Write part of the chunk, keeping its CRC up to date
+    fn put(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.crc = crc32(self.crc, bytes);
+        self.writer.write_all(bytes)
+    }
+
+    /// Add `bytes` to the image data, writing out each block as it fills
+    fn write(&mut self, mut bytes: &[u8]) -> io::Result<()> {
+        while !bytes.is_empty() {
+            let amnt = bytes.len().min(STORED_BLOCK_LEN - self.block.len());
+            self.block.extend_from_slice(&bytes[..amnt]);
+            self.remaining -= amnt;
+            bytes = &bytes[amnt..];
+            if self.block.len() == STORED_BLOCK_LEN || self.remaining == 0 {
+                self.end_block()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write out the block being filled. The last block is followed by the Adler-32 of
+    /// the image data, which ends the chunk.
+    fn end_block(&mut self) -> io::Result<()> {
+        let len = self.block.len() as u16;
+        let last = self.remaining == 0;
+        self.put(&[last as u8])?;
+        self.put(&len.to_le_bytes())?;
+        self.put(&(!len).to_le_bytes())?;
+        self.crc = crc32(self.crc, &self.block);
+        self.adler = adler32(self.adler, &self.block);
+        self.writer.write_all(&self.block)?;
+        self.block.clear();
+        if last {
+            self.put(&self.adler.to_be_bytes())?;
+            self.writer.write_all(&(!self.crc).to_be_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+fn write_chunk<W: Write>(writer: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    writer.write_all(&png_u32(data.len())?.to_be_bytes())?;
+    writer.write_all(kind)?;
+    writer.write_all(data)?;
+    let crc = !crc32(crc32(!0, kind), data);
+    writer.write_all(&crc.to_be_bytes())
+}
+
+/// Update a CRC-32 (as used by PNG) with `data`, without the initial and final inversion
+fn crc32(mut crc: u32, data: &[u8]) -> u32 {
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+/// Update an Adler-32, which starts at 1, with `data`
+fn adler32(adler: u32, data: &[u8]) -> u32 {
+    let (mut a, mut b) = (adler & 0xFFFF, adler >> 16);
+    for chunk in data.chunks(5552) {
+        for byte in chunk {
+            a += *byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 3x2 RGB image whose samples count up from 1
+    fn rgb() -> Image {
+        Image {
+            width: 3,
+            height: 2,
+            format: PixelFormat::Rgb8,
+            data: (1..=18).collect(),
+        }
+    }
+
+    /// The type and data of each chunk of a PNG file, checking the signature and every CRC
+    fn png_chunks(png: &[u8]) -> Vec<([u8; 4], Vec<u8>)> {
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        let mut chunks = Vec::new();
+        let mut rest = &png[8..];
+        while !rest.is_empty() {
+            let len = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+            let (kind, data) = (&rest[4..8], &rest[8..8 + len]);
+            let crc = u32::from_be_bytes(rest[8 + len..12 + len].try_into().unwrap());
+            assert_eq!(crc, !crc32(crc32(!0, kind), data));
+            chunks.push((kind.try_into().unwrap(), data.to_vec()));
+            rest = &rest[12 + len..];
+        }
+        chunks
+    }
+
+    /// The data held by a zlib stream of stored deflate blocks, checking its Adler-32
+    fn inflate_stored(zlib: &[u8]) -> Vec<u8> {
+        assert_eq!(&zlib[..2], [0x78, 0x01]);
+        let (mut data, mut rest) = (Vec::new(), &zlib[2..]);
+        loop {
+            let last = rest[0] == 1;
+            let len = u16::from_le_bytes([rest[1], rest[2]]);
+            assert_eq!(u16::from_le_bytes([rest[3], rest[4]]), !len);
+            data.extend_from_slice(&rest[5..5 + len as usize]);
+            rest = &rest[5 + len as usize..];
+            if last {
+                break;
+            }
+        }
+        assert_eq!(rest, adler32(1, &data).to_be_bytes());
+        data
+    }
+
+    #[test]
+    fn checksums_match_known_values() {
+        assert_eq!(!crc32(!0, b"123456789"), 0xCBF4_3926);
+        assert_eq!(!crc32(!0, b"IEND"), 0xAE42_6082);
+        assert_eq!(adler32(1, b"Wikipedia"), 0x11E6_0398);
+        assert_eq!(adler32(adler32(1, b"Wiki"), b"pedia"), 0x11E6_0398);
+        // Long enough for the sums to be reduced part way through
+        assert_eq!(adler32(1, &[0xFF; 6000]), 0xA497_59EA);
+    }
+
+    #[test]
+    fn png_holds_each_row_unfiltered() {
+        let mut png = Vec::new();
+        rgb().write_png(&mut png).unwrap();
+        let chunks = png_chunks(&png);
+        let kinds: Vec<&[u8; 4]> = chunks.iter().map(|(kind, _)| kind).collect();
+        assert_eq!(kinds, [b"IHDR", b"IDAT", b"IEND"]);
+        assert_eq!(chunks[0].1, [0, 0, 0, 3, 0, 0, 0, 2, 8, 2, 0, 0, 0]);
+        let mut raw = vec![0];
+        raw.extend(1..=9);
+        raw.push(0);
+        raw.extend(10..=18);
+        assert_eq!(inflate_stored(&chunks[1].1), raw);
+        assert!(chunks[2].1.is_empty());
+    }
+
+    #[test]
+    fn png_splits_data_between_stored_blocks() {
+        // 300 rows of 300 grey samples and a filter byte need two blocks
+        let image = Image {
+            width: 300,
+            height: 300,
+            format: PixelFormat::Gray8,
+            data: (0..300 * 300).map(|n| (n % 251) as u8).collect(),
+        };
+        let mut png = Vec::new();
+        image.write_png(&mut png).unwrap();
+        let chunks = png_chunks(&png);
+        assert_eq!(chunks[0].1[8..10], [8, 0]);
+        let raw = inflate_stored(&chunks[1].1);
+        assert_eq!(raw.len(), 301 * 300);
+        for (row, pixels) in raw.chunks_exact(301).zip(image.data.chunks_exact(300)) {
+            assert_eq!((row[0], &row[1..]), (0, pixels));
+        }
+    }
+
+    #[test]
+    fn png_scales_16_bit_samples_up() {
+        let image = Image16 {
+            width: 1,
+            height: 2,
+            format: PixelFormat::Gray16,
+            bit_depth: 12,
+            data: vec![0xFFF, 0x123],
+        };
+        let mut png = Vec::new();
+        image.write_png(&mut png).unwrap();
+        let chunks = png_chunks(&png);
+        assert_eq!(chunks[0].1[8..10], [16, 0]);
+        assert_eq!((&chunks[1].0, &chunks[1].1[..]), (b"sBIT", &[12][..]));
+        assert_eq!(inflate_stored(&chunks[2].1), [0, 0xFF, 0xF0, 0, 0x12, 0x30]);
+    }
+
+    #[test]
+    fn bmp_rows_are_padded_bgr_and_bottom_up() {
+        let mut bmp = Vec::new();
+        rgb().write_bmp(&mut bmp).unwrap();
+        let u32_at = |at: usize| u32::from_le_bytes(bmp[at..at + 4].try_into().unwrap());
+        assert_eq!(&bmp[..2], b"BM");
+        // 9 bytes of pixels padded to 12 in each row
+        assert_eq!((u32_at(2), u32_at(10)), (54 + 24, 54));
+        assert_eq!((u32_at(18), u32_at(22)), (3, 2));
+        assert_eq!(u16::from_le_bytes([bmp[28], bmp[29]]), 24);
+        assert_eq!(u32_at(34), 24);
+        #[rustfmt::skip]
+        assert_eq!(bmp[54..], [
+            12, 11, 10, 15, 14, 13, 18, 17, 16, 0, 0, 0,
+            3, 2, 1, 6, 5, 4, 9, 8, 7, 0, 0, 0,
+        ]);
+    }
+
+    #[test]
+    fn greyscale_bmp_has_a_palette() {
+        let image = Image {
+            width: 5,
+            height: 1,
+            format: PixelFormat::Gray8,
+            data: vec![1, 2, 3, 4, 5],
+        };
+        let mut bmp = Vec::new();
+        image.write_bmp(&mut bmp).unwrap();
+        let offset = 54 + 256 * 4;
+        assert_eq!(
+            u32::from_le_bytes(bmp[10..14].try_into().unwrap()),
+            offset as u32
+        );
+        assert_eq!(bmp[28], 8);
+        assert_eq!(bmp[54 + 7 * 4..54 + 8 * 4], [7, 7, 7, 0]);
+        assert_eq!(bmp[offset..], [1, 2, 3, 4, 5, 0, 0, 0]);
+    }
+
+    #[test]
+    fn pnm_header_describes_the_samples() {
+        let mut ppm = Vec::new();
+        rgb().write_pnm(&mut ppm).unwrap();
+        assert_eq!(&ppm[..11], b"P6\n3 2\n255\n");
+        assert_eq!(ppm[11..], (1..=18).collect::<Vec<u8>>());
+
+        let image = Image16 {
+            width: 2,
+            height: 1,
+            format: PixelFormat::Gray16,
+            bit_depth: 12,
+            data: vec![0xABC, 0x001],
+        };
+        let mut pgm = Vec::new();
+        image.write_pnm(&mut pgm).unwrap();
+        assert_eq!(pgm, b"P5\n2 1\n4095\n\x0A\xBC\x00\x01");
+    }
+
+    #[test]
+    fn images_without_pixels_are_refused() {
+        for (width, height, len) in [(0, 2, 0), (3, 0, 0), (3, 2, 17)] {
+            let image = Image {
+                width,
+                height,
+                format: PixelFormat::Rgb8,
+                data: vec![0; len],
+            };
+            let image16 = Image16::from(image.clone());
+            for result in [
+                image.write_pnm(io::sink()),
+                image.write_bmp(io::sink()),
+                image.write_png(io::sink()),
+                image16.write_pnm(io::sink()),
+                image16.write_png(io::sink()),
+            ] {
+                assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+            }
+        }
+        let image = Image16 {
+            bit_depth: 0,
+            ..Image16::from(rgb())
+        };
+        assert!(image.write_png(io::sink()).is_err());
+    }
+}
@@ -0,0 +1,47 @@
+use std::thread;
+
+/// Call `f` on runs of consecutive `chunk_len` long chunks of `data`, one run per thread, with the
+/// index of the first chunk in the run. A single thread handles all of `data` without spawning.
+pub(crate) fn for_each_run<T: Send>(
+    data: &mut [T],
+    chunk_len: usize,
+    threads: usize,
+    f: impl Fn(usize, &mut [T]) + Sync,
+) {
+    let chunks = data.len().div_ceil(chunk_len.max(1));
+    let per_thread = chunks.div_ceil(threads.max(1)).max(1);
+    if threads <= 1 || chunks <= 1 {
+        return f(0, data);
+    }
+    thread::scope(|scope| {
+        for (n, run) in data.chunks_mut(per_thread * chunk_len).enumerate() {
+            let f = &f;
+            scope.spawn(move || f(n * per_thread, run));
+        }
+    });
+}
+
+/// Call `f` on each of `items` on a thread of its own, returning the results in order.
+/// A single item is handled without spawning.
+pub(crate) fn map_each<I: Send, R: Send>(items: Vec<I>, f: impl Fn(I) -> R + Sync) -> Vec<R> {
+    if items.len() <= 1 {
+        return items.into_iter().map(f).collect();
+    }
+    thread::scope(|scope| {
+        let handles: Vec<_> = items
+            .into_iter()
+            .map(|item| {
+                let f = &f;
+                scope.spawn(move || f(item))
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| {
+                handle
+                    .join()
+                    .unwrap_or_else(|e| std::panic::resume_unwind(e))
+            })
+            .collect()
+    })
+}
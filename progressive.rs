@@ -0,0 +1,154 @@
+use crate::{
+    bitreader::BitReader, error::EntropyErrorKind, huffman::HuffmanDecoder, quant::ZIGZAG,
+};
+
+type Result<T> = std::result::Result<T, EntropyErrorKind>;
+
+/// Decode the first (or only) pass of a block's DC coefficient (ITU T.81 G.1.2.1)
+pub(crate) fn decode_dc_first(
+    reader: &mut BitReader,
+    dc: Option<&HuffmanDecoder>,
+    prediction: &mut i32,
+    approx_low: u8,
+    block: &mut [i16; 64],
+) -> Result<()> {
+    let size = dc
+        .and_then(|dc| dc.decode(reader))
+        .ok_or(EntropyErrorKind::InvalidCode)?;
+    if size > 16 {
+        return Err(EntropyErrorKind::InvalidCode);
+    }
+    *prediction += reader.receive_extend(size as u32);
+    block[0] = i16::try_from(*prediction << approx_low)
+        .map_err(|_| EntropyErrorKind::CoefficientOverflow)?;
+    Ok(())
+}
+
+/// Add the next bit of a block's DC coefficient (ITU T.81 G.1.2.1)
+pub(crate) fn decode_dc_refine(reader: &mut BitReader, approx_low: u8, block: &mut [i16; 64]) {
+    if reader.get_bit() {
+        block[0] |= 1 << approx_low;
+    }
+}
+
+/// Decode the first pass of a band of AC coefficients (ITU T.81 G.1.2.2).
+/// `eob_run` counts the blocks remaining in the current run of empty bands.
+pub(crate) fn decode_ac_first(
+    reader: &mut BitReader,
+    ac: Option<&HuffmanDecoder>,
+    band: (u8, u8),
+    approx_low: u8,
+    eob_run: &mut u32,
+    block: &mut [i16; 64],
+) -> Result<()> {
+    if *eob_run > 0 {
+        *eob_run -= 1;
+        return Ok(());
+    }
+    let (start, end) = (band.0 as usize, band.1 as usize);
+    let mut k = start;
+    while k <= end {
+        let rs = ac
+            .and_then(|ac| ac.decode(reader))
+            .ok_or(EntropyErrorKind::InvalidCode)?;
+        let (run, size) = ((rs >> 4) as u32, (rs & 0x0F) as u32);
+        if size == 0 {
+            if run < 15 {
+                // EOBn, ending this band and the next 2^n - 1 + (n bits) bands
+                *eob_run = (1 << run) - 1 + reader.get_bits(run);
+                break;
+            }
+            // ZRL, a run of 16 zeros
+            k += 16;
+            continue;
+        }
+        k += run as usize;
+        if k > end {
+            return Err(EntropyErrorKind::CoefficientOverflow);
+        }
+        block[ZIGZAG[k]] = i16::try_from(reader.receive_extend(size) << approx_low)
+            .map_err(|_| EntropyErrorKind::CoefficientOverflow)?;
+        k += 1;
+    }
+    Ok(())
+}
+
+/// Add the next bit of a band of AC coefficients (ITU T.81 G.1.2.3). Coefficients which are
+/// already non-zero receive a correction bit, whilst zero coefficients may become +/-1 (shifted).
+pub(crate) fn decode_ac_refine(
+    reader: &mut BitReader,
+    ac: Option<&HuffmanDecoder>,
+    band: (u8, u8),
+    approx_low: u8,
+    eob_run: &mut u32,
+    block: &mut [i16; 64],
+) -> Result<()> {
+    let (start, end) = (band.0 as usize, band.1 as usize);
+    let bit = 1i16 << approx_low;
+    let mut k = start;
+
+    if *eob_run == 0 {
+        while k <= end {
+            let rs = ac
+                .and_then(|ac| ac.decode(reader))
+                .ok_or(EntropyErrorKind::InvalidCode)?;
+            let (mut run, size) = ((rs >> 4) as i32, rs & 0x0F);
+            let value = match size {
+                0 if run < 15 => {
+                    *eob_run = (1 << run) + reader.get_bits(run as u32);
+                    break;
+                }
+                // ZRL, skipping 16 zero coefficients
+                0 => 0,
+                // Newly non-zero coefficients can only be +/-1 at this bit position
+                1 if reader.get_bit() => bit,
+                1 => -bit,
+                _ => return Err(EntropyErrorKind::InvalidCode),
+            };
+
+            // Skip `run` zero coefficients, refining any non-zero ones passed along the way
+            while k <= end {
+                let coefficient = &mut block[ZIGZAG[k]];
+                if *coefficient != 0 {
+                    refine(reader, coefficient, bit)?;
+                } else {
+                    if run == 0 {
+                        break;
+                    }
+                    run -= 1;
+                }
+                k += 1;
+            }
+            if value != 0 {
+                if k > end {
+                    return Err(EntropyErrorKind::CoefficientOverflow);
+                }
+                block[ZIGZAG[k]] = value;
+            }
+            k += 1;
+        }
+    }
+
+    if *eob_run > 0 {
+        // The band has no newly non-zero coefficients, but existing ones are still refined
+        for k in k..=end {
+            let coefficient = &mut block[ZIGZAG[k]];
+            if *coefficient != 0 {
+                refine(reader, coefficient, bit)?;
+            }
+        }
+        *eob_run -= 1;
+    }
+    Ok(())
+}
+
+/// Apply a correction bit to a coefficient which is already non-zero, moving it away from zero
+fn refine(reader: &mut BitReader, coefficient: &mut i16, bit: i16) -> Result<()> {
+    if reader.get_bit() && *coefficient & bit == 0 {
+        let step = if *coefficient >= 0 { bit } else { -bit };
+        *coefficient = coefficient
+            .checked_add(step)
+            .ok_or(EntropyErrorKind::CoefficientOverflow)?;
+    }
+    Ok(())
+}
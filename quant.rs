@@ -0,0 +1,156 @@
+use crate::{
+    error::{JpegError, Result},
+    segment::Segment,
+};
+
+/// Maps the position of a coefficient in zigzag order to its position in natural (row-major) order
+pub const ZIGZAG: [usize; 64] = [
+    0, 1, 8, 16, 9, 2, 3, 10, 17, 24, 32, 25, 18, 11, 4, 5, 12, 19, 26, 33, 40, 48, 41, 34, 27, 20,
+    13, 6, 7, 14, 21, 28, 35, 42, 49, 56, 57, 50, 43, 36, 29, 22, 15, 23, 30, 37, 44, 51, 58, 59,
+    52, 45, 38, 31, 39, 46, 53, 60, 61, 54, 47, 55, 62, 63,
+];
+
+/// A quantization table, as defined by a DQT segment
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuantTable {
+    /// Quantization table destination identifier (Tq), 0 -> 3
+    pub id: u8,
+    /// Precision of each value in bits (8 or 16)
+    pub precision: u8,
+    /// The 64 quantization values, in the zigzag order they are stored in
+    pub values: [u16; 64],
+}
+
+impl QuantTable {
+    /// The quantization values in natural (row-major) order
+    pub fn natural(&self) -> [u16; 64] {
+        let mut natural = [0u16; 64];
+        for (zz, value) in self.values.iter().enumerate() {
+            natural[ZIGZAG[zz]] = *value;
+        }
+        natural
+    }
+}
+
+/// Parse every quantization table defined in a DQT segment
+pub(crate) fn parse_quant_tables(segment: &Segment) -> Result<Vec<QuantTable>> {
+    let mut tables = Vec::new();
+    let mut data = segment.payload.as_slice();
+    while let Some((&pq_tq, rest)) = data.split_first() {
+        let precision = pq_tq >> 4;
+        let id = pq_tq & 0x0F;
+        if precision > 1 || id > 3 {
+            return Err(JpegError::Malformed {
+                offset: segment.offset,
+                marker: segment.marker,
+                reason: "quantization table precision must be 0 or 1 and destination 0 -> 3",
+            });
+        }
+
+        let width = if precision == 0 { 1 } else { 2 };
+        if rest.len() < 64 * width {
+            return Err(JpegError::BadLength {
+                offset: segment.offset,
+                marker: segment.marker,
+                length: segment.length,
+            });
+        }
+        let (table, rest) = rest.split_at(64 * width);
+        let mut values = [0u16; 64];
+        for (value, bytes) in values.iter_mut().zip(table.chunks_exact(width)) {
+            *value = match bytes {
+                [v] => *v as u16,
+                [hi, lo] => u16::from_be_bytes([*hi, *lo]),
+                _ => unreachable!(),
+            };
+        }
+
+        tables.push(QuantTable {
+            id,
+            precision: 8 * width as u8,
+            values,
+        });
+        data = rest;
+    }
+    Ok(tables)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::marker::JpegMarker;
+
+    fn segment(payload: Vec<u8>) -> Segment {
+        Segment {
+            marker: JpegMarker::DQT,
+            offset: 20,
+            length: payload.len(),
+            payload,
+            entropy: None,
+        }
+    }
+
+    #[test]
+    fn one_segment_can_hold_8_and_16_bit_tables() {
+        let mut payload = vec![0x02];
+        payload.extend(1..=64);
+        payload.push(0x13);
+        for value in 0..64u16 {
+            payload.extend((value * 1000 + 255).to_be_bytes());
+        }
+        let tables = parse_quant_tables(&segment(payload)).unwrap();
+
+        assert_eq!(tables.len(), 2);
+        assert_eq!((tables[0].id, tables[0].precision), (2, 8));
+        assert!(tables[0].values.iter().copied().eq(1..=64));
+        assert_eq!((tables[1].id, tables[1].precision), (3, 16));
+        assert!(tables[1]
+            .values
+            .iter()
+            .copied()
+            .eq((0..64).map(|v| v * 1000 + 255)));
+    }
+
+    #[test]
+    fn natural_order_undoes_the_zigzag() {
+        let mut payload = vec![0x00];
+        payload.extend(0..64);
+        let natural = parse_quant_tables(&segment(payload)).unwrap()[0].natural();
+        // The first row of the block is reached at zigzag positions 0, 1, 5, 6, 14, 15, 27, 28
+        assert_eq!(natural[..8], [0, 1, 5, 6, 14, 15, 27, 28]);
+        assert_eq!(natural[8..16], [2, 4, 7, 13, 16, 26, 29, 42]);
+        assert_eq!(natural[56..], [35, 36, 48, 49, 57, 58, 62, 63]);
+    }
+
+    #[test]
+    fn tables_must_be_complete() {
+        let mut short_16 = vec![0x10];
+        short_16.extend([0; 127]);
+        let mut short_8 = vec![0x00];
+        short_8.extend([1; 64]);
+        short_8.push(0x01);
+        short_8.extend([1; 63]);
+        for payload in [short_16, short_8, vec![0x00]] {
+            assert!(matches!(
+                parse_quant_tables(&segment(payload)),
+                Err(JpegError::BadLength { offset: 20, .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn precision_and_destination_are_checked() {
+        for pq_tq in [0x20, 0x04, 0x1F] {
+            let mut payload = vec![pq_tq];
+            payload.extend([0; 128]);
+            assert!(
+                matches!(
+                    parse_quant_tables(&segment(payload)),
+                    Err(JpegError::Malformed { offset: 20, .. })
+                ),
+                "Pq/Tq {:#04x}",
+                pq_tq
+            );
+        }
+    }
+}
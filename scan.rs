@@ -0,0 +1,195 @@
+use crate::{
+    error::{JpegError, Result},
+    segment::{EntropyData, Segment},
+};
+
+/// A component taking part in a scan, as listed in its Start of Scan segment
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ScanComponent {
+    /// Scan component selector (Csj), matching the id of a frame component
+    pub selector: u8,
+    /// DC entropy coding table destination selector (Tdj)
+    pub dc_table: u8,
+    /// AC entropy coding table destination selector (Taj)
+    pub ac_table: u8,
+}
+
+/// The parameters of a scan, as read from its Start of Scan segment
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScanHeader {
+    pub components: Vec<ScanComponent>,
+    /// Start of spectral or predictor selection (Ss)
+    pub spectral_start: u8,
+    /// End of spectral selection (Se)
+    pub spectral_end: u8,
+    /// Successive approximation bit position high (Ah)
+    pub approx_high: u8,
+    /// Successive approximation bit position low, or point transform (Al)
+    pub approx_low: u8,
+}
+
+/// A scan header along with the location of the entropy-coded data which followed it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Scan {
+    pub header: ScanHeader,
+    pub entropy: EntropyData,
+}
+
+pub(crate) fn parse_scan_header(segment: &Segment) -> Result<ScanHeader> {
+    let data = &segment.payload;
+    let bad_length = JpegError::BadLength {
+        offset: segment.offset,
+        marker: segment.marker,
+        length: segment.length,
+    };
+    let Some(&component_count) = data.first() else {
+        return Err(bad_length);
+    };
+    let component_count = component_count as usize;
+    if !(1..=4).contains(&component_count) {
+        return Err(JpegError::Malformed {
+            offset: segment.offset,
+            marker: segment.marker,
+            reason: "scans must contain between 1 and 4 components",
+        });
+    }
+    // Ns, then Csj and Tdj << 4 | Taj for each component, then Ss, Se and Ah << 4 | Al
+    if data.len() != 1 + component_count * 2 + 3 {
+        return Err(bad_length);
+    }
+
+    let (specs, params) = data[1..].split_at(component_count * 2);
+    let components = specs
+        .chunks_exact(2)
+        .map(|spec| ScanComponent {
+            selector: spec[0],
+            dc_table: spec[1] >> 4,
+            ac_table: spec[1] & 0x0F,
+        })
+        .collect::<Vec<_>>();
+    if components.iter().any(|c| c.dc_table > 3 || c.ac_table > 3) {
+        return Err(JpegError::Malformed {
+            offset: segment.offset,
+            marker: segment.marker,
+            reason: "entropy coding table selectors must be 0 -> 3",
+        });
+    }
+
+    Ok(ScanHeader {
+        components,
+        spectral_start: params[0],
+        spectral_end: params[1],
+        approx_high: params[2] >> 4,
+        approx_low: params[2] & 0x0F,
+    })
+}
+
+/// Read the number of MCUs in each restart interval from a DRI segment (0 disables restart intervals)
+pub(crate) fn parse_restart_interval(segment: &Segment) -> Result<u16> {
+    match segment.payload.as_slice() {
+        [hi, lo] => Ok(u16::from_be_bytes([*hi, *lo])),
+        _ => Err(JpegError::BadLength {
+            offset: segment.offset,
+            marker: segment.marker,
+            length: segment.length,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::marker::JpegMarker;
+
+    fn segment(marker: JpegMarker, payload: Vec<u8>) -> Segment {
+        Segment {
+            marker,
+            offset: 20,
+            length: payload.len(),
+            payload,
+            entropy: None,
+        }
+    }
+
+    #[test]
+    fn components_and_spectral_parameters_are_read() {
+        let payload = vec![3, 1, 0x00, 2, 0x11, 3, 0x23, 1, 5, 0x21];
+        let header = parse_scan_header(&segment(JpegMarker::SOS, payload)).unwrap();
+        assert_eq!(
+            header,
+            ScanHeader {
+                components: vec![
+                    ScanComponent {
+                        selector: 1,
+                        dc_table: 0,
+                        ac_table: 0,
+                    },
+                    ScanComponent {
+                        selector: 2,
+                        dc_table: 1,
+                        ac_table: 1,
+                    },
+                    ScanComponent {
+                        selector: 3,
+                        dc_table: 2,
+                        ac_table: 3,
+                    },
+                ],
+                spectral_start: 1,
+                spectral_end: 5,
+                approx_high: 2,
+                approx_low: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn scans_hold_between_1_and_4_components() {
+        for count in [0, 5] {
+            let mut payload = vec![count];
+            payload.extend(std::iter::repeat_n([1, 0x00], count as usize).flatten());
+            payload.extend([0, 63, 0]);
+            assert!(matches!(
+                parse_scan_header(&segment(JpegMarker::SOS, payload)),
+                Err(JpegError::Malformed { offset: 20, .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn table_selectors_are_checked() {
+        for tables in [0x40, 0x04, 0xFF] {
+            let payload = vec![1, 1, tables, 0, 63, 0];
+            assert!(matches!(
+                parse_scan_header(&segment(JpegMarker::SOS, payload)),
+                Err(JpegError::Malformed { offset: 20, .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn segment_length_must_match_the_component_count() {
+        for payload in [
+            vec![],
+            vec![2, 1, 0x00, 0, 63, 0],
+            vec![1, 1, 0x00, 0, 63, 0, 0],
+        ] {
+            assert!(matches!(
+                parse_scan_header(&segment(JpegMarker::SOS, payload)),
+                Err(JpegError::BadLength { offset: 20, .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn restart_interval_is_two_bytes() {
+        let dri = |payload| parse_restart_interval(&segment(JpegMarker::DRI, payload));
+        assert_eq!(dri(vec![0x01, 0x02]).unwrap(), 0x0102);
+        assert_eq!(dri(vec![0, 0]).unwrap(), 0);
+        assert!(matches!(dri(vec![1]), Err(JpegError::BadLength { .. })));
+        assert!(matches!(
+            dri(vec![0, 1, 2]),
+            Err(JpegError::BadLength { .. })
+        ));
+    }
+}
@@ -0,0 +1,479 @@
+use std::io::{self, Read};
+
+use crate::{
+    error::{JpegError, Result},
+    marker::JpegMarker,
+};
+
+/// Size of the read buffer used by `SegmentReader`
+pub const READ_BUF_SIZE: usize = 8192;
+
+/// Location of the entropy-coded data following a Start of Scan segment
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct EntropyData {
+    /// Offset of the first byte of entropy-coded data
+    pub offset: usize,
+    /// Length of the entropy-coded data, including any stuffed bytes and RSTn markers
+    pub length: usize,
+    /// Number of RSTn markers found within the data
+    pub restart_markers: usize,
+}
+
+/// A marker and its payload, as yielded by `SegmentReader`
+#[derive(Debug)]
+pub struct Segment {
+    pub marker: JpegMarker,
+    /// Offset of the 0xFF which introduced the marker
+    pub offset: usize,
+    /// Length of the payload (i.e excluding the marker and the two length bytes)
+    pub length: usize,
+    /// The retained part of the payload. This is shorter than `length` if the reader was told to
+    /// skip some or all of the payload for this marker (see `SegmentReader::with_retain`).
+    pub payload: Vec<u8>,
+    /// For SOS segments, the entropy-coded data which followed it
+    pub entropy: Option<EntropyData>,
+}
+
+/// Streams marker segments out of a reader using a fixed 8192 byte buffer.
+/// Segments which straddle the end of the buffer are carried across reads,
+/// so only the payload of the current segment is ever held in memory.
+pub struct SegmentReader<R: Read> {
+    reader: R,
+    buf: [u8; READ_BUF_SIZE],
+    /// Index of the next unread byte in `buf`
+    pos: usize,
+    /// Number of valid bytes in `buf`
+    filled: usize,
+    /// File offset of `buf[0]`
+    buf_offset: usize,
+    /// A marker which has been read, but not yet yielded (i.e the marker which ended a scan)
+    pending: Option<(JpegMarker, usize)>,
+    /// Total length of the stream, if known
+    stream_len: Option<usize>,
+    /// Number of payload bytes to retain for each marker. Anything beyond this is skipped.
+    retain: fn(JpegMarker) -> usize,
+    /// Number of bytes skipped between segments because they were not part of a marker
+    extraneous_bytes: usize,
+    /// Set once the End of Image marker is reached, or an error occurs
+    done: bool,
+}
+
+impl<R: Read> SegmentReader<R> {
+    pub fn new(reader: R) -> SegmentReader<R> {
+        SegmentReader {
+            reader,
+            buf: [0u8; READ_BUF_SIZE],
+            pos: 0,
+            filled: 0,
+            buf_offset: 0,
+            pending: None,
+            stream_len: None,
+            retain: |_| usize::MAX,
+            extraneous_bytes: 0,
+            done: false,
+        }
+    }
+
+    /// Tell the reader how long the stream is, so that segments claiming to be longer than
+    /// the rest of the stream can be rejected before reading them
+    pub fn with_stream_len(mut self, stream_len: usize) -> SegmentReader<R> {
+        self.stream_len = Some(stream_len);
+        self
+    }
+
+    /// Only retain the first `retain(marker)` bytes of each payload, skipping the remainder
+    /// rather than copying it. By default every payload is retained in full.
+    pub fn with_retain(mut self, retain: fn(JpegMarker) -> usize) -> SegmentReader<R> {
+        self.retain = retain;
+        self
+    }
+
+    /// File offset of the next unread byte
+    pub fn offset(&self) -> usize {
+        self.buf_offset + self.pos
+    }
+
+    /// Refill the buffer once every byte has been consumed. Returns false at end of file.
+    fn fill(&mut self) -> io::Result<bool> {
+        if self.pos < self.filled {
+            return Ok(true);
+        }
+        self.buf_offset += self.filled;
+        self.pos = 0;
+        self.filled = 0;
+        loop {
+            match self.reader.read(&mut self.buf) {
+                Ok(amnt) => {
+                    self.filled = amnt;
+                    return Ok(amnt > 0);
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn next_byte(&mut self) -> io::Result<Option<u8>> {
+        if !self.fill()? {
+            return Ok(None);
+        }
+        self.pos += 1;
+        Ok(Some(self.buf[self.pos - 1]))
+    }
+
+    /// Copy `length` bytes into `out`, reading across as many buffer refills as required.
+    /// Returns false if the stream ended first.
+    fn read_payload(&mut self, length: usize, out: &mut Vec<u8>) -> io::Result<bool> {
+        out.reserve_exact(length);
+        let mut remaining = length;
+        while remaining > 0 {
+            if !self.fill()? {
+                return Ok(false);
+            }
+            let amnt = remaining.min(self.filled - self.pos);
+            out.extend_from_slice(&self.buf[self.pos..self.pos + amnt]);
+            self.pos += amnt;
+            remaining -= amnt;
+        }
+        Ok(true)
+    }
+
+    /// Advance `length` bytes without copying them. Returns false if the stream ended first.
+    fn skip(&mut self, length: usize) -> io::Result<bool> {
+        let mut remaining = length;
+        while remaining > 0 {
+            if !self.fill()? {
+                return Ok(false);
+            }
+            let amnt = remaining.min(self.filled - self.pos);
+            self.pos += amnt;
+            remaining -= amnt;
+        }
+        Ok(true)
+    }
+
+    /// Read the marker which must follow a segment. Fill bytes (0xFF 0xFF) may precede it, but anything
+    /// else is extraneous and skipped until the next 0xFF. Returns the marker along with the offset of its 0xFF prefix.
+    fn next_marker(&mut self) -> io::Result<Option<(JpegMarker, usize)>> {
+        loop {
+            let Some(byte) = self.next_byte()? else {
+                return Ok(None);
+            };
+            if JpegMarker::from_u8(byte) != JpegMarker::INDICATOR {
+                self.extraneous_bytes += 1;
+                continue;
+            }
+            match self.marker_after_indicator()? {
+                Some((JpegMarker::None(_), _)) => self.extraneous_bytes += 2,
+                marker => return Ok(marker),
+            }
+        }
+    }
+
+    /// Having read a 0xFF, skip any fill bytes and read the byte which follows
+    fn marker_after_indicator(&mut self) -> io::Result<Option<(JpegMarker, usize)>> {
+        let mut offset = self.offset() - 1;
+        loop {
+            let Some(byte) = self.next_byte()? else {
+                return Ok(None);
+            };
+            match JpegMarker::from_u8(byte) {
+                JpegMarker::INDICATOR => offset = self.offset() - 1,
+                marker => return Ok(Some((marker, offset))),
+            }
+        }
+    }
+
+    /// Skip over the entropy-coded data following a SOS segment. Stuffed bytes (0xFF 0x00) and
+    /// RSTn markers are part of the data, so the scan ends at the first other marker (or end of file).
+    fn read_entropy_data(&mut self) -> io::Result<EntropyData> {
+        let offset = self.offset();
+        let mut restart_markers = 0;
+        loop {
+            if !self.fill()? {
+                return Ok(EntropyData {
+                    offset,
+                    length: self.offset() - offset,
+                    restart_markers,
+                });
+            }
+            // Jump straight to the next 0xFF in the buffer rather than inspecting every byte
+            match self.buf[self.pos..self.filled]
+                .iter()
+                .position(|b| *b == 0xFF)
+            {
+                Some(idx) => self.pos += idx + 1,
+                None => {
+                    self.pos = self.filled;
+                    continue;
+                }
+            }
+            match self.marker_after_indicator()? {
+                Some((JpegMarker::None(_), _)) => {}
+                Some((JpegMarker::RST(_), _)) => restart_markers += 1,
+                Some((marker, marker_offset)) => {
+                    self.pending = Some((marker, marker_offset));
+                    return Ok(EntropyData {
+                        offset,
+                        length: marker_offset - offset,
+                        restart_markers,
+                    });
+                }
+                None => {}
+            }
+        }
+    }
+
+    /// Read the first two bytes of the stream, which must be the Start of Image marker. Returns the
+    /// marker they form instead, if any, so that anything else is rejected without reading further.
+    pub(crate) fn read_start(&mut self) -> io::Result<Option<JpegMarker>> {
+        let mut start = Vec::with_capacity(2);
+        if !self.read_payload(2, &mut start)? || start[0] != 0xFF {
+            return Ok(None);
+        }
+        match JpegMarker::from_u8(start[1]) {
+            JpegMarker::INDICATOR | JpegMarker::None(_) => Ok(None),
+            marker => Ok(Some(marker)),
+        }
+    }
+
+    /// Number of bytes skipped between segments because they were not part of a marker
+    pub fn extraneous_bytes(&self) -> usize {
+        self.extraneous_bytes
+    }
+
+    fn read_segment(&mut self) -> Result<Option<Segment>> {
+        let next = match self.pending.take() {
+            Some(pending) => Some(pending),
+            None => self.next_marker().map_err(|source| JpegError::Io {
+                offset: self.offset(),
+                marker: None,
+                source,
+            })?,
+        };
+        let Some((marker, offset)) = next else {
+            return Ok(None);
+        };
+        let io_error = |source| JpegError::Io {
+            offset,
+            marker: Some(marker),
+            source,
+        };
+
+        let mut segment = Segment {
+            marker,
+            offset,
+            length: 0,
+            payload: Vec::new(),
+            entropy: None,
+        };
+        if marker.has_length() {
+            let mut length = [0u8; 2];
+            for byte in length.iter_mut() {
+                *byte = self
+                    .next_byte()
+                    .map_err(io_error)?
+                    .ok_or(JpegError::Truncated { offset, marker })?;
+            }
+            let length = u16::from_be_bytes(length) as usize;
+            if length < 2 {
+                return Err(JpegError::BadLength {
+                    offset,
+                    marker,
+                    length,
+                });
+            }
+            segment.length = length - 2;
+            if let Some(stream_len) = self.stream_len {
+                if self.offset() + segment.length > stream_len {
+                    return Err(JpegError::BadLength {
+                        offset,
+                        marker,
+                        length,
+                    });
+                }
+            }
+
+            let retained = segment.length.min((self.retain)(marker));
+            if !self
+                .read_payload(retained, &mut segment.payload)
+                .map_err(io_error)?
+                || !self.skip(segment.length - retained).map_err(io_error)?
+            {
+                return Err(JpegError::Truncated { offset, marker });
+            }
+        }
+        if marker == JpegMarker::SOS {
+            segment.entropy = Some(self.read_entropy_data().map_err(io_error)?);
+        }
+        Ok(Some(segment))
+    }
+}
+
+impl<R: Read> Iterator for SegmentReader<R> {
+    type Item = Result<Segment>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let segment = self.read_segment();
+        match &segment {
+            Ok(Some(s)) if s.marker != JpegMarker::END => {}
+            _ => self.done = true,
+        }
+        segment.transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Hands out `data` in chunks of the given sizes, repeating them until the data runs out
+    struct Chunked<'a> {
+        data: &'a [u8],
+        sizes: std::iter::Cycle<std::slice::Iter<'a, usize>>,
+    }
+
+    impl<'a> Chunked<'a> {
+        fn new(data: &'a [u8], sizes: &'a [usize]) -> Chunked<'a> {
+            Chunked {
+                data,
+                sizes: sizes.iter().cycle(),
+            }
+        }
+    }
+
+    impl Read for Chunked<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let amnt = (*self.sizes.next().unwrap())
+                .min(buf.len())
+                .min(self.data.len());
+            buf[..amnt].copy_from_slice(&self.data[..amnt]);
+            self.data = &self.data[amnt..];
+            Ok(amnt)
+        }
+    }
+
+    /// A stream with fill bytes before markers, extraneous bytes between segments, a payload
+    /// longer than the read buffer, and entropy-coded data holding stuffed bytes and RSTn markers
+    fn stream() -> Vec<u8> {
+        let mut data = vec![0xFF, 0xD8];
+        data.extend([0xFF, 0xFF, 0xFF, 0xE0, 0x00, 0x07]);
+        data.extend(b"JFIF\0");
+        data.extend([0x12, 0x34]);
+        data.extend([0xFF, 0xE1, 0x23, 0x2A]);
+        data.extend((0..9000).map(|n| n as u8));
+        data.extend([0xFF, 0xDA, 0x00, 0x08, 0x01, 0x01, 0x00, 0x00, 0x3F, 0x00]);
+        data.extend([
+            0x12, 0xFF, 0x00, 0x34, 0xFF, 0xD0, 0x56, 0xFF, 0xFF, 0xD1, 0x78,
+        ]);
+        data.extend([0xFF, 0x00]);
+        data.extend([0xFF, 0xFF, 0xFE, 0x00, 0x04, b'h', b'i']);
+        data.extend([0xFF, 0xD9]);
+        data
+    }
+
+    /// Marker, offset, length, retained payload and entropy-coded data of each segment
+    type Summary = (JpegMarker, usize, usize, Vec<u8>, Option<EntropyData>);
+
+    fn summarise(reader: &mut SegmentReader<impl Read>) -> Vec<Summary> {
+        reader
+            .map(|segment| {
+                let s = segment.unwrap();
+                (s.marker, s.offset, s.length, s.payload, s.entropy)
+            })
+            .collect()
+    }
+
+    fn expected(app1: Vec<u8>) -> Vec<Summary> {
+        let entropy = EntropyData {
+            offset: 9029,
+            length: 14,
+            restart_markers: 2,
+        };
+        vec![
+            (JpegMarker::START, 0, 0, vec![], None),
+            (JpegMarker::APP(0xE0), 4, 5, b"JFIF\0".to_vec(), None),
+            (JpegMarker::APP(0xE1), 15, 9000, app1, None),
+            (
+                JpegMarker::SOS,
+                9019,
+                6,
+                vec![0x01, 0x01, 0x00, 0x00, 0x3F, 0x00],
+                Some(entropy),
+            ),
+            (JpegMarker::COM, 9043, 2, b"hi".to_vec(), None),
+            (JpegMarker::END, 9049, 0, vec![], None),
+        ]
+    }
+
+    #[test]
+    fn segments_straddling_reads_are_reassembled() {
+        let data = stream();
+        let app1: Vec<u8> = (0..9000).map(|n| n as u8).collect();
+        for sizes in [&[1][..], &[2, 3], &[3, 7, 5], &[13], &[5, 1, 8191]] {
+            let mut reader = SegmentReader::new(Chunked::new(&data, sizes));
+            let segments = summarise(&mut reader);
+            assert_eq!(segments, expected(app1.clone()), "chunks of {:?}", sizes);
+            assert_eq!(reader.extraneous_bytes(), 2, "chunks of {:?}", sizes);
+            assert_eq!(reader.offset(), data.len(), "chunks of {:?}", sizes);
+        }
+    }
+
+    #[test]
+    fn skipped_payloads_straddle_reads() {
+        let data = stream();
+        for sizes in [&[1][..], &[3, 7, 5]] {
+            let mut reader =
+                SegmentReader::new(Chunked::new(&data, sizes)).with_retain(|marker| match marker {
+                    JpegMarker::APP(_) => 4,
+                    _ => usize::MAX,
+                });
+            let mut expected = expected(vec![0, 1, 2, 3]);
+            expected[1].3.truncate(4);
+            assert_eq!(summarise(&mut reader), expected, "chunks of {:?}", sizes);
+            assert_eq!(reader.offset(), data.len(), "chunks of {:?}", sizes);
+        }
+    }
+
+    #[test]
+    fn truncated_payload_is_an_error() {
+        let data = stream();
+        let mut reader = SegmentReader::new(Chunked::new(&data[..5000], &[3, 7, 5]));
+        assert!(matches!(
+            reader.nth(2),
+            Some(Err(JpegError::Truncated {
+                offset: 15,
+                marker: JpegMarker::APP(0xE1)
+            }))
+        ));
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn streams_not_starting_with_soi_are_rejected_at_once() {
+        assert!(matches!(
+            crate::parse_jpeg(&b"hello"[..]),
+            Err(JpegError::NotJpeg {
+                offset: 0,
+                marker: None
+            })
+        ));
+        // The APP0 segment without the Start of Image before it
+        assert!(matches!(
+            crate::parse_jpeg(&stream()[4..]),
+            Err(JpegError::NotJpeg {
+                offset: 0,
+                marker: Some(JpegMarker::APP(0xE0))
+            })
+        ));
+
+        // Nothing beyond the first two bytes is read
+        let data = vec![0u8; 1 << 20];
+        let mut chunked = Chunked::new(&data, &[2]);
+        assert!(crate::parse_jpeg(&mut chunked).is_err());
+        assert_eq!(chunked.data.len(), data.len() - 2);
+    }
+}
@@ -0,0 +1,207 @@
+use crate::{
+    bitreader::BitReader,
+    decoder::{self, ScanLayout},
+    error::{EntropyErrorKind, JpegError, Result},
+    frame::ImgProps,
+    huffman::HuffmanDecoder,
+    idct::idct_block_scaled,
+    image::{Conversion, PixelFormat},
+    marker::JpegMarker,
+    upsample::Plane,
+};
+
+/// Rows of pixels decoded one at a time from the single scan of a sequential image, as returned
+/// by `Decoder::scanlines`. Each row holds `width` pixels laid out as described by `format`.
+pub struct Scanlines<'a> {
+    reader: BitReader<'a>,
+    props: ImgProps,
+    conversion: Conversion,
+    layout: ScanLayout,
+    /// DC table, AC table and quantization table (in natural order) of each scan component
+    tables: Vec<(HuffmanDecoder, HuffmanDecoder, [u16; 64])>,
+    restart_interval: usize,
+    predictions: [i32; 4],
+    /// Samples of each component, holding only the rows needed for the next output row
+    planes: Vec<Plane<u8>>,
+    /// Size of the decoded samples of each block of each component
+    block_sizes: Vec<usize>,
+    /// Number of MCU rows decoded so far
+    decoded: usize,
+    /// The next output row
+    y: usize,
+    /// Upsampled samples of each component for the current output row
+    rows: Vec<Vec<u8>>,
+    failed: bool,
+}
+
+impl<'a> Scanlines<'a> {
+    /// Prepare to decode a scan containing every component of the frame, starting at the
+    /// beginning of its entropy-coded data
+    pub(crate) fn new(
+        reader: BitReader<'a>,
+        props: ImgProps,
+        conversion: Conversion,
+        layout: ScanLayout,
+        tables: Vec<(HuffmanDecoder, HuffmanDecoder, [u16; 64])>,
+        restart_interval: usize,
+    ) -> Scanlines<'a> {
+        let block_sizes: Vec<usize> = (0..props.components.len())
+            .map(|idx| conversion.scale.component_block_size(&props, idx))
+            .collect();
+        let planes = block_sizes
+            .iter()
+            .enumerate()
+            .map(|(idx, size)| {
+                let stride = props.blocks_per_line(idx) * size;
+                conversion.plane(&props, idx, Vec::new(), stride)
+            })
+            .collect();
+        let width = conversion.size(&props).0;
+        Scanlines {
+            reader,
+            rows: vec![vec![0; width]; props.components.len()],
+            props,
+            conversion,
+            layout,
+            tables,
+            restart_interval,
+            predictions: [0; 4],
+            planes,
+            block_sizes,
+            decoded: 0,
+            y: 0,
+            failed: false,
+        }
+    }
+
+    /// Width in pixels of each row
+    pub fn width(&self) -> usize {
+        self.conversion.size(&self.props).0
+    }
+
+    /// Number of rows in the image
+    pub fn height(&self) -> usize {
+        self.conversion.size(&self.props).1
+    }
+
+    pub fn format(&self) -> PixelFormat {
+        PixelFormat::with_channels(self.conversion.channels(), false)
+    }
+
+    /// Number of rows of samples of the component at `idx` in each MCU row
+    fn strip_height(&self, idx: usize) -> usize {
+        self.props.block_rows(idx) / self.props.mcu_rows() * self.block_sizes[idx]
+    }
+
+    /// Decode the next row of MCUs, transforming its blocks onto the end of each plane
+    fn decode_mcu_row(&mut self) -> Result<()> {
+        for idx in 0..self.planes.len() {
+            let len =
+                self.planes[idx].data.len() + self.strip_height(idx) * self.planes[idx].stride;
+            self.planes[idx].data.resize(len, 0);
+        }
+        let entropy_error = |reader: &BitReader, kind| JpegError::Entropy {
+            offset: reader.position(),
+            marker: JpegMarker::SOS,
+            kind,
+        };
+        let mcus_per_line = self.layout.mcus_per_line;
+        for mcu in self.decoded * mcus_per_line..(self.decoded + 1) * mcus_per_line {
+            if self.restart_interval > 0 && mcu > 0 && mcu % self.restart_interval == 0 {
+                decoder::expect_restart(self.reader.restart(), mcu, self.restart_interval)
+                    .map_err(|kind| entropy_error(&self.reader, kind))?;
+                self.predictions = [0; 4];
+            }
+
+            for (scan_idx, x, y) in self.layout.blocks(mcu) {
+                let (dc, ac, quant) = &self.tables[scan_idx];
+                let mut block = [0i16; 64];
+                decoder::decode_block(
+                    &mut self.reader,
+                    Some(dc),
+                    Some(ac),
+                    &mut self.predictions[scan_idx],
+                    &mut block,
+                )
+                .map_err(|kind| entropy_error(&self.reader, kind))?;
+                let idx = self.layout.components[scan_idx];
+                let (plane, size) = (&mut self.planes[idx], self.block_sizes[idx]);
+                let start = (y * size - plane.first_row) * plane.stride + x * size;
+                idct_block_scaled(
+                    &block,
+                    quant,
+                    &mut plane.data[start..],
+                    plane.stride,
+                    8,
+                    size,
+                );
+            }
+            if self.reader.overrun() {
+                return Err(entropy_error(
+                    &self.reader,
+                    self.reader.marker().map_or(
+                        EntropyErrorKind::PrematureEnd,
+                        EntropyErrorKind::UnexpectedMarker,
+                    ),
+                ));
+            }
+        }
+        self.decoded += 1;
+        Ok(())
+    }
+}
+
+impl Iterator for Scanlines<'_> {
+    type Item = Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed || self.y >= self.height() {
+            return None;
+        }
+        // Upsampling interpolates between neighbouring rows, so the first row of the next
+        // MCU row and the last row of the previous one are needed as well as the current one
+        let mcu_row = self.y / (self.props.mcu_height() / self.conversion.scale.denominator());
+        while self.decoded < (mcu_row + 2).min(self.layout.mcu_rows) {
+            if let Err(e) = self.decode_mcu_row() {
+                self.failed = true;
+                return Some(Err(e));
+            }
+        }
+        for idx in 0..self.planes.len() {
+            let keep = (mcu_row * self.strip_height(idx)).saturating_sub(1);
+            let plane = &mut self.planes[idx];
+            if keep > plane.first_row {
+                plane.data.drain(..(keep - plane.first_row) * plane.stride);
+                plane.first_row = keep;
+            }
+        }
+
+        for (plane, row) in self.planes.iter().zip(self.rows.iter_mut()) {
+            plane.upsample_row(self.y, row);
+        }
+        let mut out = vec![0; self.width() * self.conversion.channels()];
+        self.conversion.convert_row(&self.rows, &mut out, 8);
+        self.y += 1;
+        Some(Ok(out))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Decoder;
+
+    #[test]
+    fn only_two_mcu_rows_and_a_row_of_samples_are_held() {
+        let data = include_bytes!("tests/data/big-r7.jpg");
+        let mut scanlines = Decoder::new(data).scanlines().unwrap();
+        assert_eq!(scanlines.layout.mcu_rows, 8);
+        while let Some(row) = scanlines.next() {
+            row.unwrap();
+            for (idx, plane) in scanlines.planes.iter().enumerate() {
+                let held = plane.data.len() / plane.stride;
+                assert!(held <= 2 * scanlines.strip_height(idx) + 1, "{} rows", held);
+            }
+        }
+        assert_eq!(scanlines.y, 120);
+    }
+}
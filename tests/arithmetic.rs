@@ -0,0 +1,74 @@
+//! Decoding arithmetic coded JPEGs: the 61x45 scene of scene.jpg transcoded by jpegtran to
+//! arithmetic coding without changing a coefficient, once as a sequential image with a restart
+//! marker every 2 MCUs (scene-arithmetic.jpg) and once with libjpeg's default progressive script
+//! and conditioning of L = 1, U = 4 and Kx = 2 rather than the defaults
+//! (scene-arithmetic-progressive.jpg). libjpeg decodes all three files to the same pixels.
+
+mod common;
+
+use common::Pnm;
+use jpeg_parser::{parse_jpeg, Decoder, TableClass};
+
+const SCENE: &[u8] = include_bytes!("data/scene.jpg");
+const SEQUENTIAL: &[u8] = include_bytes!("data/scene-arithmetic.jpg");
+const PROGRESSIVE: &[u8] = include_bytes!("data/scene-arithmetic-progressive.jpg");
+
+#[test]
+fn conditioning_tables_are_read() {
+    let info = parse_jpeg(SEQUENTIAL).unwrap();
+    assert_eq!(info.frames[0].0, 0xC9);
+    assert_eq!(info.restart_interval, 2);
+    let tables: Vec<_> = info
+        .conditioning_tables
+        .iter()
+        .map(|t| (t.class, t.id, t.value))
+        .collect();
+    assert_eq!(
+        tables,
+        [
+            (TableClass::DC, 0, 0x10),
+            (TableClass::AC, 0, 5),
+            (TableClass::DC, 1, 0x10),
+            (TableClass::AC, 1, 5),
+        ]
+    );
+
+    let info = parse_jpeg(PROGRESSIVE).unwrap();
+    assert_eq!(info.frames[0].0, 0xCA);
+    assert_eq!(info.scans.len(), 10);
+    // The DC bounds are given before the first scan, and the AC threshold before each AC scan
+    let tables = &info.conditioning_tables;
+    assert_eq!(tables.len(), 10);
+    for table in &tables[..2] {
+        assert_eq!((table.class, table.dc_bounds()), (TableClass::DC, (1, 4)));
+    }
+    for table in &tables[2..] {
+        assert_eq!((table.class, table.value), (TableClass::AC, 2));
+    }
+}
+
+#[test]
+fn coefficients_match_the_huffman_coded_image() {
+    let expected = Decoder::new(SCENE).coefficients().unwrap();
+    for data in [SEQUENTIAL, PROGRESSIVE] {
+        let actual = Decoder::new(data).coefficients().unwrap();
+        assert_eq!(actual.components.len(), expected.components.len());
+        for (actual, expected) in actual.components.iter().zip(&expected.components) {
+            assert_eq!(actual.quant_table, expected.quant_table);
+            assert_eq!(actual.blocks, expected.blocks, "component {}", actual.id);
+        }
+    }
+}
+
+#[test]
+fn pixels_match_libjpeg() {
+    let expected = Pnm::read(include_bytes!("data/scene.ppm"));
+    for data in [SEQUENTIAL, PROGRESSIVE] {
+        let image = Decoder::new(data).decode().unwrap();
+        assert_eq!(
+            (image.width, image.height),
+            (expected.width, expected.height)
+        );
+        assert_eq!(image.data, expected.bytes());
+    }
+}
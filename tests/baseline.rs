@@ -0,0 +1,82 @@
+//! Decoding a small baseline JPEG written by libjpeg: a 20x12 test card at quality 75 with 4:2:0
+//! chroma, so that both dimensions end part way through an MCU. The expected coefficients are
+//! those libjpeg reads from the file, and the expected pixels libjpeg's own decoding of it (with
+//! its default integer IDCT and fancy upsampling) at full and half size.
+
+mod common;
+
+use common::Pnm;
+use jpeg_parser::{Decoder, PixelFormat, Scale};
+
+const CARD: &[u8] = include_bytes!("data/card.jpg");
+
+#[test]
+fn coefficients_match_libjpeg() {
+    let coefficients = Decoder::new(CARD).coefficients().unwrap();
+    let components = &coefficients.components;
+    assert_eq!(components.len(), 3);
+    // Luma is stored to the end of the second MCU column, one block beyond the image
+    assert_eq!(
+        (components[0].blocks_per_line, components[0].block_rows),
+        (4, 2)
+    );
+    assert_eq!(
+        (components[1].blocks_per_line, components[1].block_rows),
+        (2, 1)
+    );
+
+    let dc = |idx: usize, blocks: &[usize]| -> Vec<i16> {
+        blocks
+            .iter()
+            .map(|n| components[idx].blocks[*n][0])
+            .collect()
+    };
+    assert_eq!(dc(0, &[0, 1, 2, 4, 5, 6]), [-40, -33, -6, 34, 61, 88]);
+    assert_eq!(dc(1, &[0, 1]), [-14, -69]);
+    assert_eq!(dc(2, &[0, 1]), [-18, 51]);
+
+    #[rustfmt::skip]
+    let luma: [i16; 64] = [
+        -40, -8, -3, 0, 0, 0, 0, 0,
+        -37, -5, 2, 0, 0, 0, 0, 0,
+        -2, 2, -1, 0, 0, 0, 0, 0,
+        -3, 0, 0, 1, 0, 0, 0, 0,
+        0, 0, 0, 0, 0, 0, 0, 0,
+        -1, 0, 0, 0, 0, 0, 0, 0,
+        0, 0, 0, 0, 0, 0, 0, 0,
+        0, 0, 0, 0, 0, 0, 0, 0,
+    ];
+    assert_eq!(components[0].blocks[0], luma);
+    #[rustfmt::skip]
+    let cb: [i16; 64] = [
+        -14, 37, 5, 2, 1, 0, 0, 0,
+        47, 9, -7, -2, 0, -1, 0, 0,
+        7, -2, -3, 0, 1, 0, 0, 0,
+        0, -1, -1, 1, 0, 0, 0, 0,
+        1, 0, 0, 1, 0, -1, 0, 0,
+        0, 0, 0, 0, 0, 0, 1, 0,
+        0, 0, 0, 0, 0, 0, 0, 0,
+        0, 0, 0, 0, 0, 0, 0, -1,
+    ];
+    assert_eq!(components[1].blocks[0], cb);
+}
+
+#[test]
+fn pixels_match_libjpeg() {
+    let image = Decoder::new(CARD).decode().unwrap();
+    let expected = Pnm::read(include_bytes!("data/card.ppm"));
+    assert_eq!(
+        (image.width, image.height),
+        (expected.width, expected.height)
+    );
+    assert_eq!(image.format, PixelFormat::Rgb8);
+    assert_eq!(image.data, expected.bytes());
+
+    let image = Decoder::new(CARD).with_scale(Scale::Half).decode().unwrap();
+    let expected = Pnm::read(include_bytes!("data/card-half.ppm"));
+    assert_eq!(
+        (image.width, image.height),
+        (expected.width, expected.height)
+    );
+    assert_eq!(image.data, expected.bytes());
+}
@@ -0,0 +1,150 @@
+//! Finding where the entropy-coded data of a file first fails: the 20x12 card written by libjpeg
+//! without restart markers (card.jpg), two 16x16 MCUs of 4:2:0 YCbCr, and the 200x120 image with
+//! a restart marker every 7 MCUs (big-r7.jpg). Corrupt bytes are set to one bits, which no
+//! Huffman code begins with, so decoding fails at the first code read from them.
+
+mod common;
+
+use std::{fs, path::PathBuf, process::Command};
+
+use common::{corrupt, restart_intervals};
+use jpeg_parser::{Decoder, EntropyErrorKind, Fault};
+
+const CARD: &[u8] = include_bytes!("data/card.jpg");
+const SEVENS: &[u8] = include_bytes!("data/big-r7.jpg");
+
+fn fault(
+    offset: usize,
+    (mcu_row, mcu_column): (usize, usize),
+    component: u8,
+    kind: EntropyErrorKind,
+) -> Option<Fault> {
+    Some(Fault {
+        scan: 0,
+        offset,
+        mcu_row,
+        mcu_column,
+        component: Some(component),
+        kind,
+    })
+}
+
+#[test]
+fn intact_files_have_no_fault() {
+    for data in [CARD, SEVENS] {
+        assert_eq!(Decoder::new(data).check().unwrap(), None);
+    }
+}
+
+#[test]
+fn corrupt_data_is_located() {
+    // card.jpg has a single interval, without any restart markers
+    let card = 623..727;
+    assert_eq!(restart_intervals(CARD), std::slice::from_ref(&card));
+    let sevens = restart_intervals(SEVENS);
+    for (data, expected) in [
+        // The first luma block
+        (
+            corrupt(CARD, card.clone()),
+            fault(623, (0, 0), 1, EntropyErrorKind::InvalidCode),
+        ),
+        // The Cb block of the first MCU, after its four luma blocks
+        (
+            corrupt(CARD, card.start + 60..card.end),
+            fault(683, (0, 0), 2, EntropyErrorKind::InvalidCode),
+        ),
+        // The interval of MCUs 35 to 41, which begins at column 9 of MCU row 2. The code being
+        // read when the corruption starts began in the byte before it.
+        (
+            corrupt(SEVENS, sevens[5].start + 20..sevens[5].end),
+            fault(1685, (2, 9), 1, EntropyErrorKind::InvalidCode),
+        ),
+        (
+            corrupt(SEVENS, sevens[14].clone()),
+            fault(4107, (7, 7), 1, EntropyErrorKind::InvalidCode),
+        ),
+    ] {
+        assert_eq!(Decoder::new(&data).check().unwrap(), expected);
+    }
+}
+
+#[test]
+fn truncated_data_ends_prematurely_at_the_end_of_the_file() {
+    for (data, len, expected) in [
+        (
+            CARD,
+            623,
+            fault(623, (0, 0), 1, EntropyErrorKind::PrematureEnd),
+        ),
+        (
+            CARD,
+            663,
+            fault(663, (0, 0), 1, EntropyErrorKind::PrematureEnd),
+        ),
+        // The last byte of the Cr block which ends the second MCU
+        (
+            CARD,
+            726,
+            fault(726, (0, 1), 3, EntropyErrorKind::PrematureEnd),
+        ),
+        // Part way through the interval of MCUs 42 to 48
+        (
+            SEVENS,
+            2000,
+            fault(2000, (3, 7), 1, EntropyErrorKind::PrematureEnd),
+        ),
+    ] {
+        assert_eq!(
+            Decoder::new(&data[..len]).check().unwrap(),
+            expected,
+            "{}",
+            len
+        );
+        // An End of Image marker straight after the data makes no difference
+        let mut ended = data[..len].to_vec();
+        ended.extend([0xFF, 0xD9]);
+        assert_eq!(Decoder::new(&ended).check().unwrap(), expected, "{}", len);
+    }
+}
+
+#[test]
+fn cli_exits_with_the_worst_status() {
+    let dir = std::env::temp_dir().join(format!("jpeg-parser-check-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let write = |name: &str, data: &[u8]| -> PathBuf {
+        let path = dir.join(name);
+        fs::write(&path, data).unwrap();
+        path
+    };
+    let intact = write("intact.jpg", CARD);
+    let corrupt = write("corrupt.jpg", &corrupt(CARD, 623..727));
+    let unreadable = write("unreadable.jpg", b"hello");
+
+    let check = |files: &[&PathBuf]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_jpeg-parser"))
+            .arg("--check")
+            .args(files)
+            .output()
+            .unwrap();
+        (
+            output.status.code(),
+            String::from_utf8(output.stdout).unwrap(),
+        )
+    };
+    let (status, stdout) = check(&[&intact]);
+    assert_eq!(status, Some(0));
+    assert!(stdout.ends_with("intact.jpg: ok\n"), "{}", stdout);
+    let (status, stdout) = check(&[&intact, &corrupt]);
+    assert_eq!(status, Some(2));
+    assert!(
+        stdout.ends_with(
+            "corrupt.jpg: corrupt at offset 623 (scan 0, MCU row 0 column 0, component 1): \
+             invalid Huffman code\n"
+        ),
+        "{}",
+        stdout
+    );
+    assert_eq!(check(&[&unreadable, &corrupt, &intact]).0, Some(3));
+    assert_eq!(check(&[&dir.join("missing.jpg")]).0, Some(3));
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,65 @@
+//! Decoding four component JPEGs written by libjpeg with an Adobe APP14 segment: the 61x45
+//! scene as inverted CMYK without a transform (cmyk.jpg), and as YCCK with Y and K sampled 2x2
+//! (ycck.jpg). The expected samples are libjpeg's own decoding of each file, which leaves the
+//! samples inverted as Adobe applications store them, so that 255 is no ink.
+
+mod common;
+
+use common::{without_segment, Pnm};
+use jpeg_parser::{parse_jpeg, AdobeTransform, Decoder, PixelFormat};
+
+const CMYK: &[u8] = include_bytes!("data/cmyk.jpg");
+const YCCK: &[u8] = include_bytes!("data/ycck.jpg");
+
+/// The samples of `image` with Adobe's inversion undone, so that 0 is no ink
+fn uninverted(image: &Pnm) -> Vec<u8> {
+    image.bytes().iter().map(|s| 255 - s).collect()
+}
+
+#[test]
+fn adobe_segments_are_read() {
+    for (data, transform) in [
+        (CMYK, AdobeTransform::Unknown),
+        (YCCK, AdobeTransform::Ycck),
+    ] {
+        let adobe = parse_jpeg(data).unwrap().metadata.adobe.unwrap();
+        assert_eq!((adobe.version, adobe.flags0, adobe.flags1), (100, 0, 0));
+        assert_eq!(adobe.transform, transform);
+    }
+}
+
+#[test]
+fn pixels_match_libjpeg() {
+    let cmyk = Pnm::read(include_bytes!("data/cmyk.pam"));
+    let ycck = Pnm::read(include_bytes!("data/ycck.pam"));
+    for (data, expected) in [(CMYK, &cmyk), (YCCK, &ycck)] {
+        assert_eq!(expected.channels, 4);
+        let image = Decoder::new(data).decode().unwrap();
+        assert_eq!(
+            (image.width, image.height),
+            (expected.width, expected.height)
+        );
+        assert_eq!(image.format, PixelFormat::Cmyk8);
+        assert_eq!(image.data, uninverted(expected));
+    }
+
+    // Without an Adobe segment the samples are taken as they are stored
+    let image = Decoder::new(&without_segment(CMYK, 0xEE)).decode().unwrap();
+    assert_eq!(image.data, cmyk.bytes());
+}
+
+#[test]
+fn conversion_to_rgb_scales_by_black() {
+    let expected = Pnm::read(include_bytes!("data/ycck.pam"));
+    let image = Decoder::new(YCCK).with_cmyk_to_rgb(true).decode().unwrap();
+    assert_eq!(image.format, PixelFormat::Rgb8);
+    let rgb: Vec<u8> = expected
+        .bytes()
+        .chunks_exact(4)
+        .flat_map(|cmyk| {
+            let k = cmyk[3] as u32;
+            [0, 1, 2].map(|idx| ((cmyk[idx] as u32 * k + 127) / 255) as u8)
+        })
+        .collect();
+    assert_eq!(image.data, rgb);
+}
@@ -0,0 +1,137 @@
+//! Inferring the colour space of JPEGs written by libjpeg: the 61x45 scene as JFIF YCbCr
+//! (scene.jpg), greyscale (grey.jpg), RGB with components 'R', 'G' and 'B' and an Adobe segment
+//! without a transform (rgb.jpg), CMYK (cmyk.jpg) and YCCK (ycck.jpg). The expected pixels are
+//! libjpeg's own decoding of each file. lossless-12.jpg is a lossless image of three components
+//! without any JFIF or Adobe segment, whose samples are in lossless-12.ppm.
+
+mod common;
+
+use common::{with_segment, without_segment, Pnm};
+use jpeg_parser::{parse_jpeg, ColorSpace, Decoder, Image16, PixelFormat};
+
+const SCENE: &[u8] = include_bytes!("data/scene.jpg");
+const GREY: &[u8] = include_bytes!("data/grey.jpg");
+const RGB: &[u8] = include_bytes!("data/rgb.jpg");
+const CMYK: &[u8] = include_bytes!("data/cmyk.jpg");
+const YCCK: &[u8] = include_bytes!("data/ycck.jpg");
+const LOSSLESS: &[u8] = include_bytes!("data/lossless-12.jpg");
+
+fn color_space(data: &[u8]) -> Option<ColorSpace> {
+    parse_jpeg(data).unwrap().color_space()
+}
+
+#[test]
+fn color_spaces_are_detected() {
+    assert_eq!(color_space(SCENE), Some(ColorSpace::YCbCr));
+    assert_eq!(color_space(GREY), Some(ColorSpace::Grayscale));
+    assert_eq!(color_space(RGB), Some(ColorSpace::Rgb));
+    assert_eq!(color_space(CMYK), Some(ColorSpace::Cmyk));
+    assert_eq!(color_space(YCCK), Some(ColorSpace::Ycck));
+}
+
+#[test]
+fn component_identifiers_decide_without_jfif_or_adobe_segments() {
+    // rgb.jpg names its components 'R', 'G' and 'B', scene.jpg numbers them 1 to 3
+    assert_eq!(
+        color_space(&without_segment(RGB, 0xEE)),
+        Some(ColorSpace::Rgb)
+    );
+    assert_eq!(
+        color_space(&without_segment(SCENE, 0xE0)),
+        Some(ColorSpace::YCbCr)
+    );
+    // Four components are CMYK unless an Adobe segment says otherwise
+    assert_eq!(
+        color_space(&without_segment(YCCK, 0xEE)),
+        Some(ColorSpace::Cmyk)
+    );
+}
+
+#[test]
+fn pixels_match_libjpeg() {
+    let expected = Pnm::read(include_bytes!("data/grey.pgm"));
+    let image = Decoder::new(GREY).decode().unwrap();
+    assert_eq!(
+        (image.width, image.height),
+        (expected.width, expected.height)
+    );
+    assert_eq!(image.format, PixelFormat::Gray8);
+    assert_eq!(image.data, expected.bytes());
+
+    // RGB components are not converted, with or without the Adobe segment
+    let expected = Pnm::read(include_bytes!("data/rgb.ppm"));
+    for data in [RGB.to_vec(), without_segment(RGB, 0xEE)] {
+        let image = Decoder::new(&data).decode().unwrap();
+        assert_eq!(image.format, PixelFormat::Rgb8);
+        assert_eq!(image.data, expected.bytes());
+    }
+}
+
+/// Check that `image` holds the JFIF conversion to RGB of `samples`, which are YCbCr of `precision` bits
+fn assert_converted(image: &Image16, samples: &[u16], precision: usize) {
+    assert_eq!(
+        (image.format, image.bit_depth),
+        (PixelFormat::Rgb16, precision)
+    );
+    let max = ((1u32 << precision) - 1) as f64;
+    let center = (1u32 << (precision - 1)) as f64;
+    for (pixel, ycbcr) in image.data.chunks_exact(3).zip(samples.chunks_exact(3)) {
+        let [y, cb, cr] = [0, 1, 2].map(|idx| ycbcr[idx] as f64);
+        let (cb, cr) = (cb - center, cr - center);
+        let rgb = [
+            y + 1.402 * cr,
+            y - 0.344136 * cb - 0.714136 * cr,
+            y + 1.772 * cb,
+        ];
+        for (sample, expected) in pixel.iter().zip(rgb) {
+            let expected = expected.clamp(0.0, max);
+            assert!((*sample as f64 - expected).abs() <= 1.5, "{:?}", ycbcr);
+        }
+    }
+}
+
+#[test]
+fn lossless_frames_are_rgb_unless_a_segment_says_otherwise() {
+    assert_eq!(color_space(LOSSLESS), Some(ColorSpace::Rgb));
+    let image = Decoder::new(LOSSLESS).decode_16().unwrap();
+    let samples = Pnm::read(include_bytes!("data/lossless-12.ppm")).samples;
+    assert_eq!(image.data, samples);
+
+    // An Adobe segment with transform 1 marks the samples as YCbCr, which are then converted
+    let adobe = with_segment(
+        LOSSLESS,
+        &[
+            0xFF, 0xEE, 0, 14, b'A', b'd', b'o', b'b', b'e', 0, 100, 0, 0, 0, 0, 1,
+        ],
+    );
+    assert_eq!(color_space(&adobe), Some(ColorSpace::YCbCr));
+    assert_converted(&Decoder::new(&adobe).decode_16().unwrap(), &samples, 12);
+}
+
+#[test]
+fn lossless_16_bit_ycbcr_is_converted() {
+    // lossless-12.jpg codes 10 bit samples with a point transform of 2. Marking it as 16 bit with
+    // a point transform of 6 leaves the coded samples alone, and shifts each up by another 4 bits.
+    let mut data = LOSSLESS.to_vec();
+    let sof = data.windows(2).position(|w| w == [0xFF, 0xC3]).unwrap();
+    assert_eq!(data[sof + 4], 12);
+    data[sof + 4] = 16;
+    let sos = data.windows(2).position(|w| w == [0xFF, 0xDA]).unwrap();
+    assert_eq!(data[sos + 13], 2);
+    data[sos + 13] = 6;
+    let samples: Vec<u16> = Pnm::read(include_bytes!("data/lossless-12.ppm"))
+        .samples
+        .iter()
+        .map(|s| s << 4)
+        .collect();
+    assert_eq!(Decoder::new(&data).decode_16().unwrap().data, samples);
+
+    let jfif = with_segment(
+        &data,
+        &[
+            0xFF, 0xE0, 0, 16, b'J', b'F', b'I', b'F', 0, 1, 1, 0, 0, 1, 0, 1, 0, 0,
+        ],
+    );
+    assert_eq!(color_space(&jfif), Some(ColorSpace::YCbCr));
+    assert_converted(&Decoder::new(&jfif).decode_16().unwrap(), &samples, 16);
+}
@@ -0,0 +1,138 @@
+//! Helpers shared by the integration tests
+
+#![allow(dead_code)]
+
+/// A binary PGM (P5), PPM (P6) or PAM (P7) image
+pub struct Pnm {
+    pub width: usize,
+    pub height: usize,
+    pub channels: usize,
+    pub max: u32,
+    /// Samples in row-major order, interleaved by channel
+    pub samples: Vec<u16>,
+}
+
+impl Pnm {
+    pub fn read(data: &[u8]) -> Pnm {
+        let number = |field: &[u8]| std::str::from_utf8(field).unwrap().parse().unwrap();
+        let (width, height, channels, max, pixels);
+        if let Some(rest) = data.strip_prefix(b"P7\n") {
+            // KEY VALUE lines up to ENDHDR
+            let end = rest.windows(7).position(|w| w == b"ENDHDR\n").unwrap();
+            let field = |key: &[u8]| {
+                rest[..end]
+                    .split(|&b| b == b'\n')
+                    .find_map(|line| line.strip_prefix(key)?.strip_prefix(b" "))
+                    .map(number)
+                    .unwrap()
+            };
+            (width, height, channels, max) = (
+                field(b"WIDTH"),
+                field(b"HEIGHT"),
+                field(b"DEPTH"),
+                field(b"MAXVAL") as u32,
+            );
+            pixels = &rest[end + 7..];
+        } else {
+            // P5 or P6, width, height and maximum value, each followed by a single whitespace byte
+            let fields: Vec<&[u8]> = data.splitn(5, |b| b.is_ascii_whitespace()).collect();
+            channels = match fields[0] {
+                b"P5" => 1,
+                b"P6" => 3,
+                magic => panic!("unexpected magic number {:?}", magic),
+            };
+            (width, height, max) = (
+                number(fields[1]),
+                number(fields[2]),
+                number(fields[3]) as u32,
+            );
+            pixels = fields[4];
+        }
+
+        let samples: Vec<u16> = if max < 256 {
+            pixels.iter().map(|&s| s as u16).collect()
+        } else {
+            pixels
+                .chunks_exact(2)
+                .map(|s| u16::from_be_bytes([s[0], s[1]]))
+                .collect()
+        };
+        assert_eq!(samples.len(), width * height * channels);
+        Pnm {
+            width,
+            height,
+            channels,
+            max,
+            samples,
+        }
+    }
+
+    /// The samples of an image whose maximum value fits in a byte
+    pub fn bytes(&self) -> Vec<u8> {
+        assert!(self.max < 256);
+        self.samples.iter().map(|&s| s as u8).collect()
+    }
+}
+
+/// A copy of the JPEG `data` with every segment before the first scan which has the given
+/// marker (e.g 0xEE for APP14) removed
+pub fn without_segment(data: &[u8], marker: u8) -> Vec<u8> {
+    let mut kept = data[..2].to_vec();
+    let mut pos = 2;
+    while data[pos + 1] != 0xDA {
+        let end = pos + 2 + u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+        if data[pos + 1] != marker {
+            kept.extend(&data[pos..end]);
+        }
+        pos = end;
+    }
+    kept.extend(&data[pos..]);
+    kept
+}
+
+/// A copy of the JPEG `data` with `segment` (its marker, length and payload) inserted straight
+/// after the Start of Image marker
+pub fn with_segment(data: &[u8], segment: &[u8]) -> Vec<u8> {
+    let mut data = data.to_vec();
+    data.splice(2..2, segment.iter().copied());
+    data
+}
+
+/// Byte ranges of the entropy-coded data of each restart interval of the first scan of `data`,
+/// excluding the RSTn markers between them
+pub fn restart_intervals(data: &[u8]) -> Vec<std::ops::Range<usize>> {
+    let sos = data.windows(2).position(|w| w == [0xFF, 0xDA]).unwrap();
+    let mut start = sos + 2 + u16::from_be_bytes([data[sos + 2], data[sos + 3]]) as usize;
+    let mut intervals = Vec::new();
+    let mut pos = start;
+    loop {
+        if data[pos] == 0xFF && data[pos + 1] != 0x00 {
+            intervals.push(start..pos);
+            if !(0xD0..=0xD7).contains(&data[pos + 1]) {
+                return intervals;
+            }
+            start = pos + 2;
+            pos = start;
+        } else if data[pos] == 0xFF {
+            pos += 2;
+        } else {
+            pos += 1;
+        }
+    }
+}
+
+/// A copy of `data` with the bytes in `range` set to one bits, which no Huffman code can begin
+/// with. The 0xFF bytes are stuffed, so a 0x00 follows each of them in place of the next byte,
+/// and an odd byte at the end is 0xFE.
+pub fn corrupt(data: &[u8], range: std::ops::Range<usize>) -> Vec<u8> {
+    let mut data = data.to_vec();
+    let len = range.len();
+    for (idx, byte) in data[range].iter_mut().enumerate() {
+        *byte = match idx % 2 {
+            0 if idx + 1 == len => 0xFE,
+            0 => 0xFF,
+            _ => 0x00,
+        };
+    }
+    data
+}
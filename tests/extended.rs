@@ -0,0 +1,51 @@
+//! Decoding 12 bit JPEGs: scene-12.jpg (SOF1) holds the coefficients of the 61x45 scene in
+//! scene.jpg with quantization tables 16 times larger, so that each coefficient stands for 16
+//! times the value it did at 8 bits, and scene-12-progressive.jpg (SOF2) is the same image with
+//! libjpeg's default progressive script. The expected pixels were computed with libjpeg's 12 bit
+//! integer IDCT, fancy upsampling and colour conversion, by a transcription of them which
+//! reproduces libjpeg's own 8 bit decoding of scene.jpg exactly.
+
+mod common;
+
+use common::Pnm;
+use jpeg_parser::{parse_jpeg, Decoder, JpegError, PixelFormat};
+
+const SCENE: &[u8] = include_bytes!("data/scene.jpg");
+const SEQUENTIAL: &[u8] = include_bytes!("data/scene-12.jpg");
+const PROGRESSIVE: &[u8] = include_bytes!("data/scene-12-progressive.jpg");
+
+#[test]
+fn coefficients_match_the_8_bit_image() {
+    let expected = Decoder::new(SCENE).coefficients().unwrap();
+    for (data, process) in [(SEQUENTIAL, 0xC1), (PROGRESSIVE, 0xC2)] {
+        let info = parse_jpeg(data).unwrap();
+        assert_eq!((info.frames[0].0, info.props.bit_depth), (process, 12));
+
+        let actual = Decoder::new(data).coefficients().unwrap();
+        for (actual, expected) in actual.components.iter().zip(&expected.components) {
+            assert_eq!(actual.blocks, expected.blocks, "component {}", actual.id);
+            let scaled = expected.quant_table.unwrap().map(|q| q * 16);
+            assert_eq!(actual.quant_table, Some(scaled));
+        }
+    }
+}
+
+#[test]
+fn samples_keep_all_12_bits() {
+    let expected = Pnm::read(include_bytes!("data/scene-12.ppm"));
+    assert_eq!(expected.max, 4095);
+    for data in [SEQUENTIAL, PROGRESSIVE] {
+        let image = Decoder::new(data).decode_16().unwrap();
+        assert_eq!((image.width, image.height), (61, 45));
+        assert_eq!((image.format, image.bit_depth), (PixelFormat::Rgb16, 12));
+        assert_eq!(image.data, expected.samples);
+    }
+}
+
+#[test]
+fn samples_are_too_wide_for_8_bit_images() {
+    assert!(matches!(
+        Decoder::new(SEQUENTIAL).decode(),
+        Err(JpegError::UnsupportedProcess { .. })
+    ));
+}
@@ -0,0 +1,69 @@
+//! Decoding lossless (SOF3) JPEGs. lossless-1.jpg to lossless-7.jpg hold the same 23x13 8 bit
+//! greyscale samples coded with each of the seven predictors, with a restart marker every 4
+//! rows so that the first row of each interval is predicted like the first row of the image.
+//! lossless-12.jpg is a 12 bit image of three interleaved components, the first sampled 2x2,
+//! coded with predictor 6 and a point transform of 2, and lossless-16.jpg holds 16 bit noise
+//! coded with predictor 7. The expected samples are those which were encoded, with chroma
+//! replicated to full size and without colour conversion.
+
+mod common;
+
+use common::Pnm;
+use jpeg_parser::{parse_jpeg, Decoder, PixelFormat};
+
+const PREDICTORS: [&[u8]; 7] = [
+    include_bytes!("data/lossless-1.jpg"),
+    include_bytes!("data/lossless-2.jpg"),
+    include_bytes!("data/lossless-3.jpg"),
+    include_bytes!("data/lossless-4.jpg"),
+    include_bytes!("data/lossless-5.jpg"),
+    include_bytes!("data/lossless-6.jpg"),
+    include_bytes!("data/lossless-7.jpg"),
+];
+
+#[test]
+fn every_predictor_reproduces_the_samples() {
+    let expected = Pnm::read(include_bytes!("data/lossless.pgm"));
+    for (predictor, data) in (1..).zip(PREDICTORS) {
+        let info = parse_jpeg(data).unwrap();
+        assert_eq!(info.frames[0].0, 0xC3);
+        assert_eq!(info.restart_interval, 23 * 4);
+        assert_eq!(info.scans[0].header.spectral_start, predictor);
+
+        let image = Decoder::new(data).decode_16().unwrap();
+        assert_eq!((image.width, image.height), (23, 13));
+        assert_eq!((image.format, image.bit_depth), (PixelFormat::Gray16, 8));
+        assert_eq!(image.data, expected.samples, "predictor {}", predictor);
+
+        let image = Decoder::new(data).decode().unwrap();
+        assert_eq!(image.format, PixelFormat::Gray8);
+        assert_eq!(image.data, expected.bytes(), "predictor {}", predictor);
+    }
+}
+
+#[test]
+fn point_transform_is_undone_for_subsampled_12_bit_components() {
+    let data = include_bytes!("data/lossless-12.jpg");
+    let header = &parse_jpeg(&data[..]).unwrap().scans[0].header;
+    assert_eq!(header.components.len(), 3);
+    assert_eq!((header.spectral_start, header.approx_low), (6, 2));
+
+    let expected = Pnm::read(include_bytes!("data/lossless-12.ppm"));
+    assert_eq!(expected.max, 4095);
+    let image = Decoder::new(data).decode_16().unwrap();
+    assert_eq!((image.width, image.height), (23, 13));
+    assert_eq!((image.format, image.bit_depth), (PixelFormat::Rgb16, 12));
+    assert_eq!(image.data, expected.samples);
+    assert!(image.data.iter().all(|s| s % 4 == 0));
+}
+
+#[test]
+fn samples_may_use_all_16_bits() {
+    let expected = Pnm::read(include_bytes!("data/lossless-16.pgm"));
+    let image = Decoder::new(include_bytes!("data/lossless-16.jpg"))
+        .decode_16()
+        .unwrap();
+    assert_eq!((image.format, image.bit_depth), (PixelFormat::Gray16, 16));
+    assert_eq!(image.data, expected.samples);
+    assert!(image.data.iter().any(|&s| s > 0xF000));
+}
@@ -0,0 +1,86 @@
+//! Decoding a progressive JPEG: a 61x45 scene with 4:2:0 chroma, written by libjpeg as a
+//! baseline image (scene.jpg) at quality 75 and transcoded by jpegtran to libjpeg's default
+//! progressive script (scene-progressive.jpg) without changing a coefficient. The expected pixels
+//! are libjpeg's own decoding of the progressive file (with its default integer IDCT and fancy
+//! upsampling), which is identical to its decoding of the baseline one.
+
+mod common;
+
+use common::Pnm;
+use jpeg_parser::{parse_jpeg, Coefficients, Decoder, PixelFormat};
+
+const SCENE: &[u8] = include_bytes!("data/scene.jpg");
+const PROGRESSIVE: &[u8] = include_bytes!("data/scene-progressive.jpg");
+
+fn assert_same_coefficients(actual: &Coefficients, expected: &Coefficients) {
+    assert_eq!(actual.components.len(), expected.components.len());
+    for (actual, expected) in actual.components.iter().zip(&expected.components) {
+        assert_eq!(actual.id, expected.id);
+        assert_eq!(
+            (actual.blocks_per_line, actual.block_rows),
+            (expected.blocks_per_line, expected.block_rows)
+        );
+        assert_eq!(actual.quant_table, expected.quant_table);
+        for (n, (a, e)) in actual.blocks.iter().zip(&expected.blocks).enumerate() {
+            assert_eq!(a, e, "block {} of component {}", n, actual.id);
+        }
+    }
+}
+
+#[test]
+fn every_kind_of_progressive_scan_is_present() {
+    let info = parse_jpeg(PROGRESSIVE).unwrap();
+    let scans: Vec<_> = info
+        .scans
+        .iter()
+        .map(|scan| {
+            let h = &scan.header;
+            let selectors: Vec<u8> = h.components.iter().map(|c| c.selector).collect();
+            (
+                selectors,
+                h.spectral_start,
+                h.spectral_end,
+                h.approx_high,
+                h.approx_low,
+            )
+        })
+        .collect();
+    // DC first and refinement scans of every component, then first and refinement scans of
+    // bands of AC coefficients of one component at a time
+    assert_eq!(
+        scans,
+        [
+            (vec![1, 2, 3], 0, 0, 0, 1),
+            (vec![1], 1, 5, 0, 2),
+            (vec![3], 1, 63, 0, 1),
+            (vec![2], 1, 63, 0, 1),
+            (vec![1], 6, 63, 0, 2),
+            (vec![1], 1, 63, 2, 1),
+            (vec![1, 2, 3], 0, 0, 1, 0),
+            (vec![3], 1, 63, 1, 0),
+            (vec![2], 1, 63, 1, 0),
+            (vec![1], 1, 63, 1, 0),
+        ]
+    );
+}
+
+#[test]
+fn coefficients_match_the_baseline_image() {
+    let progressive = Decoder::new(PROGRESSIVE).coefficients().unwrap();
+    let baseline = Decoder::new(SCENE).coefficients().unwrap();
+    assert_same_coefficients(&progressive, &baseline);
+}
+
+#[test]
+fn pixels_match_libjpeg() {
+    let expected = Pnm::read(include_bytes!("data/scene.ppm"));
+    for data in [PROGRESSIVE, SCENE] {
+        let image = Decoder::new(data).decode().unwrap();
+        assert_eq!(
+            (image.width, image.height),
+            (expected.width, expected.height)
+        );
+        assert_eq!(image.format, PixelFormat::Rgb8);
+        assert_eq!(image.data, expected.bytes());
+    }
+}
@@ -0,0 +1,105 @@
+//! Recovering from corrupt entropy-coded data in the 200x120 image with 4:2:0 chroma written by
+//! libjpeg with a restart marker every 7 MCUs (big-r7.jpg), so that the lost intervals begin and
+//! end part way through rows of 13 MCUs.
+
+mod common;
+
+use std::ops::Range;
+
+use common::{corrupt, restart_intervals};
+use jpeg_parser::{Damage, Decoder, EntropyErrorKind};
+
+const SEVENS: &[u8] = include_bytes!("data/big-r7.jpg");
+
+/// Whether the pixel at `x`, `y` is in, or next to, one of the 16x16 MCUs in `mcus`. Chroma is
+/// interpolated with the neighbouring samples, so the pixels bordering a lost MCU change too.
+fn near(mcus: &Range<usize>, x: usize, y: usize) -> bool {
+    let (xs, ys) = (x.saturating_sub(1)..=x + 1, y.saturating_sub(1)..=y + 1);
+    ys.flat_map(|y| xs.clone().map(move |x| (x, y)))
+        .filter(|&(x, y)| x < 200 && y < 120)
+        .any(|(x, y)| mcus.contains(&((y / 16) * 13 + x / 16)))
+}
+
+#[test]
+fn decoding_resumes_at_the_next_restart_marker() {
+    let intervals = restart_intervals(SEVENS);
+    assert_eq!(intervals.len(), 15);
+    let clean = Decoder::new(SEVENS).decode().unwrap();
+    for (bytes, mcus, offset) in [
+        // From part way through the first MCU of the interval of MCUs 35 to 41, the last four
+        // of MCU row 2 and first three of row 3
+        (intervals[5].start + 20..intervals[5].end, 35..42, 1685),
+        // The whole of the first interval and the last, which is short
+        (intervals[0].clone(), 0..7, intervals[0].start),
+        (intervals[14].clone(), 98..104, intervals[14].start),
+    ] {
+        let recovered = Decoder::new(&corrupt(SEVENS, bytes.clone()))
+            .recover()
+            .unwrap();
+        assert_eq!(
+            recovered.damage,
+            [Damage {
+                scan: 0,
+                mcus: mcus.clone(),
+                mcus_per_line: 13,
+                offset,
+                kind: EntropyErrorKind::InvalidCode,
+            }],
+            "{:?}",
+            bytes
+        );
+        assert!(recovered.stopped.is_none());
+
+        // Every other interval decodes exactly as it does in the intact file
+        let image = recovered.image;
+        assert_eq!((image.width, image.height), (200, 120));
+        for (idx, (pixel, expected)) in image
+            .data
+            .chunks_exact(3)
+            .zip(clean.data.chunks_exact(3))
+            .enumerate()
+        {
+            let (x, y) = (idx % 200, idx / 200);
+            if !near(&mcus, x, y) {
+                assert_eq!(pixel, expected, "{:?} at {}, {}", bytes, x, y);
+            }
+        }
+    }
+}
+
+#[test]
+fn data_ending_at_a_restart_marker_keeps_the_intervals_before_it() {
+    let intervals = restart_intervals(SEVENS);
+    let clean = Decoder::new(SEVENS).decode().unwrap();
+    // Cut off at the marker which follows the interval of MCUs 42 to 48, with and without an EOI
+    let end = intervals[6].end;
+    let ended = [&SEVENS[..end], &[0xFF, 0xD9]].concat();
+    for data in [&SEVENS[..end], &ended[..]] {
+        let recovered = Decoder::new(data).recover().unwrap();
+        assert_eq!(
+            recovered.damage,
+            [Damage {
+                scan: 0,
+                mcus: 49..104,
+                mcus_per_line: 13,
+                offset: end,
+                kind: EntropyErrorKind::PrematureEnd,
+            }]
+        );
+        assert!(recovered.stopped.is_none());
+
+        // The interval before the marker decoded completely, so it is kept
+        let image = recovered.image;
+        for (idx, (pixel, expected)) in image
+            .data
+            .chunks_exact(3)
+            .zip(clean.data.chunks_exact(3))
+            .enumerate()
+        {
+            let (x, y) = (idx % 200, idx / 200);
+            if !near(&(49..104), x, y) {
+                assert_eq!(pixel, expected, "at {}, {}", x, y);
+            }
+        }
+    }
+}
@@ -0,0 +1,121 @@
+//! Decoding regions of the 200x120 image with 4:2:0 chroma written by libjpeg with a restart
+//! marker at the start of every row of MCUs (big.jpg), and every 7 MCUs (big-r7.jpg). A region
+//! has to decode to the same pixels as cropping the whole image decoded at the same scale.
+
+mod common;
+
+use common::{corrupt, restart_intervals};
+use jpeg_parser::{Decoder, Image, Region, Scale};
+
+const ROWS: &[u8] = include_bytes!("data/big.jpg");
+const SEVENS: &[u8] = include_bytes!("data/big-r7.jpg");
+
+fn rect(x: usize, y: usize, width: usize, height: usize) -> Region {
+    Region {
+        x,
+        y,
+        width,
+        height,
+    }
+}
+
+/// The pixels of `region` of `image`, clipped to the image
+fn crop(image: &Image, region: Region) -> Image {
+    let channels = image.format.channels();
+    let width = region.width.min(image.width - region.x);
+    let height = region.height.min(image.height - region.y);
+    let data = image
+        .data
+        .chunks_exact(image.width * channels)
+        .skip(region.y)
+        .take(height)
+        .flat_map(|row| &row[region.x * channels..(region.x + width) * channels])
+        .copied()
+        .collect();
+    Image {
+        width,
+        height,
+        format: image.format,
+        data,
+    }
+}
+
+fn assert_region(data: &[u8], scale: Scale, region: Region) {
+    let expected = crop(
+        &Decoder::new(data).with_scale(scale).decode().unwrap(),
+        region,
+    );
+    let image = Decoder::new(data)
+        .with_scale(scale)
+        .with_region(region)
+        .decode()
+        .unwrap();
+    assert_eq!(
+        (image.width, image.height, image.format),
+        (expected.width, expected.height, expected.format),
+        "{:?} at {:?}",
+        region,
+        scale
+    );
+    assert_eq!(image.data, expected.data, "{:?} at {:?}", region, scale);
+}
+
+#[test]
+fn regions_match_the_cropped_image() {
+    for data in [ROWS, SEVENS] {
+        for region in [
+            // Whole MCUs, and the whole image
+            rect(16, 32, 48, 16),
+            rect(0, 0, 200, 120),
+            // Edges part way through MCUs, on every side
+            rect(5, 3, 50, 37),
+            rect(123, 77, 77, 43),
+            rect(199, 119, 1, 1),
+            rect(17, 0, 1, 120),
+            // Clipped to the image
+            rect(150, 100, 100, 100),
+        ] {
+            assert_region(data, Scale::Full, region);
+        }
+    }
+}
+
+#[test]
+fn scaled_regions_match_the_cropped_scaled_image() {
+    for data in [ROWS, SEVENS] {
+        for (scale, region) in [
+            (Scale::Half, rect(3, 5, 40, 21)),
+            (Scale::Half, rect(60, 40, 40, 20)),
+            (Scale::Quarter, rect(7, 1, 30, 29)),
+            (Scale::Eighth, rect(2, 3, 20, 12)),
+            (Scale::Eighth, rect(24, 14, 1, 1)),
+        ] {
+            assert_region(data, scale, region);
+        }
+    }
+}
+
+#[test]
+fn intervals_away_from_the_region_are_not_decoded() {
+    // Restart intervals which cannot decode are harmless as long as they are skipped
+    let intervals = restart_intervals(ROWS);
+    assert_eq!(intervals.len(), 8);
+    let damaged = corrupt(ROWS, intervals[1].clone());
+    assert!(Decoder::new(&damaged).decode().is_err());
+    // MCU rows 4 and 5, away from row 1 and the rows either side of it used for upsampling
+    let region = rect(10, 70, 100, 20);
+    let expected = crop(&Decoder::new(ROWS).decode().unwrap(), region);
+    let image = Decoder::new(&damaged).with_region(region).decode().unwrap();
+    assert_eq!(image.data, expected.data);
+
+    // With an interval every 7 MCUs, those wholly beside the region in its rows are skipped too
+    let intervals = restart_intervals(SEVENS);
+    assert_eq!(intervals.len(), 15);
+    let region = rect(0, 64, 24, 16);
+    let expected = crop(&Decoder::new(SEVENS).decode().unwrap(), region);
+    // The interval holding MCUs 56 to 62, i.e columns 4 to 10 of MCU row 4
+    let damaged = corrupt(SEVENS, intervals[8].clone());
+    assert!(Decoder::new(&damaged).decode().is_err());
+    let image = Decoder::new(&damaged).with_region(region).decode().unwrap();
+    assert_eq!(image.data, expected.data);
+}
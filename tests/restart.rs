@@ -0,0 +1,41 @@
+//! Decoding restart intervals on several threads: a 200x120 image with 4:2:0 chroma written by
+//! libjpeg with a restart marker every 13 MCUs, i.e at the start of every row of MCUs
+//! (big.jpg), and the same coefficients with a restart marker every 7 MCUs (big-r7.jpg), so that
+//! intervals begin part way through a row.
+
+use jpeg_parser::{parse_jpeg, Decoder};
+
+const ROWS: &[u8] = include_bytes!("data/big.jpg");
+const SEVENS: &[u8] = include_bytes!("data/big-r7.jpg");
+
+#[test]
+fn restart_intervals_are_read() {
+    for (data, interval) in [(ROWS, 13), (SEVENS, 7)] {
+        let info = parse_jpeg(data).unwrap();
+        assert_eq!(info.restart_interval, interval);
+        assert_eq!(info.props.mcus_per_line(), 13);
+        assert_eq!(info.props.mcu_rows(), 8);
+    }
+}
+
+#[test]
+fn coefficients_do_not_depend_on_the_interval() {
+    let rows = Decoder::new(ROWS).coefficients().unwrap();
+    let sevens = Decoder::new(SEVENS).coefficients().unwrap();
+    for (a, b) in rows.components.iter().zip(&sevens.components) {
+        assert_eq!(a.blocks, b.blocks, "component {}", a.id);
+    }
+}
+
+#[test]
+fn threads_decode_the_same_pixels() {
+    let expected = Decoder::new(ROWS).decode().unwrap();
+    assert_eq!((expected.width, expected.height), (200, 120));
+    for data in [ROWS, SEVENS] {
+        // More threads than intervals, and counts which split the intervals unevenly
+        for threads in [2, 3, 5, 16] {
+            let image = Decoder::new(data).with_threads(threads).decode().unwrap();
+            assert_eq!(image.data, expected.data, "{} threads", threads);
+        }
+    }
+}
@@ -0,0 +1,56 @@
+//! Streaming the rows of sequential JPEGs written by libjpeg: the 20x12 card (card.jpg) and
+//! 61x45 scene (scene.jpg), whose sizes are not multiples of their 16x16 MCUs, and the 200x120
+//! image with a restart marker every 7 MCUs (big-r7.jpg). Every row has to match the whole
+//! image decoded at once.
+
+use jpeg_parser::{Decoder, Scale};
+
+const IMAGES: [(&str, &[u8]); 3] = [
+    ("card.jpg", include_bytes!("data/card.jpg")),
+    ("scene.jpg", include_bytes!("data/scene.jpg")),
+    ("big-r7.jpg", include_bytes!("data/big-r7.jpg")),
+];
+
+#[test]
+fn rows_match_the_decoded_image() {
+    for (name, data) in IMAGES {
+        for scale in [Scale::Full, Scale::Half, Scale::Eighth] {
+            let expected = Decoder::new(data).with_scale(scale).decode().unwrap();
+            let scanlines = Decoder::new(data).with_scale(scale).scanlines().unwrap();
+            assert_eq!(
+                (scanlines.width(), scanlines.height(), scanlines.format()),
+                (expected.width, expected.height, expected.format),
+                "{} at {:?}",
+                name,
+                scale
+            );
+            let rows: Vec<Vec<u8>> = scanlines.map(Result::unwrap).collect();
+            assert_eq!(rows.len(), expected.height, "{} at {:?}", name, scale);
+            let row_len = expected.width * expected.format.channels();
+            for (y, (row, pixels)) in rows
+                .iter()
+                .zip(expected.data.chunks_exact(row_len))
+                .enumerate()
+            {
+                assert_eq!(row, pixels, "{} at {:?}, row {}", name, scale, y);
+            }
+        }
+    }
+}
+
+#[test]
+fn truncated_data_ends_the_rows_with_an_error() {
+    let (_, data) = IMAGES[2];
+    let expected = Decoder::new(data).decode().unwrap();
+    // Truncated part way through the fourth row of MCUs, which the rows of the third are
+    // interpolated with
+    let mut truncated = data[..data.len() / 2].to_vec();
+    truncated.extend([0xFF, 0xD9]);
+    let rows: Vec<_> = Decoder::new(&truncated).scanlines().unwrap().collect();
+    let (last, decoded) = rows.split_last().unwrap();
+    assert!(last.is_err());
+    assert_eq!(decoded.len(), 32);
+    for (row, pixels) in decoded.iter().zip(expected.data.chunks_exact(200 * 3)) {
+        assert_eq!(row.as_ref().unwrap(), pixels);
+    }
+}
@@ -0,0 +1,75 @@
+use crate::image::Sample;
+
+/// The decoded samples of a single component
+pub(crate) struct Plane<T> {
+    pub(crate) data: Vec<T>,
+    /// Distance between rows of `data`
+    pub(crate) stride: usize,
+    /// The row of the component held at the start of `data`, when only some rows are kept
+    pub(crate) first_row: usize,
+    /// Number of valid samples in each row, which may be fewer than `stride` due to block padding
+    pub(crate) width: usize,
+    /// Number of valid rows
+    pub(crate) height: usize,
+    /// How many output pixels each sample covers horizontally and vertically (Hmax / Hi and Vmax / Vi)
+    pub(crate) scale: (usize, usize),
+    /// Whether halved chroma is interpolated rather than replicated
+    pub(crate) fancy: bool,
+}
+
+impl<T: Sample> Plane<T> {
+    fn row(&self, y: usize) -> &[T] {
+        let y = y.min(self.height - 1) - self.first_row;
+        &self.data[y * self.stride..y * self.stride + self.width]
+    }
+
+    /// Fill `out` with row `y` of the component at full resolution. Chroma which is halved
+    /// horizontally (and optionally vertically) is interpolated using the triangular filter
+    /// the IJG calls "fancy upsampling", unless `fancy` is unset. Any other factor is replicated.
+    pub(crate) fn upsample_row(&self, y: usize, out: &mut [T]) {
+        match self.scale {
+            (1, 1) => out.copy_from_slice(&self.row(y)[..out.len()]),
+            (2, 1) if self.fancy => {
+                let row = self.row(y);
+                fancy_h2(row.len(), |x| row[x].into() * 4, (4, 8), out)
+            }
+            (2, 2) if self.fancy => {
+                // Blend the nearest row with the one above or below it, 3:1
+                let near = self.row(y / 2);
+                let far = if y.is_multiple_of(2) {
+                    self.row((y / 2).saturating_sub(1))
+                } else {
+                    self.row(y / 2 + 1)
+                };
+                fancy_h2(
+                    near.len(),
+                    |x| near[x].into() * 3 + far[x].into(),
+                    (8, 7),
+                    out,
+                )
+            }
+            (h, v) => {
+                let row = self.row(y / v);
+                for (x, pixel) in out.iter_mut().enumerate() {
+                    *pixel = row[(x / h).min(self.width - 1)];
+                }
+            }
+        }
+    }
+}
+
+/// Double the width of a row of `len` samples (each scaled by 4) using a 3:1 triangular filter.
+/// `bias` is the rounding applied to the left and right output of each sample, which
+/// alternates so that errors do not accumulate in one direction.
+fn fancy_h2<T: Sample>(len: usize, sample: impl Fn(usize) -> u32, bias: (u32, u32), out: &mut [T]) {
+    let last = len - 1;
+    for (x, pixel) in out.iter_mut().enumerate() {
+        let idx = (x / 2).min(last);
+        let (neighbour, bias) = if x.is_multiple_of(2) {
+            (idx.saturating_sub(1), bias.0)
+        } else {
+            ((idx + 1).min(last), bias.1)
+        };
+        *pixel = T::from_u32((sample(idx) * 3 + sample(neighbour) + bias) >> 4);
+    }
+}